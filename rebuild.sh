@@ -6,6 +6,23 @@ rm -f data/sample.db
 
 cargo run add data/sample.db data/foo.schema data/foo.csv
 cargo run add data/sample.db data/bar.schema data/bar.csv
+cargo run add data/sample.db data/baz.schema data/baz.csv
+cargo run add data/sample.db data/qux.schema data/qux.csv
+cargo run add data/sample.db data/post.schema data/post.csv
+cargo run add data/sample.db data/tie.schema data/tie.csv
+cargo run add data/sample.db data/author.schema data/author.csv
+cargo run add data/sample.db data/book.schema data/book.csv
+cargo run add data/sample.db data/review.schema data/review.csv
+cargo run add data/sample.db data/signup.schema data/signup.csv
+cargo run add data/sample.db data/activity.schema data/activity.csv
+cargo run add data/sample.db data/reading.schema data/reading.csv
+cargo run add data/sample.db data/visit.schema data/visit.csv
+cargo run add data/sample.db data/contact.schema data/contact.csv
+cargo run add data/sample.db data/ledger.schema data/ledger.csv
+cargo run add data/sample.db data/blob.schema data/blob.csv
+cargo run add data/sample.db data/writer.schema data/writer.csv
+cargo run add data/sample.db data/note.schema data/note.csv
+cargo run add data/sample.db data/game.schema data/game.csv
 
 rm -f data/music.db
 
@@ -1,8 +1,14 @@
 #!/usr/bin/env python
 
+import csv
+import gzip
+import io
+import json
 import os
+import shutil
 import subprocess
 import sys
+import tempfile
 
 
 class TestFile:
@@ -43,6 +49,1205 @@ class TestFile:
             sys.exit(1)
 
 
+class QueryFileTest(TestFile):
+    """Same as `TestFile`, with each query read from a file by `--file`"""
+
+    def _command(self, query):
+        return ['target/debug/twin-query', 'query', '--file', self.query_file, self.db_file]
+
+    def _run_test(self, test):
+        with tempfile.TemporaryDirectory() as tmp:
+            self.query_file = os.path.join(tmp, 'query.q')
+            with open(self.query_file, 'w') as f:
+                f.write(test[0] + '\n')
+            TestFile._run_test(self, test)
+
+
+class ConcurrentQueryTest(TestFile):
+    """Runs all of the queries at once with `query-all`, each on its own
+    thread against the same Db, the results have to come back in order"""
+
+    def run(self):
+        print('Running {}'.format(self.name))
+        with tempfile.TemporaryDirectory() as tmp:
+            queries_file = os.path.join(tmp, 'all.q')
+            with open(queries_file, 'w') as f:
+                f.write('\n\n'.join(query for (query, _) in self.tests) + '\n')
+            output = subprocess.check_output(['target/debug/twin-query', 'query-all',
+                                              self.db_file, queries_file]).decode('utf-8')
+
+        actual = '\n'.join(line for line in output.split('\n') if line.strip())
+        expected = '\n'.join(result for (_, result) in self.tests)
+        if len(actual.split('\n')) == len(expected.split('\n')) and \
+                self._compare_results(expected, actual):
+            print('.', end='', flush=True)
+        else:
+            print('\nERROR')
+            print('\nExpected:')
+            print(expected)
+            print('\nActual:')
+            print(actual)
+            sys.exit(1)
+        print()
+
+
+class FailingQueryTest:
+    """The query has to fail with `message` on stderr, and exit with `code`
+    when it's given. `args` are passed to `query` as well"""
+
+    def __init__(self, db_file, query, message='', code=None, args=()):
+        self.db_file = db_file
+        self.query = query
+        self.message = message
+        self.code = code
+        self.args = list(args)
+
+    def run(self):
+        command = ['target/debug/twin-query', 'query'] + self.args + [self.db_file, self.query]
+        output = subprocess.run(command, stdout=subprocess.DEVNULL, stderr=subprocess.PIPE)
+        error = output.stderr.decode('utf-8')
+
+        code_matches = output.returncode == self.code if self.code else output.returncode != 0
+        if code_matches and self.message in error:
+            print('.', end='', flush=True)
+        else:
+            print('\nERROR')
+            print(self.query)
+            print('\nExpected the query to be rejected with exit code {}:'.format(self.code))
+            print(self.message)
+            print('\nExit code: {}'.format(output.returncode))
+            print('\nActual:')
+            print(error)
+            sys.exit(1)
+
+
+class CreateTableTest:
+
+    def __init__(self, db_file, create_query, query, expected):
+        self.db_file = db_file
+        self.create_query = create_query
+        self.query = query
+        self.expected = expected
+
+    def run(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            db_file = os.path.join(tmp, 'copy.db')
+            shutil.copy(self.db_file, db_file)
+
+            subprocess.check_output(['target/debug/twin-query', 'query', db_file,
+                                     self.create_query])
+            test = TestFile('create table', db_file, [(self.query, self.expected)])
+            test._run_test((self.query, self.expected))
+
+
+class ModifyTest:
+    """Runs a subcommand that changes a copy of the db, like `delete` or
+    `update`, `times` times in a row, then checks a query against the changed
+    copy"""
+
+    def __init__(self, db_file, subcommand, args, query, expected, times=1):
+        self.db_file = db_file
+        self.subcommand = subcommand
+        self.args = args
+        self.query = query
+        self.expected = expected
+        self.times = times
+
+    def run(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            db_file = os.path.join(tmp, 'copy.db')
+            shutil.copy(self.db_file, db_file)
+
+            for _ in range(self.times):
+                subprocess.check_output(['target/debug/twin-query', self.subcommand, db_file] +
+                                        self.args)
+            test = TestFile(self.subcommand, db_file, [(self.query, self.expected)])
+            test._run_test((self.query, self.expected))
+
+
+def build_db(tmp, name, tables):
+    """Adds each (schema, CSV rows) pair to `name`.db in `tmp`"""
+    db_file = os.path.join(tmp, '{}.db'.format(name))
+    csv_file = os.path.join(tmp, '{}.csv'.format(name))
+    for (schema_file, rows) in tables:
+        with open(csv_file, 'w') as f:
+            f.write('\n'.join(rows) + '\n')
+        subprocess.check_output(['target/debug/twin-query', 'add', db_file, schema_file,
+                                 csv_file])
+    return db_file
+
+
+class SampleTest:
+    """Adds `rows` rows to a qux table, `query` has to select the ids in
+    `expected`, in any order. `args` are passed to `query` as well"""
+
+    def __init__(self, rows, query, expected, args=()):
+        self.rows = rows
+        self.query = query
+        self.expected = expected
+        self.args = list(args)
+
+    def run(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            rows = ['{},{},{}'.format(i, i, i * 2) for i in range(self.rows)]
+            db_file = build_db(tmp, 'sample', [('data/qux.schema', rows)])
+            output = subprocess.check_output(['target/debug/twin-query', 'query', '--format',
+                                              'csv'] + self.args + [db_file, self.query])
+            output = output.decode('utf-8')
+
+        ids = sorted(int(line) for line in output.split()[1:])
+        if ids == self.expected:
+            print('.', end='', flush=True)
+        else:
+            print('\nERROR')
+            print(self.query)
+            print('\nExpected ids: {}'.format(self.expected))
+            print('Got: {}'.format(ids))
+            sys.exit(1)
+
+
+class MergeTest:
+    """Builds two dbs from (schema, CSV rows) pairs and merges the second into
+    the first. `expected` is None when the merge should fail."""
+
+    def __init__(self, left, right, query, expected):
+        self.left = left
+        self.right = right
+        self.query = query
+        self.expected = expected
+
+    def run(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            left = build_db(tmp, 'left', self.left)
+            right = build_db(tmp, 'right', self.right)
+            command = ['target/debug/twin-query', 'merge', left, right]
+            output = subprocess.run(command, stdout=subprocess.DEVNULL, stderr=subprocess.PIPE)
+
+            if self.expected is None:
+                if output.returncode != 0 and 'TypeMismatch' in output.stderr.decode('utf-8'):
+                    print('.', end='', flush=True)
+                else:
+                    print('\nERROR')
+                    print('Expected merging {} into {} to fail'.format(self.right, self.left))
+                    sys.exit(1)
+                return
+
+            test = TestFile('merge', left, [(self.query, self.expected)])
+            test._run_test((self.query, self.expected))
+
+
+class AppendTest:
+    """Builds a db from the first (schema, CSV rows) pair and appends the
+    others as segments, `query` has to return `expected` before and after
+    `compact` folds them back in"""
+
+    def __init__(self, tables, query, expected):
+        self.tables = tables
+        self.query = query
+        self.expected = expected
+
+    def run(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            db_file = build_db(tmp, 'append', self.tables[:1])
+            csv_file = os.path.join(tmp, 'append.csv')
+            for (schema_file, rows) in self.tables[1:]:
+                with open(csv_file, 'w') as f:
+                    f.write('\n'.join(rows) + '\n')
+                subprocess.check_output(['target/debug/twin-query', 'add', '--append', db_file,
+                                         schema_file, csv_file])
+
+            segments = sorted(f for f in os.listdir(tmp) if '.segment' in f)
+            expected = ['append.db.segment{}'.format(i + 1) for i in range(len(self.tables) - 1)]
+            if segments != expected:
+                print('\nERROR')
+                print('Expected segments {}, got {}'.format(expected, segments))
+                sys.exit(1)
+            test = TestFile('append', db_file, [(self.query, self.expected)])
+            test._run_test((self.query, self.expected))
+
+            # A compact that can't write its temp file keeps the segments
+            os.mkdir(db_file + '.tmp')
+            failed = subprocess.run(['target/debug/twin-query', 'compact', db_file],
+                                    stdout=subprocess.DEVNULL, stderr=subprocess.DEVNULL)
+            os.rmdir(db_file + '.tmp')
+            if failed.returncode == 0 or \
+               sorted(f for f in os.listdir(tmp) if '.segment' in f) != expected:
+                print('\nERROR')
+                print('Expected a failed compact to keep the segments of {}'.format(db_file))
+                sys.exit(1)
+            test._run_test((self.query, self.expected))
+
+            subprocess.check_output(['target/debug/twin-query', 'compact', db_file])
+            if any('.segment' in f or f.endswith('.tmp') for f in os.listdir(tmp)):
+                print('\nERROR')
+                print('Expected compact to remove the segments of {}'.format(db_file))
+                sys.exit(1)
+            test._run_test((self.query, self.expected))
+
+
+class RejectedAppendTest:
+    """Appending `rows` with a schema that retypes a column of the db built
+    from `base` has to fail without writing a segment, `query` has to still
+    return `expected` afterwards"""
+
+    def __init__(self, base, schema_file, rows, query, expected):
+        self.base = base
+        self.schema_file = schema_file
+        self.rows = rows
+        self.query = query
+        self.expected = expected
+
+    def run(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            db_file = build_db(tmp, 'rejected', [self.base])
+            csv_file = os.path.join(tmp, 'append.csv')
+            with open(csv_file, 'w') as f:
+                f.write('\n'.join(self.rows) + '\n')
+            output = subprocess.run(['target/debug/twin-query', 'add', '--append', db_file,
+                                     self.schema_file, csv_file],
+                                    stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+            if output.returncode == 0 or any('.segment' in f for f in os.listdir(tmp)):
+                print('\nERROR')
+                print('Expected appending {} to {} to fail'.format(self.schema_file, db_file))
+                sys.exit(1)
+            test = TestFile('rejected', db_file, [(self.query, self.expected)])
+            test._run_test((self.query, self.expected))
+
+
+class InterruptedCompactTest:
+    """Appends `rows` to the db built from `base` and compacts it, then puts
+    the segment back as if compact stopped before removing it. Loading must
+    not merge it a second time, `query` has to return `expected`."""
+
+    def __init__(self, base, schema_file, rows, query, expected):
+        self.base = base
+        self.schema_file = schema_file
+        self.rows = rows
+        self.query = query
+        self.expected = expected
+
+    def run(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            db_file = build_db(tmp, 'interrupted', [self.base])
+            csv_file = os.path.join(tmp, 'append.csv')
+            with open(csv_file, 'w') as f:
+                f.write('\n'.join(self.rows) + '\n')
+            subprocess.check_output(['target/debug/twin-query', 'add', '--append', db_file,
+                                     self.schema_file, csv_file])
+            segment = db_file + '.segment1'
+            shutil.copy(segment, segment + '.kept')
+            subprocess.check_output(['target/debug/twin-query', 'compact', db_file])
+            shutil.move(segment + '.kept', segment)
+
+            test = TestFile('interrupted', db_file, [(self.query, self.expected)])
+            test._run_test((self.query, self.expected))
+
+
+class AddTest:
+    """Adds every table of a schema that names its own CSVs to a new db"""
+
+    def __init__(self, schema_file, query, expected):
+        self.schema_file = schema_file
+        self.query = query
+        self.expected = expected
+
+    def run(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            db_file = os.path.join(tmp, 'added.db')
+            subprocess.check_output(['target/debug/twin-query', 'add', db_file,
+                                     self.schema_file])
+            test = TestFile('add', db_file, [(self.query, self.expected)])
+            test._run_test((self.query, self.expected))
+
+
+class InferTest:
+    """Adds a CSV with a header row, guessing its schema"""
+
+    def __init__(self, table, rows, query, expected):
+        self.table = table
+        self.rows = rows
+        self.query = query
+        self.expected = expected
+
+    def run(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            db_file = os.path.join(tmp, 'inferred.db')
+            csv_file = os.path.join(tmp, '{}.csv'.format(self.table))
+            with open(csv_file, 'w') as f:
+                f.write('\n'.join(self.rows) + '\n')
+            subprocess.check_output(['target/debug/twin-query', 'add', '--infer', db_file,
+                                     csv_file])
+            test = TestFile('infer', db_file, [(self.query, self.expected)])
+            test._run_test((self.query, self.expected))
+
+
+class GzipCsvTest:
+    """Adds a CSV as is, gzipped with a `.gz` name and gzipped with `--gzip`,
+    every query has to answer the same way for all three"""
+
+    def __init__(self, schema_file, csv_file, queries):
+        self.schema_file = schema_file
+        self.csv_file = csv_file
+        self.queries = queries
+
+    def run(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            with open(self.csv_file, 'rb') as f:
+                contents = f.read()
+            gz_file = os.path.join(tmp, 'data.csv.gz')
+            unnamed_file = os.path.join(tmp, 'data.bin')
+            for path in [gz_file, unnamed_file]:
+                with gzip.open(path, 'wb') as f:
+                    f.write(contents)
+
+            outputs = []
+            for (name, csv_file, flags) in [('plain', self.csv_file, []),
+                                            ('gz', gz_file, []),
+                                            ('flag', unnamed_file, ['--gzip'])]:
+                db_file = os.path.join(tmp, '{}.db'.format(name))
+                subprocess.check_output(['target/debug/twin-query', 'add'] + flags +
+                                        [db_file, self.schema_file, csv_file])
+                outputs.append([subprocess.check_output(['target/debug/twin-query', 'query',
+                                                         db_file, query])
+                                for query in self.queries])
+
+        if all(output == outputs[0] for output in outputs) and outputs[0][0].strip():
+            print('.', end='', flush=True)
+        else:
+            print('\nERROR')
+            print('{} with {}'.format(self.csv_file, self.schema_file))
+            print('\nExpected the same query results from the gzipped CSVs')
+            sys.exit(1)
+
+
+class BatchTest:
+    """Adds a CSV with shuffled times in one go and in batches spilled to disk,
+    both dbs have to answer every query the same way"""
+
+    def __init__(self, rows, batch, queries):
+        self.rows = rows
+        self.batch = batch
+        self.queries = queries
+
+    @staticmethod
+    def _runs():
+        return set(f for f in os.listdir(tempfile.gettempdir())
+                   if f.startswith('twin-query-runs-'))
+
+    def run(self):
+        runs = self._runs()
+        with tempfile.TemporaryDirectory() as tmp:
+            schema_file = os.path.join(tmp, 'big.schema')
+            csv_file = os.path.join(tmp, 'big.csv')
+            with open(schema_file, 'w') as f:
+                f.write('table = "big"\ncsv_ordering = ["id", "time", "v"]\n\n'
+                        '[columns]\nv = "Int"\n')
+            with open(csv_file, 'w') as f:
+                for i in range(self.rows):
+                    f.write('{},{},{}\n'.format(i % 50, (i * 7919) % self.rows, i % 13))
+
+            outputs = []
+            for (name, flags) in [('whole', []), ('batched', ['--batch', str(self.batch)])]:
+                db_file = os.path.join(tmp, '{}.db'.format(name))
+                subprocess.check_output(['target/debug/twin-query', 'add'] + flags +
+                                        [db_file, schema_file, csv_file])
+                outputs.append([subprocess.check_output(['target/debug/twin-query', 'query',
+                                                         db_file, query])
+                                for query in self.queries])
+            leftover = [f for f in os.listdir(tmp) if '.run' in f] + \
+                sorted(self._runs() - runs)
+
+        if outputs[0] == outputs[1] and not leftover:
+            print('.', end='', flush=True)
+        else:
+            print('\nERROR')
+            print('{} rows in batches of {}'.format(self.rows, self.batch))
+            print('\nExpected the same query results and no runs left, found {}'.format(
+                leftover))
+            sys.exit(1)
+
+
+class SkipBadRowsTest:
+    """Adds a CSV mixing good and bad rows with `--skip-bad-rows`"""
+
+    def __init__(self, schema_file, rows, skipped, query, expected):
+        self.schema_file = schema_file
+        self.rows = rows
+        self.skipped = skipped
+        self.query = query
+        self.expected = expected
+
+    def run(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            db_file = os.path.join(tmp, 'skipped.db')
+            csv_file = os.path.join(tmp, 'mixed.csv')
+            with open(csv_file, 'w') as f:
+                f.write('\n'.join(self.rows) + '\n')
+            output = subprocess.check_output(['target/debug/twin-query', 'add',
+                                              '--skip-bad-rows', db_file, self.schema_file,
+                                              csv_file]).decode('utf-8')
+
+            summary = 'skipped {} bad rows'.format(self.skipped)
+            if summary not in output:
+                print('\nERROR')
+                print('\nExpected "{}" in:\n{}'.format(summary, output))
+                sys.exit(1)
+
+            test = TestFile('skip bad rows', db_file, [(self.query, self.expected)])
+            test._run_test((self.query, self.expected))
+
+
+class CsvFormatTest:
+    """Adds a CSV written with `contents` using `flags` like `--delimiter`.
+    Without a query the add has to fail with `expected` on stderr."""
+
+    def __init__(self, schema_file, contents, flags, query, expected):
+        self.schema_file = schema_file
+        self.contents = contents
+        self.flags = flags
+        self.query = query
+        self.expected = expected
+
+    def run(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            db_file = os.path.join(tmp, 'formatted.db')
+            csv_file = os.path.join(tmp, 'formatted.csv')
+            with open(csv_file, 'w') as f:
+                f.write(self.contents)
+            command = (['target/debug/twin-query', 'add'] + self.flags +
+                       [db_file, self.schema_file, csv_file])
+            output = subprocess.run(command, stdout=subprocess.DEVNULL, stderr=subprocess.PIPE)
+
+            if self.query is None:
+                error = output.stderr.decode('utf-8')
+                if output.returncode != 0 and self.expected in error:
+                    print('.', end='', flush=True)
+                else:
+                    print('\nERROR')
+                    print(' '.join(self.flags))
+                    print('\nExpected failure with: {}'.format(self.expected))
+                    print(error)
+                    sys.exit(1)
+                return
+
+            test = TestFile('CSV format', db_file, [(self.query, self.expected)])
+            test._run_test((self.query, self.expected))
+
+
+class JsonLinesTest:
+    """Adds `lines` of JSON objects with `add-json`. Without a query the add
+    has to fail with `expected` on stderr."""
+
+    def __init__(self, schema_file, lines, query, expected):
+        self.schema_file = schema_file
+        self.lines = lines
+        self.query = query
+        self.expected = expected
+
+    def run(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            db_file = os.path.join(tmp, 'json.db')
+            json_file = os.path.join(tmp, 'rows.jsonl')
+            with open(json_file, 'w') as f:
+                f.write('\n'.join(self.lines) + '\n')
+            command = ['target/debug/twin-query', 'add-json', db_file, self.schema_file, json_file]
+            output = subprocess.run(command, stdout=subprocess.DEVNULL, stderr=subprocess.PIPE)
+
+            if self.query is None:
+                error = output.stderr.decode('utf-8')
+                if output.returncode != 0 and self.expected in error:
+                    print('.', end='', flush=True)
+                else:
+                    print('\nERROR')
+                    print('\nExpected failure with: {}'.format(self.expected))
+                    print(error)
+                    sys.exit(1)
+                return
+
+            test = TestFile('JSON lines', db_file, [(self.query, self.expected)])
+            test._run_test((self.query, self.expected))
+
+
+class CompressTest:
+    """Rewrites two copies of the db at different compression levels, both
+    have to answer every query the same way"""
+
+    def __init__(self, db_file, levels, queries):
+        self.db_file = db_file
+        self.levels = levels
+        self.queries = queries
+
+    def run(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            outputs = []
+            sizes = []
+            for level in self.levels:
+                db_file = os.path.join(tmp, '{}.db'.format(level))
+                shutil.copy(self.db_file, db_file)
+                subprocess.check_output(['target/debug/twin-query', 'compress', db_file, level])
+                sizes.append(os.path.getsize(db_file))
+                outputs.append([subprocess.check_output(['target/debug/twin-query', 'query',
+                                                         db_file, query])
+                                for query in self.queries])
+
+        if all(output == outputs[0] for output in outputs) and len(set(sizes)) == len(sizes):
+            print('.', end='', flush=True)
+        else:
+            print('\nERROR')
+            print('Compression levels {} gave sizes {}'.format(self.levels, sizes))
+            print('\nExpected the same query results at every level')
+            sys.exit(1)
+
+
+class CorruptTest:
+    """Queries a copy of the db with one byte flipped, or cut short, which has
+    to fail its checksum on load"""
+
+    def __init__(self, db_file, truncate=False):
+        self.db_file = db_file
+        self.truncate = truncate
+
+    def run(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            db_file = os.path.join(tmp, 'corrupt.db')
+            shutil.copy(self.db_file, db_file)
+            subprocess.check_output(['target/debug/twin-query', 'compress', db_file, 'fast'])
+            with open(db_file, 'rb') as f:
+                contents = bytearray(f.read())
+            if self.truncate:
+                contents = contents[:len(contents) // 2]
+            else:
+                contents[len(contents) // 2] ^= 0xff
+            with open(db_file, 'wb') as f:
+                f.write(contents)
+
+            command = ['target/debug/twin-query', 'query', db_file, 's foo.a']
+            output = subprocess.run(command, stdout=subprocess.DEVNULL, stderr=subprocess.PIPE)
+            error = output.stderr.decode('utf-8')
+
+        if output.returncode == 4 and 'Corrupt(' in error:
+            print('.', end='', flush=True)
+        else:
+            print('\nERROR')
+            print('Expected loading a corrupted {} to fail its checksum'.format(self.db_file))
+            print('\nExit code: {}'.format(output.returncode))
+            print(error)
+            sys.exit(1)
+
+
+class HeaderTest:
+    """Queries the headerless zlib db `db_file` with `header` written before
+    it, `expected` has to be in what it prints to stdout, or stderr when it
+    fails"""
+
+    def __init__(self, db_file, header, query, expected):
+        self.db_file = db_file
+        self.header = header
+        self.query = query
+        self.expected = expected
+
+    def run(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            db_file = os.path.join(tmp, 'header.db')
+            with open(self.db_file, 'rb') as f:
+                contents = f.read()
+            with open(db_file, 'wb') as f:
+                f.write(self.header + contents)
+
+            command = ['target/debug/twin-query', 'query', db_file, self.query]
+            output = subprocess.run(command, stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+            printed = output.stdout.decode('utf-8') + output.stderr.decode('utf-8')
+        printed = '\n'.join(line.rstrip() for line in printed.splitlines())
+
+        if self.expected in printed:
+            print('.', end='', flush=True)
+        else:
+            print('\nERROR')
+            print('Header {!r} before {}'.format(self.header, self.db_file))
+            print('\nExpected: {}'.format(self.expected))
+            print(printed)
+            sys.exit(1)
+
+
+class RejectedModifyTest:
+
+    def __init__(self, db_file, subcommand, args, message):
+        self.db_file = db_file
+        self.subcommand = subcommand
+        self.args = args
+        self.message = message
+
+    def run(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            db_file = os.path.join(tmp, 'copy.db')
+            shutil.copy(self.db_file, db_file)
+
+            command = ['target/debug/twin-query', self.subcommand, db_file] + self.args
+            output = subprocess.run(command, stdout=subprocess.DEVNULL, stderr=subprocess.PIPE)
+            error = output.stderr.decode('utf-8')
+
+        if output.returncode != 0 and self.message in error:
+            print('.', end='', flush=True)
+        else:
+            print('\nERROR')
+            print('{} {}'.format(self.subcommand, ' '.join(self.args)))
+            print('\nExpected failure with: {}'.format(self.message))
+            print(error)
+            sys.exit(1)
+
+
+class ValidateTest:
+
+    def __init__(self, schema_file, queries_file, should_pass):
+        self.schema_file = schema_file
+        self.queries_file = queries_file
+        self.should_pass = should_pass
+
+    def run(self):
+        command = ['target/debug/twin-query', 'validate-queries', self.schema_file,
+                   self.queries_file]
+        passed = subprocess.call(command, stdout=subprocess.DEVNULL) == 0
+
+        if passed == self.should_pass:
+            print('.', end='', flush=True)
+        else:
+            print('\nERROR')
+            print('{} against {}'.format(self.queries_file, self.schema_file))
+            print('\nExpected validation to {}'.format('pass' if self.should_pass else 'fail'))
+            sys.exit(1)
+
+
+class ValidateQueryTest:
+    """`validate` has to exit with `code` for `query` without running it"""
+
+    def __init__(self, db_file, query, code, expected):
+        self.db_file = db_file
+        self.query = query
+        self.code = code
+        self.expected = expected
+
+    def run(self):
+        command = ['target/debug/twin-query', 'validate', self.db_file, self.query]
+        output = subprocess.run(command, stdout=subprocess.PIPE, stderr=subprocess.STDOUT)
+        actual = output.stdout.decode('utf-8').strip()
+
+        if output.returncode == self.code and actual.startswith(self.expected):
+            print('.', end='', flush=True)
+        else:
+            print('\nERROR')
+            print(self.query)
+            print('\nExpected code {} and:\n{}'.format(self.code, self.expected))
+            print('\nActual code {} and:\n{}'.format(output.returncode, actual))
+            sys.exit(1)
+
+
+class SchemaTest:
+    """Builds a db from (schema, CSV rows) pairs, `schema` has to print
+    `expected` for it and leave the db file as it was"""
+
+    def __init__(self, tables, table, expected):
+        self.tables = tables
+        self.table = table
+        self.expected = expected
+
+    def run(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            db_file = build_db(tmp, 'schema', self.tables)
+            with open(db_file, 'rb') as f:
+                before = f.read()
+            command = ['target/debug/twin-query', 'schema', db_file] + \
+                      ([self.table] if self.table else [])
+            output = subprocess.check_output(command).decode('utf-8')
+            with open(db_file, 'rb') as f:
+                after = f.read()
+
+        lines = [line.strip() for line in output.strip().split('\n')]
+        if lines == [line.strip() for line in self.expected] and before == after:
+            print('.', end='', flush=True)
+        else:
+            print('\nERROR')
+            print('schema {}'.format(self.table or ''))
+            print('\nExpected:')
+            print('\n'.join(self.expected))
+            print('\nActual:')
+            print(output)
+            if before != after:
+                print('The db file changed')
+            sys.exit(1)
+
+
+class ExportTest:
+    """Adds a CSV, `export` has to write the same rows back, as a CSV with a
+    header, in any order"""
+
+    def __init__(self, schema_file, csv_file, ordering):
+        self.schema_file = schema_file
+        self.csv_file = csv_file
+        self.ordering = ordering
+
+    def run(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            db_file = os.path.join(tmp, 'export.db')
+            subprocess.check_output(['target/debug/twin-query', 'add', db_file, self.schema_file,
+                                     self.csv_file])
+            table = os.path.basename(self.csv_file).split('.')[0]
+            output = subprocess.check_output(['target/debug/twin-query', 'export', db_file,
+                                              table]).decode('utf-8')
+
+        exported = list(csv.reader(io.StringIO(output)))
+        header = exported[0]
+        actual = sorted([row[header.index(column)] for column in self.ordering]
+                        for row in exported[1:])
+        with open(self.csv_file) as f:
+            expected = sorted(row for row in csv.reader(f))
+
+        if actual == expected:
+            print('.', end='', flush=True)
+        else:
+            print('\nERROR')
+            print('export of {}'.format(self.csv_file))
+            print('\nExpected rows: {}'.format(expected))
+            print('Got: {}'.format(actual))
+            sys.exit(1)
+
+
+class CompleteTest:
+    """The REPL's tab completions for a partial token"""
+
+    def __init__(self, db_file, token, expected):
+        self.db_file = db_file
+        self.token = token
+        self.expected = expected
+
+    def run(self):
+        command = ['target/debug/twin-query', 'complete', self.db_file, self.token]
+        output = subprocess.check_output(command).decode('utf-8').split()
+
+        if output == self.expected:
+            print('.', end='', flush=True)
+        else:
+            print('\nERROR')
+            print('completing "{}"'.format(self.token))
+            print('\nExpected: {}'.format(self.expected))
+            print('Got: {}'.format(output))
+            sys.exit(1)
+
+
+class ReplTest:
+    """Types `lines` into the REPL, every line of `expected` has to be printed
+    and none of `unexpected`"""
+
+    def __init__(self, db_file, lines, expected, unexpected=[]):
+        self.db_file = db_file
+        self.lines = lines
+        self.expected = expected
+        self.unexpected = unexpected
+
+    def run(self):
+        command = ['target/debug/twin-query', 'repl', self.db_file]
+        stdin = '\n'.join(self.lines + ['exit']) + '\n'
+        with tempfile.TemporaryDirectory() as tmp:
+            # The history is written to the working directory
+            command[0] = os.path.abspath(command[0])
+            command[2] = os.path.abspath(command[2])
+            output = subprocess.run(command, input=stdin.encode('utf-8'), cwd=tmp,
+                                    stdout=subprocess.PIPE, check=True).stdout
+        output = [line.rstrip() for line in output.decode('utf-8', 'replace').splitlines()]
+
+        missing = [line for line in self.expected if line.rstrip() not in output]
+        extra = [line for line in self.unexpected if line.rstrip() in output]
+        if missing or extra:
+            print('\nERROR')
+            print('\n'.join(self.lines))
+            print('\nExpected lines: {}'.format(missing))
+            print('Unexpected lines: {}'.format(extra))
+            print('\n'.join(output))
+            sys.exit(1)
+        print('.', end='', flush=True)
+
+
+class StatsTotalsTest:
+    """Adds each (schema, CSV) pair of `tables` to a new db, the last line
+    `:stats` prints for it has to be `expected`"""
+
+    def __init__(self, tables, expected):
+        self.tables = tables
+        self.expected = expected
+
+    def run(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            db_file = os.path.join(tmp, 'stats.db')
+            for (schema_file, csv_file) in self.tables:
+                subprocess.check_output(['target/debug/twin-query', 'add', db_file,
+                                         schema_file, csv_file])
+            ReplTest(db_file, [':stats'], [self.expected]).run()
+
+
+class HistoryTest:
+    """Runs `queries` through the REPL, `./.history` has to keep only the
+    last `max_history` lines"""
+
+    def __init__(self, db_file, queries, max_history):
+        self.db_file = db_file
+        self.queries = queries
+        self.max_history = max_history
+
+    def run(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            command = [os.path.abspath('target/debug/twin-query'), 'repl', '--max-history',
+                       str(self.max_history), os.path.abspath(self.db_file)]
+            stdin = ''.join(query + '\n\n' for query in self.queries) + 'exit\n'
+            subprocess.run(command, input=stdin.encode('utf-8'), cwd=tmp,
+                           stdout=subprocess.DEVNULL, check=True)
+
+            with open(os.path.join(tmp, '.history')) as f:
+                history = f.read().splitlines()
+
+            expected = '\n'.join(self.queries).splitlines()[-self.max_history:]
+            if history == expected:
+                print('.', end='', flush=True)
+            else:
+                print('\nERROR')
+                print('\nExpected history: {}'.format(expected))
+                print('Got: {}'.format(history))
+                sys.exit(1)
+
+
+class ProgressTest:
+
+    def __init__(self, db_file, query, stages, skipped=0, scanned=None):
+        self.db_file = db_file
+        self.query = query
+        self.stages = stages
+        self.skipped = skipped
+        self.scanned = scanned
+
+    def run(self):
+        command = ['target/debug/twin-query', 'query', '--progress', self.db_file, self.query]
+        output = subprocess.run(command, stdout=subprocess.DEVNULL, stderr=subprocess.PIPE)
+        lines = output.stderr.decode('utf-8').strip().split('\n')
+        expected = ['stage {}/{}'.format(i + 1, self.stages) for i in range(self.stages)]
+        skipped = '{} skipped'.format(self.skipped)
+        scanned = '{} scanned'.format(self.scanned)
+
+        if [' '.join(l.split(' ')[:2]) for l in lines] == expected and \
+           lines[-1].endswith(skipped) and \
+           (self.scanned is None or scanned in lines[-1]):
+            print('.', end='', flush=True)
+        else:
+            print('\nERROR')
+            print(self.query)
+            print('\nExpected progress for {} stages with {} skipped nodes, got:'
+                  .format(self.stages, self.skipped))
+            if self.scanned is not None:
+                print('and {} scanned datums'.format(self.scanned))
+            print('\n'.join(lines))
+            sys.exit(1)
+
+
+class TimingsTest:
+    """`--timings` has to print one time per stage of the plan"""
+
+    def __init__(self, db_file, query, stages):
+        self.db_file = db_file
+        self.query = query
+        self.stages = stages
+
+    def run(self):
+        command = ['target/debug/twin-query', 'query', '--timings', self.db_file, self.query]
+        output = subprocess.run(command, stdout=subprocess.DEVNULL, stderr=subprocess.PIPE)
+        lines = output.stderr.decode('utf-8').strip().split('\n')
+        expected = ['stage {}/{} time:'.format(i + 1, self.stages) for i in range(self.stages)]
+
+        if [l.rsplit(' ', 1)[0] for l in lines] == expected and \
+           all(float(l.rsplit(' ', 1)[1]) >= 0 for l in lines):
+            print('.', end='', flush=True)
+        else:
+            print('\nERROR')
+            print(self.query)
+            print('\nExpected times for {} stages, got:'.format(self.stages))
+            print('\n'.join(lines))
+            sys.exit(1)
+
+
+class ThreadsTest:
+
+    def __init__(self, db_file, query):
+        self.db_file = db_file
+        self.query = query
+
+    def _output(self, extra):
+        command = ['target/debug/twin-query', 'query'] + extra + [self.db_file, self.query]
+        return subprocess.check_output(command).decode('utf-8')
+
+    def run(self):
+        single = self._output(['--threads', '1'])
+        default = self._output([])
+
+        if single == default:
+            print('.', end='', flush=True)
+        else:
+            print('\nERROR')
+            print(self.query)
+            print('\nWith 1 thread:')
+            print(single)
+            print('\nWith the default pool:')
+            print(default)
+            sys.exit(1)
+
+
+class StreamTest:
+
+    def __init__(self, db_file, query):
+        self.db_file = db_file
+        self.query = query
+
+    @staticmethod
+    def _columns(output):
+        lines = output.split('\n')
+        separators = [i for i, l in enumerate(lines)
+                      if l.strip() and set(l.strip()) <= set('-+')]
+        columns = []
+        for n, i in enumerate(separators):
+            end = separators[n + 1] - 1 if n + 1 < len(separators) else len(lines)
+            table = [(c.strip(), []) for c in lines[i - 1].split('|')]
+            for line in lines[i + 1:end]:
+                for (_, cells), cell in zip(table, line.split('|')):
+                    if cell.strip():
+                        cells.append(cell.strip())
+            columns.extend(table)
+        return sorted(columns)
+
+    def run(self):
+        command = ['target/debug/twin-query', 'query', self.db_file, self.query]
+        materialized = subprocess.check_output(command).decode('utf-8')
+        command.insert(2, '--stream')
+        streamed = subprocess.check_output(command).decode('utf-8')
+
+        if self._columns(materialized) == self._columns(streamed):
+            print('.', end='', flush=True)
+        else:
+            print('\nERROR')
+            print(self.query)
+            print('\nMaterialized:')
+            print(materialized)
+            print('\nStreamed:')
+            print(streamed)
+            sys.exit(1)
+
+
+class PlanJsonTest:
+
+    def __init__(self, db_file, query, expected):
+        self.db_file = db_file
+        self.query = query
+        self.expected = expected
+
+    def run(self):
+        command = ['target/debug/twin-query', 'query', '--plan-json', self.db_file, self.query]
+        output = subprocess.check_output(command).decode('utf-8').strip().split('\n')[-1]
+
+        if json.loads(output) == self.expected:
+            print('.', end='', flush=True)
+        else:
+            print('\nERROR')
+            print(self.query)
+            print('\nExpected:')
+            print(json.dumps(self.expected, indent=2))
+            print('\nActual:')
+            print(output)
+            sys.exit(1)
+
+
+class ExplainTest:
+
+    def __init__(self, db_file, query, expected):
+        self.db_file = db_file
+        self.query = query
+        self.expected = expected
+
+    def run(self):
+        command = ['target/debug/twin-query', 'query', '--explain', '--progress', self.db_file,
+                   self.query]
+        output = subprocess.run(command, stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+        explained = output.stdout.decode('utf-8').strip()
+        # Progress is only reported by exec, so there should be none
+        progress = output.stderr.decode('utf-8').strip()
+
+        if explained == self.expected and not progress:
+            print('.', end='', flush=True)
+        else:
+            print('\nERROR')
+            print(self.query)
+            print('\nExpected:')
+            print(self.expected)
+            print('\nActual:')
+            print(explained)
+            print(progress)
+            sys.exit(1)
+
+
+class CsvOutputTest:
+
+    def __init__(self, db_file, query, expected):
+        self.db_file = db_file
+        self.query = query
+        self.expected = expected
+
+    def run(self):
+        command = ['target/debug/twin-query', 'query', '--format', 'csv', self.db_file, self.query]
+        output = subprocess.check_output(command).decode('utf-8')
+
+        if output == self.expected:
+            print('.', end='', flush=True)
+        else:
+            print('\nERROR')
+            print(self.query)
+            print('\nExpected:')
+            print(repr(self.expected))
+            print('\nActual:')
+            print(repr(output))
+            sys.exit(1)
+
+
+class JsonOutputTest:
+
+    def __init__(self, db_file, query, expected, stream=False):
+        self.db_file = db_file
+        self.query = query
+        self.expected = expected
+        self.stream = stream
+
+    def run(self):
+        command = ['target/debug/twin-query', 'query', '--format', 'json', self.db_file,
+                   self.query]
+        if self.stream:
+            command.insert(2, '--stream')
+        output = subprocess.check_output(command).decode('utf-8')
+
+        if self.stream:
+            actual = [json.loads(line) for line in output.splitlines()]
+        else:
+            actual = json.loads(output)
+
+        if actual == self.expected:
+            print('.', end='', flush=True)
+        else:
+            print('\nERROR')
+            print(self.query)
+            print('\nExpected:')
+            print(json.dumps(self.expected))
+            print('\nActual:')
+            print(output)
+            sys.exit(1)
+
+
+class RejectedCsvTest:
+
+    def __init__(self, schema_file, csv_file, message=''):
+        self.schema_file = schema_file
+        self.csv_file = csv_file
+        self.message = message
+
+    def run(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            command = ['target/debug/twin-query', 'add', os.path.join(tmp, 'rejected.db'),
+                       self.schema_file, self.csv_file]
+            output = subprocess.run(command, stdout=subprocess.DEVNULL, stderr=subprocess.PIPE)
+            error = output.stderr.decode('utf-8')
+
+        if output.returncode != 0 and self.message in error:
+            print('.', end='', flush=True)
+        else:
+            print('\nERROR')
+            print('{} with {}'.format(self.csv_file, self.schema_file))
+            print('\nExpected the CSV to be rejected with: {}'.format(self.message))
+            print(error)
+            sys.exit(1)
+
+
+class TimeRangeScanTest:
+
+    def __init__(self, rows, start, end, batches=1):
+        self.rows = rows
+        self.start = start
+        self.end = end
+        self.batches = batches
+
+    def run(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            db_file = os.path.join(tmp, 'big.db')
+            schema_file = os.path.join(tmp, 'big.schema')
+            csv_file = os.path.join(tmp, 'big.csv')
+
+            with open(schema_file, 'w') as f:
+                f.write('table = "big"\ncsv_ordering = ["id", "time", "v"]\n\n'
+                        '[columns]\nv = "Int"\n')
+            # Later batches are appended to the table the first one created
+            batch_size = self.rows // self.batches
+            for batch in range(self.batches):
+                with open(csv_file, 'w') as f:
+                    for i in range(batch * batch_size, (batch + 1) * batch_size):
+                        f.write('{},{},{}\n'.format(i, i, i % 7))
+                subprocess.check_output(['target/debug/twin-query', 'add', db_file,
+                                         schema_file, csv_file])
+
+            query = 's big.v\nl 1000\nw big.v > 0\n  big.time > {}\n  big.time <= {}'.format(
+                self.start, self.end)
+            command = ['target/debug/twin-query', 'query', '--progress', db_file, query]
+            output = subprocess.run(command, stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+
+        scanned = int(output.stderr.decode('utf-8').strip().split(' ')[-4])
+        rows = [l for l in output.stdout.decode('utf-8').split('\n') if l.startswith(' (')]
+        in_range = [i for i in range(self.rows) if self.start < i <= self.end]
+        expected = [i for i in in_range if i % 7 > 0]
+
+        if scanned == len(in_range) and len(rows) == len(expected):
+            print('.', end='', flush=True)
+        else:
+            print('\nERROR')
+            print(query)
+            print('\nExpected {} datums scanned and {} rows, got {} and {}'.format(
+                len(in_range), len(expected), scanned, len(rows)))
+            sys.exit(1)
+
+
+class IndexTest:
+    """`query` has to print the same with the indexes of `indexed_schema_file`
+    as with `schema_file`, while testing fewer datums"""
+
+    def __init__(self, schema_file, indexed_schema_file, csv_file, query):
+        self.schema_file = schema_file
+        self.indexed_schema_file = indexed_schema_file
+        self.csv_file = csv_file
+        self.query = query
+
+    def _run(self, tmp, schema_file):
+        db_file = os.path.join(tmp, os.path.basename(schema_file) + '.db')
+        subprocess.check_output(['target/debug/twin-query', 'add', db_file, schema_file,
+                                 self.csv_file])
+        command = ['target/debug/twin-query', 'query', '--progress', db_file, self.query]
+        output = subprocess.run(command, stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+        scanned = int(output.stderr.decode('utf-8').strip().split(' ')[-4])
+        return (output.stdout.decode('utf-8'), scanned)
+
+    def run(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            (scan, scan_count) = self._run(tmp, self.schema_file)
+            (indexed, indexed_count) = self._run(tmp, self.indexed_schema_file)
+
+        if scan == indexed and indexed_count < scan_count:
+            print('.', end='', flush=True)
+        else:
+            print('\nERROR')
+            print(self.query)
+            print('\nScanned {} datums:'.format(scan_count))
+            print(scan)
+            print('\nIndex tested {} datums:'.format(indexed_count))
+            print(indexed)
+            sys.exit(1)
+
+
 def load_test(path):
     with open(path) as f:
         lines = f.read().split('\n')
@@ -87,3 +1292,705 @@ if __name__ == '__main__':
 
     for test in load_tests('tests'):
         test.run()
+
+    # Every multi-line query of the sample, run from a file instead
+    sample = load_test('tests/sample.txt')
+    QueryFileTest('query files', sample.db_file,
+                  [test for test in sample.tests if '\n' in test[0]]).run()
+
+    ConcurrentQueryTest('concurrent queries', sample.db_file, sample.tests).run()
+
+    print('Running rejected queries')
+    FailingQueryTest('data/sample.db',
+                     's review.stars\nj book on review.book\nj review on book.author',
+                     'joins form a cycle').run()
+    FailingQueryTest('data/sample.db', 's foo.a\nj foo on foo.id', 'joins form a cycle', 2).run()
+    FailingQueryTest('data/sample.db', 's game.home\nj game.away on game.home',
+                     'joins form a cycle', 2).run()
+    FailingQueryTest('data/sample.db', 's nope.a', 'unknown table nope').run()
+    FailingQueryTest('data/sample.db', 's nope.*', 'unknown table nope', 2).run()
+    FailingQueryTest('data/sample.db', 's foo.a as n, foo.b as n', 'alias n names two columns',
+                     2).run()
+    FailingQueryTest('data/sample.db', 's foo.a as n, foo.a',
+                     'foo.a is selected under more than one name', 2).run()
+    FailingQueryTest('data/sample.db', 's foo.', 'parse error at line 1', 2).run()
+    FailingQueryTest('data/sample.db', 's foo.z', 'unknown column foo.z', 2).run()
+    FailingQueryTest('data/sample.db', 's foo.a\ngroup foo.b\nsum foo.a', 'InvalidGroup', 3).run()
+    FailingQueryTest('data/sample.db', 's post.id\nhaving count > 1',
+                     'group needs exactly one aggregate', 2).run()
+    FailingQueryTest('data/sample.db', 'group post.author\ncount post.id\nhaving sum > 1',
+                     "having compares the query's aggregate to ints", 2).run()
+    FailingQueryTest('data/sample.db', 'group post.author\ncount post.id\nhaving count = "a"',
+                     "having compares the query's aggregate to ints", 2).run()
+    FailingQueryTest('data/sample.db', 's game.home\nw game.home > bar.c',
+                     'bar.c can only be compared to columns of its own table', 2).run()
+    FailingQueryTest('data/sample.db', 's bar.c\nw bar.c > bar.d', 'InvalidComparison', 3).run()
+    FailingQueryTest('data/sample.db', 's foo.a\nw foo.a % 2 = 0', 'InvalidModulo', 3).run()
+    FailingQueryTest('data/sample.db', 's bar.c\nw bar.c % 0 = 0', 'parse error', 2).run()
+    FailingQueryTest('data/sample.db', 's foo.a\nl 0', 'limit has to be above 0', 2).run()
+    FailingQueryTest('data/sample.db', 's foo.a\nl 2\nl 3', 'conflicting limits 2 and 3', 2).run()
+    FailingQueryTest('data/sample.db', 's foo.a\nmatch any\nmatch all',
+                     'conflicting match all and match any', 2).run()
+    FailingQueryTest('data/sample.db', 's qux.v\nas of 2\nas of 3',
+                     'conflicting as of times 2 and 3', 2).run()
+    FailingQueryTest('data/corrupt.db', 's foo.a', 'failed to load data/corrupt.db', 4).run()
+    FailingQueryTest('data/sample.db', 's foo.a\nw foo.z = 1',
+                     'unknown column foo.z\ncolumns in foo: a, b, id, time').run()
+    FailingQueryTest('data/sample.db', 's foo.a\nw foo.a >> 1',
+                     'parse error at line 2, column 10\n  w foo.a >> 1\n           ^').run()
+    FailingQueryTest('data/sample.db', 's foo.a,\nl 2',
+                     'parse error at line 1, column 9\n  s foo.a,\n          ^').run()
+    FailingQueryTest('data/sample.db', 's visit.at\nw visit.at > @2020-02-30',
+                     'parse error at line 2').run()
+    FailingQueryTest('data/sample.db', 's reading.temp\nw reading.temp > 0',
+                     "can't compare reading.temp to 0, their types differ", 2).run()
+    FailingQueryTest('data/sample.db', 's foo.a\nw foo.time > "x"',
+                     'foo.time can only be compared to ints or timestamps, not "x"', 2).run()
+    FailingQueryTest('data/sample.db', 's foo.a\nw foo.b ~ "x"', 'InvalidLike').run()
+    FailingQueryTest('data/sample.db', 's bar.c\nw bar.c > 1\n  bar.d ~ "x"',
+                     'InvalidLike').run()
+    FailingQueryTest('data/sample.db', 's bar.c\nw bar.c like "5%"', 'InvalidLike').run()
+    FailingQueryTest('data/sample.db', 's bar.c\nj foo on bar.d',
+                     "can't join foo.id on bar.d, their types differ", 2).run()
+    FailingQueryTest('data/sample.db', 's post.score\nj writer.handle on post.score',
+                     "can't join writer.handle on post.score, their types differ", 2).run()
+    print()
+
+    print('Running create table')
+    CreateTableTest('data/sample.db',
+                    'create table popular as\ngroup post.author\ncount post.id',
+                    's popular.author, popular.count_id\nw popular.count_id > 1',
+                    '\n'.join([' popular.author  | popular.count_id',
+                               '-----------------+------------------',
+                               ' (1, "brian", 7) |        (1, 2, 7)',
+                               ' (0, "ada", 10)  |       (0, 3, 10)'])).run()
+    CreateTableTest('data/sample.db', 'create table labels as\ns foo.a as label\nw foo.b = true',
+                    's labels.label',
+                    '\n'.join([' labels.label',
+                               '------------------',
+                               ' (1, "first", 0)',
+                               ' (2, "second", 0)'])).run()
+    print()
+
+    print('Running delete')
+    ModifyTest('data/sample.db', 'delete', ['post.id', 'w post.score < 5'], 's post.id\nl 100',
+               '\n'.join([' post.id',
+                          '--------------',
+                          ' (2, 2, 2)',
+                          ' (5, 5, 5)',
+                          ' (7, 7, 7)',
+                          ' (8, 8, 8)',
+                          ' (9, 9, 9)',
+                          ' (12, 12, 12)'])).run()
+    ModifyTest('data/sample.db', 'delete', ['post.author', 'w post.score = 5'],
+               's post.id\nw post.author = "brian"',
+               '\n'.join([' post.id',
+                          '-----------',
+                          ' (7, 7, 7)'])).run()
+    ModifyTest('data/sample.db', 'delete', ['foo.id', 'w foo.b = true'],
+               's bar.c\nj foo on bar.foo',
+               '\n'.join([' bar.c',
+                          '------------',
+                          ' (3, 70, 3)',
+                          ' (4, 80, 3)'])).run()
+    ModifyTest('data/sample.db', 'delete', ['qux.v', 'w qux.v > 0'], 's qux.v', '(0 rows)').run()
+    ModifyTest('data/sample.db', 'delete', ['qux.id', 'w qux.v > 0'], 's qux.v', '(0 rows)',
+               times=2).run()
+    ModifyTest('data/sample.db', 'delete', ['foo.id', 'w foo.a ~ ""'], 's bar.c, foo.a',
+               '\n'.join([' bar.c      | foo.a',
+                          '------------+-------',
+                          ' (1, 50, 0) |',
+                          ' (2, 60, 1) |',
+                          ' (3, 70, 3) |',
+                          ' (4, 80, 3) |'])).run()
+    print()
+
+    print('Running update')
+    ModifyTest('data/sample.db', 'update', ['post.score', '3', '11', '20'],
+               's post.score\nw post.id = 3',
+               '\n'.join([' post.score',
+                          '-------------',
+                          ' (3, 1, 3)',
+                          ' (3, 11, 20)'])).run()
+    ModifyTest('data/sample.db', 'update', ['foo.a', '2', 'renamed', '5'],
+               's foo.a\norder foo.a asc',
+               '\n'.join([' foo.a',
+                          '-------------------',
+                          ' (1, "first", 0)',
+                          ' (4, "fourth", 3)',
+                          ' (2, "renamed", 5)',
+                          ' (2, "second", 0)',
+                          ' (3, "third", 1)'])).run()
+    RejectedModifyTest('data/sample.db', 'update', ['post.score', '3', '11', '3'],
+                       'StaleUpdate').run()
+    RejectedModifyTest('data/sample.db', 'update', ['post.score', '99', '11', '20'],
+                       'IdNotFound').run()
+    RejectedModifyTest('data/sample.db', 'update', ['post.score', '3', 'eleven', '20'],
+                       'ParseError').run()
+    print()
+
+    print('Running multi-table schemas')
+    AddTest('data/library.schema', 's author.name, book.title',
+            '\n'.join([' author.name        | book.title',
+                       '--------------------+---------------------------',
+                       ' (1, "orwell", 1)   | (1, "1984", 2)',
+                       ' (2, "huxley", 1)   | (2, "animal farm", 2)',
+                       ' (3, "bradbury", 1) | (3, "brave new world", 2)',
+                       '                    | (4, "fahrenheit 451", 2)',
+                       '                    | (5, "beowulf", 2)'])).run()
+    print()
+
+    print('Running schema inference')
+    people = ['id,time,name,age,active,code',
+              '1,1,ada,36,true,12',
+              '2,2,brian,,false,none',
+              '3,3,carol,-4,true,7']
+    InferTest('person', people,
+              's person.name, person.age, person.active\nw person.age > 0',
+              '\n'.join([' person.active | person.age | person.name',
+                         '---------------+------------+---------------',
+                         ' (1, true, 1)  | (1, 36, 1) | (1, "ada", 1)'])).run()
+    InferTest('person', people,
+              's person.code\nw person.code = "none" or = "7"',
+              '\n'.join([' person.code',
+                         '----------------',
+                         ' (2, "none", 2)',
+                         ' (3, "7", 3)'])).run()
+    print()
+
+    print('Running gzipped CSVs')
+    GzipCsvTest('data/foo.schema', 'data/foo.csv', ['s foo.a, foo.b']).run()
+    GzipCsvTest('data/note.schema', 'data/note.csv',
+                ['s note.text, note.pinned, note.stars', 's note.text\nw note.text is null']).run()
+    print()
+
+    print('Running CSV formats')
+    foo_expected = '\n'.join([' foo.a                        | foo.b',
+                              '------------------------------+---------------',
+                              ' (1, "first", 0)              | (1, true, 0)',
+                              ' (2, "second, with comma", 1) | (2, false, 1)'])
+    CsvFormatTest('data/foo.schema', '1\t0\tfirst\ttrue\n2\t1\tsecond, with comma\tfalse\n',
+                  ['--delimiter', '\\t'], 's foo.a, foo.b', foo_expected).run()
+    CsvFormatTest('data/foo.schema', '1;0;first;true\n2;1;second, with comma;false\n',
+                  ['-d', ';'], 's foo.a, foo.b', foo_expected).run()
+    CsvFormatTest('data/foo.schema',
+                  'id,time,a,b\n1,0,first,true\n2,1,"second, with comma",false\n',
+                  ['--has-headers'], 's foo.a, foo.b', foo_expected).run()
+    CsvFormatTest('data/foo.schema',
+                  'id,time,a,b\n1,0,first,true\n2,1,"second, with comma",false\n',
+                  ['--check-headers'], 's foo.a, foo.b', foo_expected).run()
+    CsvFormatTest('data/foo.schema', 'id,time,b,a\n1,0,true,first\n', ['--check-headers'],
+                  None, "header id,time,b,a doesn't match csv_ordering id,time,a,b").run()
+    CsvFormatTest('data/foo.schema', '1,0,first,true\n', ['-d', '::'],
+                  None, 'delimiter has to be a single byte').run()
+    CsvFormatTest('data/contact.schema', '1,1,ada,36\n2,2,brian\n', [],
+                  None, 'row 2: expected 4 columns, got 3').run()
+    CsvFormatTest('data/contact.schema', 'id,time,name,age\n1,1,ada,36,extra\n', ['-H'],
+                  None, 'row 2: expected 4 columns, got 5').run()
+    CsvFormatTest('data/contact.schema', '1,1,ada,36\n2,-2,brian,40\n', [],
+                  None, "row 2, column contact.time: expected a non-negative Int, got '-2'").run()
+    CsvFormatTest('data/contact.schema', '1,1,ada,36\n,2,brian,40\n', [],
+                  None, "row 2, column contact.id: expected a non-negative Int, got ''").run()
+    print()
+
+    print('Running JSON lines adds')
+    JsonLinesTest('data/contact.schema',
+                  ['{"id": 1, "time": 2, "name": "ann", "age": 30}',
+                   '{"id": 2, "time": 3, "name": null}',
+                   '{"time": 4, "id": 3, "age": 5}'],
+                  's contact.name, contact.age',
+                  '\n'.join([' contact.age | contact.name',
+                             '-------------+---------------',
+                             '  (1, 30, 2) | (1, "ann", 2)',
+                             '   (3, 5, 4) |'])).run()
+    JsonLinesTest('data/foo.schema', ['{"id": 1, "time": 0, "a": "first", "b": true}'],
+                  's foo.a, foo.b',
+                  '\n'.join([' foo.a           | foo.b',
+                             '-----------------+--------------',
+                             ' (1, "first", 0) | (1, true, 0)'])).run()
+    JsonLinesTest('data/contact.schema', ['{"id": 1, "time": 2, "nick": "a"}'],
+                  None, 'line 1: unknown column contact.nick').run()
+    JsonLinesTest('data/contact.schema', ['{"id": 1, "time": 2}', '{"id": 2, "name": "b"}'],
+                  None, 'line 2: missing time').run()
+    JsonLinesTest('data/contact.schema', ['{"id": 1, "time": 2, "name": ["a"]}'],
+                  None, "contact.name can't be an array or object").run()
+    print()
+
+    print('Running skipped bad rows')
+    SkipBadRowsTest('data/contact.schema',
+                    ['1,1,ada,36', '2,2,brian,old', '3,x,carol,29', '4,4,dan,41', '5,5,,'],
+                    2, 's contact.id, contact.name, contact.age',
+                    '\n'.join([' contact.age | contact.id | contact.name',
+                               '-------------+------------+---------------',
+                               '  (1, 36, 1) |  (1, 1, 1) | (1, "ada", 1)',
+                               '  (4, 41, 4) |  (4, 4, 4) | (4, "dan", 4)',
+                               '             |  (5, 5, 5) |'])).run()
+    SkipBadRowsTest('data/contact.schema', ['1,1,ada,36', '2,2,brian', '3,3,carol,29,x'], 2,
+                    's contact.name',
+                    '\n'.join([' contact.name',
+                               '---------------',
+                               ' (1, "ada", 1)'])).run()
+    SkipBadRowsTest('data/contact.schema',
+                    ['1,1,ada,36', '2,-2,brian,40', ',3,carol,29', '-4,4,dan,41', '5,,eve,30'],
+                    4, 's contact.name',
+                    '\n'.join([' contact.name',
+                               '---------------',
+                               ' (1, "ada", 1)'])).run()
+    SkipBadRowsTest('data/reading.schema', ['0,1,a,21.5', '1,2,b,NaN', '2,3,c,1.5'], 1,
+                    's reading.sensor',
+                    '\n'.join([' reading.sensor',
+                               '----------------',
+                               ' (0, "a", 1)',
+                               ' (2, "c", 3)'])).run()
+    print()
+
+    print('Running batched adds')
+    big_queries = ['s big.v\nl 100', 's latest big.v\nl 50\nw big.time <= 5000',
+                   's time_count(big.v)\nl 50', 's big.v\nl 20\norder big.v desc']
+    BatchTest(20000, 1500, big_queries).run()
+    BatchTest(2000, 1, big_queries[:1]).run()
+    print()
+
+    print('Running merge')
+    MergeTest([('data/foo.schema', ['1,0,first,true', '2,1,second,false'])],
+              [('data/qux.schema', ['1,2,10', '3,4,30'])],
+              's foo.a, qux.v',
+              '\n'.join([' foo.a            | qux.v',
+                         '------------------+------------',
+                         ' (1, "first", 0)  | (1, 10, 2)',
+                         ' (2, "second", 1) | (3, 30, 4)'])).run()
+    MergeTest([('data/baz.schema', ['u_alpha,1,alpha', 'u_beta,2,beta'])],
+              [('data/baz.schema', ['u_gamma,3,gamma', 'u_alpha,4,alpha again'])],
+              's baz.id, baz.name',
+              '\n'.join([' baz.id            | baz.name',
+                         '-------------------+-----------------------',
+                         ' (0, "u_alpha", 1) | (0, "alpha", 1)',
+                         ' (1, "u_beta", 2)  | (1, "beta", 2)',
+                         ' (2, "u_gamma", 3) | (2, "gamma", 3)',
+                         ' (0, "u_alpha", 4) | (0, "alpha again", 4)'])).run()
+    MergeTest([('data/qux.schema', ['1,1,10'])],
+              [('data/baz.schema', ['u_alpha,1,alpha']), ('data/qux.schema', ['2,2,20'])],
+              's latest qux.v',
+              '\n'.join([' qux.v',
+                         '------------',
+                         ' (1, 10, 1)',
+                         ' (2, 20, 2)'])).run()
+    MergeTest([('data/foo.schema', ['1,0,first,true'])],
+              [('data/foo_retyped.schema', ['2,1,7'])],
+              '', None).run()
+    print()
+
+    print('Running appends')
+    RejectedAppendTest(('data/foo.schema', ['1,0,first,true']), 'data/foo_retyped.schema',
+                       ['2,1,7'], 's foo.a', '\n'.join([' foo.a',
+                                                       '-----------------',
+                                                       ' (1, "first", 0)'])).run()
+    InterruptedCompactTest(('data/qux.schema', ['1,1,10']), 'data/qux.schema', ['2,2,20'],
+                           's qux.v', '\n'.join([' qux.v',
+                                                 '------------',
+                                                 ' (1, 10, 1)',
+                                                 ' (2, 20, 2)'])).run()
+    AppendTest([('data/foo.schema', ['1,0,first,true', '2,1,second,false']),
+                ('data/foo.schema', ['3,2,third,true', '1,3,again,false'])],
+               's foo.a, foo.b',
+               '\n'.join([' foo.a            | foo.b',
+                          '------------------+---------------',
+                          ' (1, "first", 0)  | (1, true, 0)',
+                          ' (2, "second", 1) | (2, false, 1)',
+                          ' (3, "third", 2)  | (3, true, 2)',
+                          ' (1, "again", 3)  | (1, false, 3)'])).run()
+    AppendTest([('data/baz.schema', ['u_alpha,1,alpha']),
+                ('data/baz.schema', ['u_beta,2,beta']),
+                ('data/baz.schema', ['u_alpha,3,alpha again'])],
+               's baz.id, baz.name',
+               '\n'.join([' baz.id            | baz.name',
+                          '-------------------+-----------------------',
+                          ' (0, "u_alpha", 1) | (0, "alpha", 1)',
+                          ' (1, "u_beta", 2)  | (1, "beta", 2)',
+                          ' (0, "u_alpha", 3) | (0, "alpha again", 3)'])).run()
+    print()
+
+    print('Running compress')
+    CompressTest('data/sample.db', ['none', 'best'],
+                 ['s foo.a, foo.b', 's post.author\norder post.author asc',
+                  's baz.name', 's blob.payload']).run()
+    CompressTest('data/sample.db', ['fast', 'default'], ['s latest qux.v']).run()
+    CompressTest('data/sample.db', ['raw', 'best'], ['s foo.a, foo.b', 's note.text']).run()
+    CorruptTest('data/sample.db').run()
+    CorruptTest('data/sample.db', truncate=True).run()
+    # Written by the first release, before db files had a codec header or string ids
+    legacy = ('s foo.a', '\n'.join([' foo.a',
+                                    '------------------',
+                                    ' (1, "first", 0)',
+                                    ' (2, "second", 0)',
+                                    ' (3, "third", 1)',
+                                    ' (4, "fourth", 3)']))
+    TestFile('legacy', 'data/legacy.db', [legacy])._run_test(legacy)
+    # Its ints were unsigned
+    legacy_ints = ('s bar.c\nw bar.c > 60', '\n'.join([' bar.c',
+                                                      '------------',
+                                                      ' (3, 70, 3)',
+                                                      ' (4, 80, 3)']))
+    TestFile('legacy', 'data/legacy.db', [legacy_ints])._run_test(legacy_ints)
+    # Written with a codec header, before the format version was added to it
+    # post.author has a hash index in it
+    hashed = ('s post.id\nw post.author = "ada"', '\n'.join([' post.id',
+                                                             '--------------',
+                                                             '    (1, 1, 1)',
+                                                             '    (3, 3, 3)',
+                                                             ' (10, 10, 10)']))
+    for test in [legacy, hashed]:
+        TestFile('unversioned', 'data/unversioned.db', [test])._run_test(test)
+    # The header of a newer build in front of the first release's db
+    HeaderTest('data/legacy.db', b'twq\x41\x03', legacy[0], 'UnknownVersion(3)').run()
+    RejectedModifyTest('data/sample.db', 'compress', ['tiny'], 'unknown compression level').run()
+    print()
+
+    print('Running schema')
+    SchemaTest([('data/foo.schema', ['1,0,first,true', '2,1,second,false']),
+                ('data/qux.schema', ['1,1,10'])], None,
+               [' column   | type   | datums',
+                '----------+--------+--------',
+                ' foo.a    | String | 2',
+                ' foo.b    | Bool   | 2',
+                ' foo.id   | Int    | 2',
+                ' foo.time | Int    | 2',
+                ' qux.id   | Int    | 1',
+                ' qux.time | Int    | 1',
+                ' qux.v    | Int    | 1']).run()
+    SchemaTest([('data/foo.schema', ['1,0,first,true', '2,1,second,false']),
+                ('data/qux.schema', ['1,1,10'])], 'qux',
+               [' column   | type | datums',
+                '----------+------+--------',
+                ' qux.id   | Int  | 1',
+                ' qux.time | Int  | 1',
+                ' qux.v    | Int  | 1']).run()
+    print()
+
+    print('Running sampling')
+    SampleTest(45, 's qux.id\nw qux.id % 10 = 0', [0, 10, 20, 30, 40]).run()
+    SampleTest(45, 's qux.id\nw qux.id % 10 = 3 and < 30', [3, 13, 23]).run()
+    print()
+
+    print('Running limits')
+    # Without a limit 20 rows are selected, repeating the same limit is fine
+    SampleTest(45, 's qux.id', list(range(20))).run()
+    SampleTest(45, 's qux.id\nl 5\nl 5', list(range(5))).run()
+    # `--default-limit` only applies without an `l` line
+    SampleTest(45, 's qux.id', list(range(30)), ['--default-limit', '30']).run()
+    SampleTest(45, 's qux.id\nl 5', list(range(5)), ['--default-limit', '30']).run()
+    FailingQueryTest('data/sample.db', 's foo.a', 'default limit must be a positive integer',
+                     1, ['--default-limit', '0']).run()
+    print()
+
+    print('Running export')
+    ExportTest('data/qux.schema', 'data/qux.csv', ['id', 'time', 'v']).run()
+    ExportTest('data/contact.schema', 'data/contact.csv', ['id', 'time', 'name', 'age']).run()
+    ExportTest('data/foo.schema', 'data/foo.csv', ['id', 'time', 'a', 'b']).run()
+    ExportTest('data/baz.schema', 'data/baz.csv', ['id', 'time', 'name']).run()
+    print()
+
+    print('Running completions')
+    CompleteTest('data/sample.db', 'foo.', ['foo.a', 'foo.b', 'foo.id', 'foo.time']).run()
+    CompleteTest('data/sample.db', 'bar.f', ['bar.foo']).run()
+    CompleteTest('data/sample.db', 'c', ['cohort', 'contact', 'count', 'create']).run()
+    CompleteTest('data/sample.db', 'time_', ['time_count']).run()
+    CompleteTest('data/sample.db', 'nothing', []).run()
+    print()
+
+    print('Running REPL commands')
+    ReplTest('data/sample.db', [':schema foo'],
+             [' column   | type   | datums',
+              '----------+--------+--------',
+              ' foo.a    | String | 4',
+              ' foo.b    | Bool   | 4',
+              ' foo.id   | Int    | 4',
+              ' foo.time | Int    | 4']).run()
+    ReplTest('data/sample.db', [':schema reading'],
+             [' reading.sensor | String | 5', ' reading.temp   | Float  | 5']).run()
+    ReplTest('data/sample.db', [':schema'], ['activity', 'foo', 'writer']).run()
+    ReplTest('data/sample.db', [':schema nothing'], ['unknown table nothing']).run()
+    ReplTest('data/sample.db', [':stats foo'],
+             [' column   | datums | time index | min time | max time',
+              '----------+--------+------------+----------+----------',
+              ' foo.a    | 4      | no         | 0        | 3',
+              ' foo.time | 4      | no         | 0        | 3']).run()
+    StatsTotalsTest([('data/foo.schema', 'data/foo.csv'), ('data/qux.schema', 'data/qux.csv')],
+                    '2 tables, 37 datums, 7 entities').run()
+    ReplTest('data/sample.db', [':stats reading'],
+             [' reading.temp   | 5      | yes        | 1        | 5']).run()
+    ReplTest('data/sample.db', [':stats nothing'], ['unknown table nothing']).run()
+    ReplTest('data/sample.db', [':limit', ':limit 2', 's foo.a', ''],
+             ['limit 2000', 'limit 2', ' (1, "first", 0)', ' (2, "second", 0)'],
+             [' (3, "third", 1)']).run()
+    ReplTest('data/sample.db', [':limit 3', 's foo.b', ''],
+             [' (1, true, 0)', ' (3, false, 1)'], [' (4, false, 3)']).run()
+    ReplTest('data/sample.db', [':limit 3', 's foo.a', 'l 1', ''],
+             [' (1, "first", 0)'], [' (2, "second", 0)']).run()
+    ReplTest('data/sample.db', [':limit many'], ['limit has to be a positive integer']).run()
+    ReplTest('data/sample.db', [':limit 0', ':limit'],
+             ['limit has to be a positive integer', 'limit 2000'], ['limit 0']).run()
+    ReplTest('data/sample.db', [':page 3', 's qux.v', '', '', ''],
+             ['paging every 3 rows', ' (1, 11, 2)', '-- page 1/3, enter for more or q to stop --',
+              ' (3, 30, 4)', '-- page 2/3, enter for more or q to stop --', ' (2, 22, 5)'],
+             ['-- page 3/3, enter for more or q to stop --']).run()
+    ReplTest('data/sample.db', [':page 1', 's foo.a', '', '', 'q'],
+             [' (1, "first", 0)', ' (2, "second", 0)',
+              '-- page 2/4, enter for more or q to stop --q'],
+             [' (3, "third", 1)']).run()
+    ReplTest('data/sample.db', [':page 2', ':page off', 's foo.a', ''],
+             ['paging off', ' (4, "fourth", 3)'],
+             ['-- page 1/2, enter for more or q to stop --']).run()
+    ReplTest('data/sample.db', [':page 0'], ['page has to be a positive integer or off']).run()
+    ReplTest('data/sample.db', ['s foo.a', 'w foo.b = false', ':clear', 's bar.c', ''],
+             ['cleared', ' bar.c', ' (1, 50, 0)', ' (4, 80, 3)'],
+             [' foo.a', ' (3, "third", 1)']).run()
+    ReplTest('data/sample.db', ['s bar.c', 'w bar.c > 50', '  bar.c <= 70', ''],
+             ['filter: bar.c in (50, 70]']).run()
+    ReplTest('data/sample.db', ['s foo.a', 'w foo.a = "first"', ''],
+             ['filter: foo.a = "first"']).run()
+    # Stage nodes are hashed, every run has to print and combine them the same way
+    for _ in range(10):
+        ReplTest('data/sample.db',
+                 ['s foo.a, foo.b', 'w foo.a > "a"', 'w foo.a != "second"', 'w foo.b = true', ''],
+                 ['Plan: [ Where(foo.b, Constant(Equal, Bool(true)), None), '
+                  'Where(foo.a, And(Constant(Greater, String("a")), '
+                  'Constant(NotEqual, String("second"))), None) ]',
+                  '      [ Select(foo.a, 20, 0), Select(foo.b, 20, 0) ]']).run()
+    ReplTest('data/sample.db', [':page 2', 's foo.a', 'w foo.a = "nothing"', ''],
+             ['(0 rows)']).run()
+    print()
+
+    print('Running REPL history')
+    HistoryTest('data/sample.db', ['s foo.a', 's foo.b', 's bar.c', 's foo.a\nl 1'], 3).run()
+    HistoryTest('data/sample.db', ['s foo.a', 's foo.b'], 5).run()
+    HistoryTest('data/sample.db', ['s post.author\nl {}'.format(i) for i in range(10)], 4).run()
+    print()
+
+    print('Running validate-queries')
+    ValidateTest('data/foo.schema', 'data/foo_valid.queries', True).run()
+    ValidateTest('data/foo.schema', 'data/foo_invalid.queries', False).run()
+    ValidateQueryTest('data/sample.db', 's foo.a\nw foo.b = true', 0, 'query is valid').run()
+    ValidateQueryTest('data/sample.db', 's foo.nope', 2, 'unknown column foo.nope').run()
+    ValidateQueryTest('data/sample.db', 's foo.a\nw', 2, 'parse error at line 2').run()
+    ValidateQueryTest('data/sample.db', 's foo.a\nw foo.a % 2 = 0', 3, 'InvalidModulo').run()
+    ValidateQueryTest('data/sample.db', 's foo.a\nw foo.b = true\nw bar.c > 50', 0,
+                      "warning: the filter on bar.c isn't used by anything selected").run()
+    print()
+
+    print('Running rejected CSVs')
+    RejectedCsvTest('data/reading.schema', 'data/reading_nan.csv',
+                    "row 2, column reading.temp: expected Float, got 'NaN'").run()
+    RejectedCsvTest('data/visit.schema', 'data/visit_invalid.csv',
+                    "row 2, column visit.at: expected Timestamp, got '2019-02-29'").run()
+    RejectedCsvTest('data/blob.schema', 'data/blob_invalid.csv',
+                    "row 1, column blob.payload: expected Bytes, got 'abc'").run()
+    RejectedCsvTest('data/qux_unique.schema', 'data/qux.csv').run()
+    print()
+
+    print('Running progress')
+    ProgressTest('data/sample.db', 's foo.a', 1).run()
+    ProgressTest('data/sample.db', 's bar.c\nj foo on bar.foo\nw foo.b = true', 3).run()
+    ProgressTest('data/sample.db',
+                 's bar.c, bar.d\nj foo on bar.foo\nw foo.b = true\n  foo.a = "nothing"',
+                 3, 3).run()
+    ProgressTest('data/sample.db', 's foo.a\ngroup foo.b\ncount foo.a\nw foo.a = "nothing"', 2,
+                 2).run()
+    ProgressTest('data/sample.db', 's foo.a\nw foo.id = -1', 2, 2).run()
+    ProgressTest('data/sample.db', 's bar.c\nw bar.c > 0\nw bar.time > 3 and < 2', 2, 2).run()
+    ProgressTest('data/sample.db', 's bar.c\nw bar.c > 0\nw bar.d = true', 2, scanned=6).run()
+    TimingsTest('data/sample.db', 's foo.a', 1).run()
+    TimingsTest('data/sample.db', 's bar.c\nj foo on bar.foo\nw foo.b = true', 3).run()
+    print()
+
+    print('Running thread pool sizes')
+    ThreadsTest('data/sample.db',
+                's foo.a, foo.b, bar.c, bar.d, bar.foo, post.author, post.score, book.author, '
+                'book.title').run()
+    ThreadsTest('data/sample.db',
+                's bar.c, bar.d\nj foo on bar.foo\nw foo.b = true\n'
+                '  bar.c > 1\n  bar.d = true').run()
+    FailingQueryTest('data/sample.db', 's foo.a', 'threads has to be a positive integer',
+                     1, ['--threads', '0']).run()
+    FailingQueryTest('data/sample.db', 's foo.a', 'threads has to be a positive integer',
+                     1, ['--threads', 'x']).run()
+    print()
+
+    print('Running streamed results')
+    StreamTest('data/sample.db', 's foo.a, bar.c, post.author').run()
+    StreamTest('data/sample.db', 's bar.c, foo.a\nj foo on bar.foo\nw foo.b = true').run()
+    StreamTest('data/sample.db', 's bar.c, foo.a\nj foo on bar.foo\norder bar.c desc').run()
+    StreamTest('data/sample.db', 's post.author\ngroup post.author\ncount post.id').run()
+    print()
+
+    print('Running CSV output')
+    CsvOutputTest('data/sample.db', 's note.text, note.pinned, note.stars',
+                  ''.join(['note.pinned,note.stars,note.text\r\n',
+                           'true,3,plain\r\n',
+                           'false,0,"has, comma"\r\n',
+                           'true,-2,"say ""hi"""\r\n',
+                           'false,,\r\n'])).run()
+    CsvOutputTest('data/sample.db', 's foo.a\nw foo.b = false',
+                  'foo.a\r\nthird\r\nfourth\r\n').run()
+    print()
+
+    print('Running JSON output')
+    JsonOutputTest('data/sample.db', 's note.text, note.pinned, note.stars', [
+        {'note.text': 'plain', 'note.pinned': True, 'note.stars': 3},
+        {'note.text': 'has, comma', 'note.pinned': False, 'note.stars': 0},
+        {'note.text': 'say "hi"', 'note.pinned': True, 'note.stars': -2},
+        {'note.text': None, 'note.pinned': False, 'note.stars': None},
+    ]).run()
+    JsonOutputTest('data/sample.db',
+                   's foo.a, reading.temp\nw foo.b = false\n  reading.temp > 20.0',
+                   [{'foo.a': 'third', 'reading.temp': 21.5},
+                    {'foo.a': 'fourth', 'reading.temp': 21.5},
+                    {'foo.a': None, 'reading.temp': 100.0}]).run()
+    JsonOutputTest('data/sample.db', 's foo.a\nw foo.b = false',
+                   [{'foo.a': 'third'}, {'foo.a': 'fourth'}], stream=True).run()
+    print()
+
+    print('Running explain')
+    ExplainTest('data/sample.db', 's bar.c\nj foo on bar.foo\nw foo.b = true\nw bar.time > 1',
+                '\n'.join(['stage 1/3',
+                           '  Where(foo.b, Constant(Equal, Bool(true)))',
+                           'stage 2/3',
+                           '  Where(bar.time, Constant(Greater, Int(1)))',
+                           '    time bound: 1 < time',
+                           '  Join(foo.id, bar.foo)',
+                           'stage 3/3',
+                           '  Select(bar.c, 20, 0)'])).run()
+    # Id lookups run first, then equality, range and the remaining filters
+    ExplainTest('data/sample.db',
+                's bar.c\nw bar.c > 50\nw bar.d = true\nw bar.foo != 3\nw bar.id in (1, 2)',
+                '\n'.join(['stage 1/2',
+                           '  WhereId(bar.id, [1, 2])',
+                           '  Where(bar.d, Constant(Equal, Bool(true)))',
+                           '  Where(bar.c, Constant(Greater, Int(50)))',
+                           '  Where(bar.foo, Constant(NotEqual, Int(3)))',
+                           'stage 2/2',
+                           '  Select(bar.c, 20, 0)'])).run()
+    ExplainTest('data/sample.db', 's foo.a\nw foo.id != 1\nw foo.a != "first"',
+                '\n'.join(['stage 1/2',
+                           '  Where(foo.a, Constant(NotEqual, String("first")))',
+                           '  Where(foo.id, Constant(NotEqual, Int(1)))',
+                           'stage 2/2',
+                           '  Select(foo.a, 20, 0)'])).run()
+    ExplainTest('data/sample.db', 'create table top as\ns post.score\norder post.score desc',
+                '\n'.join(['stage 1/1',
+                           '  Select(post.score, 20, 0)',
+                           'order post.score Desc',
+                           'create table top'])).run()
+    ExplainTest('data/sample.db', 's bar.c\nw bar.c = 50\nw bar.c = 60\nw bar.time > 2\nmatch any',
+                '\n'.join(['stage 1/2',
+                           '  Where(bar.c, Or(Constant(Equal, Int(50)), Constant(Equal, Int(60))))',
+                           '  Where(bar.time, Constant(Greater, Int(2)))',
+                           'stage 2/2',
+                           '  Select(bar.c, 20, 0)',
+                           'match any'])).run()
+    ExplainTest('data/sample.db', 's bar.c\nw bar.c > 0\nw bar.time > 0 and >= 3',
+                '\n'.join(['stage 1/2',
+                           '  Where(bar.c, Constant(Greater, Int(0)))',
+                           '    time bound: 2 < time',
+                           'stage 2/2',
+                           '  Select(bar.c, 20, 0)'])).run()
+    ExplainTest('data/sample.db', 's bar.c\nw bar.c > 0\nw bar.time > 3 and < 2',
+                '\n'.join(['stage 1/2',
+                           '  Where(bar.c, Constant(Greater, Int(0)))',
+                           '    time bound: 3 < time <= 1',
+                           'stage 2/2',
+                           '  Select(bar.c, 20, 0)'])).run()
+    ExplainTest('data/sample.db', 's qux.v\nw qux.v >= 20\nas of 2',
+                '\n'.join(['stage 1/2',
+                           '  Where(qux.v, Constant(GreaterOrEqual, Int(20)))',
+                           '    time bound: time <= 2, newest per id',
+                           'stage 2/2',
+                           '  Latest(qux.v, 20, 0, time <= 2)',
+                           'as of 2'])).run()
+    print()
+
+    print('Running plan JSON')
+    PlanJsonTest('data/sample.db', 's bar.c\nj foo on bar.foo\nw foo.b = true', {
+        'stages': [
+            [{'variant': 'Where',
+              'fields': [{'table': 'foo', 'column': 'b'},
+                         {'variant': 'Constant',
+                          'fields': ['Equal', {'variant': 'Bool', 'fields': [True]}]},
+                         None]}],
+            [{'variant': 'Join',
+              'fields': [{'table': 'foo', 'column': 'id'},
+                         {'table': 'bar', 'column': 'foo'},
+                         'Inner']}],
+            [{'variant': 'Select',
+              'fields': [{'table': 'bar', 'column': 'c'}, 20, 0, False]}],
+        ],
+        'order': None,
+        'create_table': None,
+    }).run()
+    PlanJsonTest('data/sample.db', 's bar.c\nw bar.time > 1\norder bar.c desc', {
+        'stages': [
+            [{'variant': 'Where',
+              'fields': [{'table': 'bar', 'column': 'time'},
+                         {'variant': 'Constant',
+                          'fields': ['Greater', {'variant': 'Int', 'fields': [1]}]},
+                         {'min': 1, 'max': None, 'latest': False}]}],
+            [{'variant': 'Select',
+              'fields': [{'table': 'bar', 'column': 'c'}, 20, 0, False]}],
+        ],
+        'order': [{'table': 'bar', 'column': 'c'}, 'Desc'],
+        'create_table': None,
+    }).run()
+
+    # `and` binds tighter than `or`, parentheses group either
+    def equal(value):
+        return {'variant': 'Constant', 'fields': ['Equal', {'variant': 'Int', 'fields': [value]}]}
+
+    def not_equal(value):
+        return {'variant': 'Constant',
+                'fields': ['NotEqual', {'variant': 'Int', 'fields': [value]}]}
+
+    for (predicate, tree) in [
+            ('(= 50 or = 60) and != 50',
+             {'variant': 'And',
+              'fields': [{'variant': 'Or', 'fields': [equal(60), equal(50)]}, not_equal(50)]}),
+            ('= 50 or = 60 and != 60',
+             {'variant': 'Or',
+              'fields': [{'variant': 'And', 'fields': [equal(60), not_equal(60)]}, equal(50)]}),
+            ('not (= 50 or (= 60 and != 70))',
+             {'variant': 'Not',
+              'fields': [{'variant': 'Or',
+                          'fields': [{'variant': 'And', 'fields': [equal(60), not_equal(70)]},
+                                     equal(50)]}]})]:
+        PlanJsonTest('data/sample.db', 's bar.c\nw bar.c {}'.format(predicate), {
+            'stages': [
+                [{'variant': 'Where',
+                  'fields': [{'table': 'bar', 'column': 'c'}, tree, None]}],
+                [{'variant': 'Select',
+                  'fields': [{'table': 'bar', 'column': 'c'}, 20, 0, False]}],
+            ],
+            'order': None,
+            'create_table': None,
+        }).run()
+    print()
+
+    print('Running hash and value indexes')
+    for query in ['s post.id\nw post.author = "ada"',
+                  's post.id\nw post.author in ("ada", "brian")',
+                  's post.id\nw post.score = 5 or = 9',
+                  's post.id\nw post.author = "nobody"',
+                  's post.id\nw post.score = 5\n  post.time > 2']:
+        IndexTest('data/post.schema', 'data/post_hashed.schema', 'data/post.csv', query).run()
+    # Prefix matches only become ranges on value indexed columns
+    for query in ['s post.id\nw post.author like "b%"',
+                  's post.id\nw post.author like "ad%"',
+                  's post.id\nw post.author like "z%"']:
+        IndexTest('data/post.schema', 'data/post_indexed.schema', 'data/post.csv', query).run()
+    print()
+
+    print('Running time range scans')
+    TimeRangeScanTest(100000, 50000, 50100).run()
+    TimeRangeScanTest(100000, -1, 0).run()
+    TimeRangeScanTest(100000, 99990, 100000).run()
+    TimeRangeScanTest(100000, 99990, 100000, batches=2).run()
+    TimeRangeScanTest(100000, 20000, 20100, batches=4).run()
+    print()
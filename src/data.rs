@@ -1,21 +1,72 @@
-use bincode;
 use bincode::rustc_serialize as serialize;
 use bincode::SizeLimit;
 use flate2::write::ZlibEncoder;
 use flate2::read::ZlibDecoder;
 use flate2::Compression;
+use rustc_serialize::hex::{FromHex, ToHex};
+use rustc_serialize::{Decodable, Encodable};
 use std::cmp;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::collections::hash_map::Entry;
 use std::fmt;
-use std::fs::File;
-use std::io;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::ops::Range;
 use std::path;
 
-#[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Hash)]
+use time;
+use timestamp;
+
+/// Floats are never NaN, it is rejected when parsing queries and loading
+/// data, which is what makes `Eq` and `Hash` hold. Timestamps are seconds
+/// since the epoch in UTC.
+#[derive(Debug, Clone, PartialEq, PartialOrd, RustcEncodable, RustcDecodable)]
 pub enum Value {
     Bool(bool),
-    Int(usize),
+    Int(i64),
     String(String),
+    Float(f64),
+    Timestamp(i64),
+    Bytes(Vec<u8>),
+}
+
+impl Eq for Value {}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match *self {
+            Value::Bool(v) => (0u8, v).hash(state),
+            Value::Int(v) => (1u8, v).hash(state),
+            Value::String(ref v) => (2u8, v).hash(state),
+            Value::Float(v) => {
+                // 0.0 and -0.0 are equal so they need the same hash
+                let v = if v == 0.0 { 0.0 } else { v };
+                (3u8, v.to_bits()).hash(state)
+            }
+            Value::Timestamp(v) => (4u8, v).hash(state),
+            Value::Bytes(ref v) => (5u8, v).hash(state),
+        }
+    }
+}
+
+/// Bytes shown when displaying a `Bytes` value
+const BYTES_PREVIEW_LEN: usize = 8;
+
+impl Value {
+    /// The value as it would be written in a CSV cell, unlike `Display`
+    /// strings aren't quoted and bytes aren't cut short
+    pub fn to_raw_string(&self) -> String {
+        match *self {
+            Value::Bool(v) => v.to_string(),
+            Value::Int(v) => v.to_string(),
+            Value::String(ref v) => v.to_owned(),
+            Value::Float(v) => format!("{:?}", v),
+            Value::Timestamp(v) => timestamp::format(v),
+            Value::Bytes(ref v) => format!("0x{}", v.to_hex()),
+        }
+    }
 }
 
 impl fmt::Display for Value {
@@ -24,6 +75,16 @@ impl fmt::Display for Value {
             Value::Bool(v) => write!(f, "{:?}", v),
             Value::Int(v) => write!(f, "{:?}", v),
             Value::String(ref v) => write!(f, "{:?}", v),
+            Value::Float(v) => write!(f, "{:?}", v),
+            Value::Timestamp(v) => write!(f, "{}", timestamp::format(v)),
+            Value::Bytes(ref v) => {
+                // Only a preview, long payloads would blow up table output
+                if v.len() > BYTES_PREVIEW_LEN {
+                    write!(f, "0x{}...", v[..BYTES_PREVIEW_LEN].to_hex())
+                } else {
+                    write!(f, "0x{}", v.to_hex())
+                }
+            }
         }
     }
 }
@@ -36,7 +97,7 @@ pub struct Datum<T> {
 }
 
 impl<T> Datum<T> {
-    fn new(id: usize, value: T, time: usize) -> Datum<T> {
+    pub fn new(id: usize, value: T, time: usize) -> Datum<T> {
         Datum {
             id: id,
             value: value,
@@ -51,7 +112,7 @@ impl<T: fmt::Display> fmt::Display for Datum<T> {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct GenericDatum {
     id: usize,
     value: Value,
@@ -88,13 +149,81 @@ impl ColumnName {
         }
     }
 
+    /// Parse a `table.column` string split on its last dot, so the table can
+    /// have dots in it. Either part can be wrapped in backticks to allow dots
+    /// anywhere, e.g. `` table.`my.column` ``.
+    ///
+    /// ```
+    /// use twin_query::ColumnName;
+    ///
+    /// assert_eq!(ColumnName::from_dotted("foo.a").unwrap(), ColumnName::new("foo", "a"));
+    /// assert_eq!(ColumnName::from_dotted("my.table.a").unwrap(),
+    ///            ColumnName::new("my.table", "a"));
+    /// assert_eq!(ColumnName::from_dotted("`my.table`.a").unwrap(),
+    ///            ColumnName::new("my.table", "a"));
+    /// assert_eq!(ColumnName::from_dotted("foo.`a.b`").unwrap(), ColumnName::new("foo", "a.b"));
+    ///
+    /// for invalid in &["foo", "foo.", ".a", "foo.`a", "`foo`x.a", ""] {
+    ///     assert!(ColumnName::from_dotted(invalid).is_err());
+    /// }
+    /// ```
+    pub fn from_dotted(dotted: &str) -> Result<ColumnName, Error> {
+        let invalid = || Error::InvalidColumnName(dotted.to_owned());
+
+        let (rest, column) = split_last_identifier(dotted);
+        if !rest.ends_with('.') {
+            return Err(invalid());
+        }
+
+        let table = unquote(&rest[..rest.len() - 1]);
+        if table.is_empty() || column.is_empty() || table.contains('`') ||
+           column.contains('`') {
+            return Err(invalid());
+        }
+
+        Ok(ColumnName::new(table, column))
+    }
+
     pub fn id(&self) -> ColumnName {
         ColumnName::new(self.table.to_owned(), "id".to_owned())
     }
+
+    /// Name of a result column renamed with `as`, it has no table
+    pub fn alias<S: Into<String>>(alias: S) -> ColumnName {
+        ColumnName::new(String::new(), alias.into())
+    }
+}
+
+/// Split a trailing, optionally backticked, identifier from the rest of `s`,
+/// the rest keeps the dot before it
+fn split_last_identifier(s: &str) -> (&str, &str) {
+    if s.len() > 1 && s.ends_with('`') {
+        if let Some(start) = s[..s.len() - 1].rfind('`') {
+            return (&s[..start], &s[start + 1..s.len() - 1]);
+        }
+        return ("", s);
+    }
+
+    match s.rfind('.') {
+        Some(index) => (&s[..index + 1], &s[index + 1..]),
+        None => ("", s),
+    }
+}
+
+/// `s` without the backticks around it, if it has them
+fn unquote(s: &str) -> &str {
+    if s.len() > 1 && s.starts_with('`') && s.ends_with('`') {
+        &s[1..s.len() - 1]
+    } else {
+        s
+    }
 }
 
 impl fmt::Display for ColumnName {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.table.is_empty() {
+            return write!(f, "{}", self.column);
+        }
         write!(f, "{}.{}", self.table, self.column)
     }
 }
@@ -104,13 +233,19 @@ pub enum ColumnType {
     Bool,
     Int,
     String,
+    Float,
+    Timestamp,
+    Bytes,
 }
 
 #[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
 pub enum Data {
     Bool(Vec<Datum<bool>>),
-    Int(Vec<Datum<usize>>),
+    Int(Vec<Datum<i64>>),
     String(Vec<Datum<String>>),
+    Float(Vec<Datum<f64>>),
+    Timestamp(Vec<Datum<i64>>),
+    Bytes(Vec<Datum<Vec<u8>>>),
 }
 
 impl Data {
@@ -136,6 +271,112 @@ impl Data {
                                                datum.time))
                     })
             }
+            Data::Float(ref data) => {
+                data.get(index)
+                    .and_then(|datum| {
+                        Some(GenericDatum::new(datum.id, Value::Float(datum.value), datum.time))
+                    })
+            }
+            Data::Timestamp(ref data) => {
+                data.get(index)
+                    .and_then(|datum| {
+                        Some(GenericDatum::new(datum.id,
+                                               Value::Timestamp(datum.value),
+                                               datum.time))
+                    })
+            }
+            Data::Bytes(ref data) => {
+                data.get(index)
+                    .and_then(|datum| {
+                        Some(GenericDatum::new(datum.id,
+                                               Value::Bytes(datum.value.clone()),
+                                               datum.time))
+                    })
+            }
+        }
+    }
+
+    pub fn value(&self, index: usize) -> Option<Value> {
+        match *self {
+            Data::Bool(ref data) => data.get(index).map(|datum| Value::Bool(datum.value)),
+            Data::Int(ref data) => data.get(index).map(|datum| Value::Int(datum.value)),
+            Data::String(ref data) => {
+                data.get(index).map(|datum| Value::String(datum.value.clone()))
+            }
+            Data::Float(ref data) => data.get(index).map(|datum| Value::Float(datum.value)),
+            Data::Timestamp(ref data) => {
+                data.get(index).map(|datum| Value::Timestamp(datum.value))
+            }
+            Data::Bytes(ref data) => data.get(index).map(|datum| Value::Bytes(datum.value.clone())),
+        }
+    }
+
+    pub fn id(&self, index: usize) -> usize {
+        match *self {
+            Data::Bool(ref data) => data[index].id,
+            Data::Int(ref data) => data[index].id,
+            Data::String(ref data) => data[index].id,
+            Data::Float(ref data) => data[index].id,
+            Data::Timestamp(ref data) => data[index].id,
+            Data::Bytes(ref data) => data[index].id,
+        }
+    }
+
+    pub fn time(&self, index: usize) -> usize {
+        match *self {
+            Data::Bool(ref data) => data[index].time,
+            Data::Int(ref data) => data[index].time,
+            Data::String(ref data) => data[index].time,
+            Data::Float(ref data) => data[index].time,
+            Data::Timestamp(ref data) => data[index].time,
+            Data::Bytes(ref data) => data[index].time,
+        }
+    }
+
+    /// Earliest and latest times, `None` when there are no datums
+    pub fn time_bounds(&self) -> Option<(usize, usize)> {
+        (0..self.len()).map(|i| self.time(i)).fold(None, |bounds, time| {
+            match bounds {
+                None => Some((time, time)),
+                Some((min, max)) => Some((cmp::min(min, time), cmp::max(max, time))),
+            }
+        })
+    }
+
+    /// Whether both hold values of the same type, which values of different
+    /// types never compare equal
+    pub fn is_same_type(&self, other: &Data) -> bool {
+        match (self, other) {
+            (&Data::Bool(_), &Data::Bool(_)) |
+            (&Data::Int(_), &Data::Int(_)) |
+            (&Data::String(_), &Data::String(_)) |
+            (&Data::Float(_), &Data::Float(_)) |
+            (&Data::Timestamp(_), &Data::Timestamp(_)) |
+            (&Data::Bytes(_), &Data::Bytes(_)) => true,
+            _ => false,
+        }
+    }
+
+    /// No datums, but of the same type
+    pub fn empty_like(&self) -> Data {
+        match *self {
+            Data::Bool(_) => Data::Bool(vec![]),
+            Data::Int(_) => Data::Int(vec![]),
+            Data::String(_) => Data::String(vec![]),
+            Data::Float(_) => Data::Float(vec![]),
+            Data::Timestamp(_) => Data::Timestamp(vec![]),
+            Data::Bytes(_) => Data::Bytes(vec![]),
+        }
+    }
+
+    pub fn column_type(&self) -> ColumnType {
+        match *self {
+            Data::Bool(_) => ColumnType::Bool,
+            Data::Int(_) => ColumnType::Int,
+            Data::String(_) => ColumnType::String,
+            Data::Float(_) => ColumnType::Float,
+            Data::Timestamp(_) => ColumnType::Timestamp,
+            Data::Bytes(_) => ColumnType::Bytes,
         }
     }
 
@@ -144,6 +385,102 @@ impl Data {
             Data::Bool(ref data) => data.len(),
             Data::Int(ref data) => data.len(),
             Data::String(ref data) => data.len(),
+            Data::Float(ref data) => data.len(),
+            Data::Timestamp(ref data) => data.len(),
+            Data::Bytes(ref data) => data.len(),
+        }
+    }
+
+    /// Drop every datum with one of `ids`, returning how many were dropped
+    pub fn remove_ids(&mut self, ids: &Ids) -> usize {
+        let len = self.len();
+        match *self {
+            Data::Bool(ref mut data) => data.retain(|datum| !ids.contains(&datum.id)),
+            Data::Int(ref mut data) => data.retain(|datum| !ids.contains(&datum.id)),
+            Data::String(ref mut data) => data.retain(|datum| !ids.contains(&datum.id)),
+            Data::Float(ref mut data) => data.retain(|datum| !ids.contains(&datum.id)),
+            Data::Timestamp(ref mut data) => data.retain(|datum| !ids.contains(&datum.id)),
+            Data::Bytes(ref mut data) => data.retain(|datum| !ids.contains(&datum.id)),
+        }
+        len - self.len()
+    }
+
+    /// First id with two datums at the same time, relies on `data` being
+    /// sorted by time so only the ids of the current time are kept
+    pub fn duplicate_time(&self) -> Option<(usize, usize)> {
+        let mut time = None;
+        let mut ids = HashSet::new();
+        for index in 0..self.len() {
+            if time != Some(self.time(index)) {
+                time = Some(self.time(index));
+                ids.clear();
+            }
+            if !ids.insert(self.id(index)) {
+                return Some((self.id(index), self.time(index)));
+            }
+        }
+        None
+    }
+
+    /// Rewrite the id of every datum with `f`
+    pub fn map_ids<F>(&mut self, f: F)
+        where F: Fn(usize) -> usize
+    {
+        fn map<T, F: Fn(usize) -> usize>(data: &mut [Datum<T>], f: F) {
+            for datum in data {
+                datum.id = f(datum.id);
+            }
+        }
+
+        match *self {
+            Data::Bool(ref mut data) => map(data, &f),
+            Data::Int(ref mut data) => map(data, &f),
+            Data::String(ref mut data) => map(data, &f),
+            Data::Float(ref mut data) => map(data, &f),
+            Data::Timestamp(ref mut data) => map(data, &f),
+            Data::Bytes(ref mut data) => map(data, &f),
+        }
+    }
+
+    /// Move the datums of `other` onto the end, callers check the types match
+    fn append(&mut self, other: Data) {
+        match (self, other) {
+            (&mut Data::Bool(ref mut data), Data::Bool(other)) => data.extend(other),
+            (&mut Data::Int(ref mut data), Data::Int(other)) => data.extend(other),
+            (&mut Data::String(ref mut data), Data::String(other)) => data.extend(other),
+            (&mut Data::Float(ref mut data), Data::Float(other)) => data.extend(other),
+            (&mut Data::Timestamp(ref mut data), Data::Timestamp(other)) => data.extend(other),
+            (&mut Data::Bytes(ref mut data), Data::Bytes(other)) => data.extend(other),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        match *self {
+            Data::Bool(ref mut data) => data.truncate(len),
+            Data::Int(ref mut data) => data.truncate(len),
+            Data::String(ref mut data) => data.truncate(len),
+            Data::Float(ref mut data) => data.truncate(len),
+            Data::Timestamp(ref mut data) => data.truncate(len),
+            Data::Bytes(ref mut data) => data.truncate(len),
+        }
+    }
+
+    /// Keep at most `limit` datums, starting `offset` datums in
+    pub fn window(&mut self, offset: usize, limit: usize) {
+        fn window<T>(data: &mut Vec<Datum<T>>, offset: usize, limit: usize) {
+            let offset = cmp::min(offset, data.len());
+            data.drain(..offset);
+            data.truncate(limit);
+        }
+
+        match *self {
+            Data::Bool(ref mut data) => window(data, offset, limit),
+            Data::Int(ref mut data) => window(data, offset, limit),
+            Data::String(ref mut data) => window(data, offset, limit),
+            Data::Float(ref mut data) => window(data, offset, limit),
+            Data::Timestamp(ref mut data) => window(data, offset, limit),
+            Data::Bytes(ref mut data) => window(data, offset, limit),
         }
     }
 
@@ -156,10 +493,309 @@ impl Data {
             Data::Bool(ref mut data) => data.sort_by(sort_by_time),
             Data::Int(ref mut data) => data.sort_by(sort_by_time),
             Data::String(ref mut data) => data.sort_by(sort_by_time),
+            Data::Float(ref mut data) => data.sort_by(sort_by_time),
+            Data::Timestamp(ref mut data) => data.sort_by(sort_by_time),
+            Data::Bytes(ref mut data) => data.sort_by(sort_by_time),
         };
     }
 }
 
+/// Starts every db file, followed by a byte for the `Codec` of the rest.
+/// Files without it are from before codecs and are all zlib.
+const MAGIC: &'static [u8] = b"twq";
+
+/// Set on the codec byte of files ending in a little endian CRC-32 of
+/// everything before it, files without it load unchecked
+const CHECKSUMMED: u8 = 0x80;
+
+/// Set on the codec byte of files with a format version byte after it,
+/// files without it have the layout from before `Column::cardinality`.
+/// Files without a codec header are older still, see `BaselineDb`.
+const VERSIONED: u8 = 0x40;
+
+/// Version of the encoded `Db` layout, raised whenever it changes. Version 2
+/// added the generation after it, version 1 files have none.
+const FORMAT_VERSION: u8 = 2;
+
+/// CRC-32 with the IEEE polynomial, the one zlib and gzip use
+struct Crc32 {
+    table: [u32; 256],
+    value: u32,
+}
+
+impl Crc32 {
+    fn new() -> Crc32 {
+        let mut table = [0; 256];
+        for (byte, entry) in table.iter_mut().enumerate() {
+            let mut crc = byte as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 { 0xedb8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            }
+            *entry = crc;
+        }
+        Crc32 {
+            table: table,
+            value: 0xffff_ffff,
+        }
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.value = self.table[((self.value ^ byte as u32) & 0xff) as usize] ^
+                         (self.value >> 8);
+        }
+    }
+
+    fn sum(&self) -> u32 {
+        !self.value
+    }
+}
+
+/// Passes writes through, keeping the CRC-32 of every byte for `finish` to
+/// append
+struct ChecksumWriter<W: Write> {
+    inner: W,
+    crc: Crc32,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    fn new(inner: W) -> ChecksumWriter<W> {
+        ChecksumWriter {
+            inner: inner,
+            crc: Crc32::new(),
+        }
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        let sum = self.crc.sum();
+        try!(self.inner.write_all(&[sum as u8, (sum >> 8) as u8, (sum >> 16) as u8,
+                                    (sum >> 24) as u8]));
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = try!(self.inner.write(buf));
+        self.crc.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The encoded db between the header and the checksum of `bytes`, `None` when
+/// the checksum doesn't match
+fn checked_payload(bytes: &[u8]) -> Option<&[u8]> {
+    if bytes.len() < MAGIC.len() + 1 + 4 {
+        return None;
+    }
+
+    let (body, trailer) = bytes.split_at(bytes.len() - 4);
+    let stored = trailer.iter().rev().fold(0, |sum, &byte| (sum << 8) | byte as u32);
+    let mut crc = Crc32::new();
+    crc.update(body);
+    if crc.sum() == stored {
+        Some(&body[MAGIC.len() + 1..])
+    } else {
+        None
+    }
+}
+
+/// Sorts `offsets` by the values of `data` they point to, returning how many
+/// distinct values there are
+fn sort_by_value<T, F>(offsets: &mut Vec<usize>, data: &[Datum<T>], compare: F) -> usize
+    where F: Fn(&T, &T) -> cmp::Ordering
+{
+    offsets.sort_by(|a, b| compare(&data[*a].value, &data[*b].value));
+    if offsets.is_empty() {
+        return 0;
+    }
+    let changes = offsets.windows(2)
+                         .filter(|pair| {
+                             compare(&data[pair[0]].value, &data[pair[1]].value) !=
+                             cmp::Ordering::Equal
+                         })
+                         .count();
+    changes + 1
+}
+
+/// Decodes a `T` from `payload`, stored with the codec tagged `codec`
+fn decode_payload<T: Decodable>(codec: u8, mut payload: &[u8]) -> Result<T, Error> {
+    match codec {
+        0 => Ok(try!(serialize::decode_from(&mut payload, SizeLimit::Infinite))),
+        1 => {
+            Ok(try!(serialize::decode_from(&mut ZlibDecoder::new(payload), SizeLimit::Infinite)))
+        }
+        _ => Err(Error::UnknownCodec(codec)),
+    }
+}
+
+/// Path of the `n`th segment appended to the Db at `file_path`
+fn segment_path(file_path: &str, n: usize) -> String {
+    format!("{}.segment{}", file_path, n)
+}
+
+/// Segments appended to the Db at `file_path`, in the order they were written
+pub fn segment_paths(file_path: &str) -> Vec<String> {
+    (1..)
+        .map(|n| segment_path(file_path, n))
+        .take_while(|segment| path::Path::new(segment).exists())
+        .collect()
+}
+
+/// A run file is its number of datums followed by the datums, sorted by time
+fn write_run<T: Encodable, W: Write>(data: &[Datum<T>], writer: &mut W) -> Result<(), Error> {
+    try!(serialize::encode_into(&(data.len() as u64), writer, SizeLimit::Infinite));
+    for datum in data {
+        try!(serialize::encode_into(datum, writer, SizeLimit::Infinite));
+    }
+    try!(writer.flush());
+    Ok(())
+}
+
+/// Reads the datums of a run file one at a time
+struct RunReader {
+    reader: io::BufReader<File>,
+    left: u64,
+}
+
+impl RunReader {
+    fn open(path: &path::Path) -> Result<RunReader, Error> {
+        let mut reader = io::BufReader::new(try!(File::open(path)));
+        let left = try!(serialize::decode_from(&mut reader, SizeLimit::Infinite));
+        Ok(RunReader {
+            reader: reader,
+            left: left,
+        })
+    }
+
+    fn next<T: Decodable>(&mut self) -> Result<Option<Datum<T>>, Error> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.left -= 1;
+        Ok(Some(try!(serialize::decode_from(&mut self.reader, SizeLimit::Infinite))))
+    }
+}
+
+/// The next datum of a run while merging, the heap pops the earliest time
+/// first and ties in the order the runs were written
+struct RunHead<T> {
+    datum: Datum<T>,
+    run: usize,
+}
+
+impl<T> Ord for RunHead<T> {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        match other.datum.time.cmp(&self.datum.time) {
+            cmp::Ordering::Equal => other.run.cmp(&self.run),
+            ordering => ordering,
+        }
+    }
+}
+
+impl<T> PartialOrd for RunHead<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> PartialEq for RunHead<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == cmp::Ordering::Equal
+    }
+}
+
+impl<T> Eq for RunHead<T> {}
+
+/// K-way merge of the run files and the datums added after them, which are
+/// treated as the last run
+fn merge_run_files<T: Decodable>(paths: &[path::PathBuf],
+                                 mut added: Vec<Datum<T>>)
+                                 -> Result<Vec<Datum<T>>, Error> {
+    let mut readers = vec![];
+    for path in paths {
+        readers.push(try!(RunReader::open(path)));
+    }
+    let len = readers.iter().fold(added.len(), |acc, reader| acc + reader.left as usize);
+
+    added.sort_by(|a, b| a.time.cmp(&b.time));
+    let mut added = added.into_iter();
+    let last = readers.len();
+
+    let mut heap = BinaryHeap::new();
+    for (run, reader) in readers.iter_mut().enumerate() {
+        if let Some(datum) = try!(reader.next()) {
+            heap.push(RunHead {
+                datum: datum,
+                run: run,
+            });
+        }
+    }
+    if let Some(datum) = added.next() {
+        heap.push(RunHead {
+            datum: datum,
+            run: last,
+        });
+    }
+
+    let mut merged = Vec::with_capacity(len);
+    while let Some(RunHead { datum, run }) = heap.pop() {
+        merged.push(datum);
+        let next = if run == last {
+            added.next()
+        } else {
+            try!(readers[run].next())
+        };
+        if let Some(datum) = next {
+            heap.push(RunHead {
+                datum: datum,
+                run: run,
+            });
+        }
+    }
+    Ok(merged)
+}
+
+/// How the encoded db is stored after the header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Codec {
+    None,
+    Zlib(CompressionLevel),
+}
+
+impl Codec {
+    fn tag(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Zlib(_) => 1,
+        }
+    }
+}
+
+/// Zlib level used by `Db::write_with_compression`, `from_file` reads any
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompressionLevel {
+    None,
+    Fast,
+    Default,
+    Best,
+}
+
+impl CompressionLevel {
+    fn to_flate2(self) -> Compression {
+        match self {
+            CompressionLevel::None => Compression::None,
+            CompressionLevel::Fast => Compression::Fast,
+            CompressionLevel::Default => Compression::Default,
+            CompressionLevel::Best => Compression::Best,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     Io(io::Error),
@@ -167,16 +803,65 @@ pub enum Error {
     Decoding(serialize::DecodingError),
     NameAlreadyTake(ColumnName),
     NameNotFound(ColumnName),
-    ParseError(ColumnName, ColumnType),
+    TableAlreadyTaken(String),
+    InvalidColumnName(String),
+    /// Column, the type it expected and the value that didn't parse as one
+    ParseError(ColumnName, ColumnType, String),
+    IdNotFound(String, usize),
+    /// An update has to be later than every value the id already has
+    StaleUpdate(ColumnName, usize),
+    UnknownCodec(u8),
+    /// A db file written with a newer layout than this build reads
+    UnknownVersion(u8),
+    /// Merging dbs that both have a column, but with different types
+    TypeMismatch(ColumnName),
+    /// A db file whose checksum doesn't match, it was truncated or changed
+    /// after it was written
+    Corrupt(String),
+    /// Column, id and time of two datums that should have been one
+    DuplicateTime(ColumnName, usize, usize),
 }
 
 pub type Ids = HashSet<usize>;
 
+/// Interns external string ids into the dense `usize` ids used internally
+#[derive(Debug, Default, RustcEncodable, RustcDecodable)]
+pub struct IdMap {
+    internal: HashMap<String, usize>,
+    external: Vec<String>,
+}
+
+impl IdMap {
+    pub fn intern(&mut self, external: &str) -> usize {
+        if let Some(&id) = self.internal.get(external) {
+            return id;
+        }
+
+        let id = self.external.len();
+        self.internal.insert(external.to_owned(), id);
+        self.external.push(external.to_owned());
+        id
+    }
+
+    pub fn internal(&self, external: &str) -> Option<usize> {
+        self.internal.get(external).cloned()
+    }
+
+    pub fn external(&self, internal: usize) -> Option<&str> {
+        self.external.get(internal).map(|id| id.as_str())
+    }
+}
+
 #[derive(Debug, RustcEncodable, RustcDecodable)]
 pub struct Column {
     pub name: ColumnName,
     pub data: Data,
     time_index: Option<[usize; 5]>,
+    value_indexed: bool,
+    value_index: Option<Vec<usize>>,
+    hash_indexed: bool,
+    hash_index: Option<HashMap<Value, Vec<usize>>>,
+    cardinality: usize,
 }
 
 impl Column {
@@ -185,12 +870,163 @@ impl Column {
             ColumnType::Bool => Data::Bool(vec![]),
             ColumnType::Int => Data::Int(vec![]),
             ColumnType::String => Data::String(vec![]),
+            ColumnType::Float => Data::Float(vec![]),
+            ColumnType::Timestamp => Data::Timestamp(vec![]),
+            ColumnType::Bytes => Data::Bytes(vec![]),
         };
         Column {
             name: name,
             data: data,
             time_index: None,
+            value_indexed: false,
+            value_index: None,
+            hash_indexed: false,
+            hash_index: None,
+            cardinality: 0,
+        }
+    }
+
+    fn from_data(name: ColumnName, data: Data) -> Self {
+        Column {
+            name: name,
+            data: data,
+            time_index: None,
+            value_indexed: false,
+            value_index: None,
+            hash_indexed: false,
+            hash_index: None,
+            cardinality: 0,
+        }
+    }
+
+    /// Offsets into `data` with values equal to one of `values`, looked up
+    /// in the hash index. `None` if the column has no hash index.
+    pub fn offsets_equal_to(&self, values: &[&Value]) -> Option<Vec<usize>> {
+        let index = match self.hash_index {
+            Some(ref index) => index,
+            None => return None,
+        };
+
+        let mut offsets = vec![];
+        for value in values {
+            if let Some(matches) = index.get(*value) {
+                offsets.extend(matches.iter().cloned());
+            }
+        }
+        Some(offsets)
+    }
+
+    /// Offsets into `data` with values in `[min, max]`, found by binary
+    /// searching the value index. `None` if the column has no value index.
+    pub fn offsets_in_range(&self, min: Option<&Value>, max: Option<&Value>) -> Option<&[usize]> {
+        let index = match self.value_index {
+            Some(ref index) => index,
+            None => return None,
+        };
+
+        let start = match min {
+            Some(min) => self.partition_point(index, |value| value < min),
+            None => 0,
+        };
+        let end = match max {
+            Some(max) => self.partition_point(index, |value| value <= max),
+            None => index.len(),
+        };
+
+        if start >= end {
+            return Some(&index[0..0]);
+        }
+        Some(&index[start..end])
+    }
+
+    fn partition_point<F>(&self, index: &[usize], pred: F) -> usize
+        where F: Fn(&Value) -> bool
+    {
+        let (mut low, mut high) = (0, index.len());
+
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if pred(&self.data.value(index[mid]).unwrap()) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        low
+    }
+
+    /// Number of distinct values in the column as of its last reindex
+    ///
+    /// ```
+    /// # use std::fs;
+    /// use twin_query::{insert, ColumnName, Db};
+    ///
+    /// let path = std::env::temp_dir().join("twin-query-cardinality.db");
+    /// let path = path.to_str().unwrap();
+    /// let _ = fs::remove_file(path);
+    /// insert::add_to_db(path, "data/foo.schema", Some("data/foo.csv"), Default::default())
+    ///     .unwrap();
+    ///
+    /// let db = Db::from_file(path).unwrap();
+    /// assert_eq!(db.cols[&ColumnName::new("foo", "a")].cardinality(), 4);
+    /// assert_eq!(db.cols[&ColumnName::new("foo", "b")].cardinality(), 2);
+    /// fs::remove_file(path).unwrap();
+    /// ```
+    pub fn cardinality(&self) -> usize {
+        self.cardinality
+    }
+
+    pub fn has_value_index(&self) -> bool {
+        self.value_index.is_some()
+    }
+
+    pub fn has_time_index(&self) -> bool {
+        self.time_index.is_some()
+    }
+
+    /// Offsets into `data` with times in `(min, max]`. Relies on `data` being
+    /// sorted by time, the time index narrows where the binary search starts.
+    pub fn offsets_in_time_range(&self, min: Option<i64>, max: Option<i64>) -> Range<usize> {
+        let start = match min {
+            Some(min) => self.time_partition_point(|time| time as i64 <= min),
+            None => 0,
+        };
+        let end = match max {
+            Some(max) => self.time_partition_point(|time| time as i64 <= max),
+            None => self.data.len(),
+        };
+
+        start..cmp::max(start, end)
+    }
+
+    fn time_partition_point<F>(&self, pred: F) -> usize
+        where F: Fn(usize) -> bool
+    {
+        let (mut low, mut high) = (0, self.data.len());
+
+        if let Some(ref index) = self.time_index {
+            let increment = high / 5;
+            for (i, &time) in index.iter().enumerate() {
+                if pred(time) {
+                    low = increment * i + 1;
+                } else {
+                    high = increment * i;
+                    break;
+                }
+            }
         }
+
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if pred(self.data.time(mid)) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        low
     }
 
     fn sort(&mut self) {
@@ -202,7 +1038,9 @@ impl Column {
         let len = self.data.len();
         let mut index = [0, 0, 0, 0, 0];
 
+        // Deletes can shrink a column, so an older index may be stale
         if len < 5 {
+            self.time_index = None;
             return;
         }
 
@@ -214,60 +1052,416 @@ impl Column {
         self.time_index = Some(index);
     }
 
-    fn add_datum(&mut self, id: usize, value: String, time: usize) -> Result<(), Error> {
-        match self.data {
-            Data::Bool(ref mut data) => {
-                match value.parse::<bool>() {
-                    Ok(v) => data.push(Datum::new(id, v, time)),
-                    Err(_) => return Err(Error::ParseError(self.name.clone(), ColumnType::Bool)),
-                }
+    /// Compares the first and last sampled times of the index with the data,
+    /// columns changed without a reindex usually fail this
+    ///
+    /// ```
+    /// # use std::fs;
+    /// use twin_query::{insert, ColumnName, Data, Datum, Db};
+    ///
+    /// let path = std::env::temp_dir().join("twin-query-stale.db");
+    /// let path = path.to_str().unwrap();
+    /// let _ = fs::remove_file(path);
+    /// insert::add_to_db(path, "data/qux.schema", Some("data/qux.csv"), Default::default())
+    ///     .unwrap();
+    ///
+    /// let mut db = Db::from_file(path).unwrap();
+    /// let name = ColumnName::new("qux", "v");
+    /// assert!(!db.cols[&name].time_index_is_stale());
+    ///
+    /// // Same length, later times, the index still samples the old ones
+    /// db.cols.get_mut(&name).unwrap().data =
+    ///     Data::Int((0..7).map(|i| Datum::new(1, i as i64, 100 + i)).collect());
+    /// assert!(db.cols[&name].time_index_is_stale());
+    ///
+    /// db.rebuild_indexes();
+    /// assert!(!db.cols[&name].time_index_is_stale());
+    /// fs::remove_file(path).unwrap();
+    /// ```
+    pub fn time_index_is_stale(&self) -> bool {
+        let len = self.data.len();
+        match self.time_index {
+            None => len >= 5,
+            Some(_) if len < 5 => true,
+            Some(ref index) => {
+                let increment = len / 5;
+                index[0] != self.data.time(0) || index[4] != self.data.time(increment * 4)
             }
-            Data::Int(ref mut data) => {
-                match value.parse::<usize>() {
-                    Ok(v) => data.push(Datum::new(id, v, time)),
-                    _ => return Err(Error::ParseError(self.name.clone(), ColumnType::Int)),
-                }
+        }
+    }
+
+    /// Sorts the offsets by value to count the distinct values, only value
+    /// indexed columns keep them
+    fn index_by_value(&mut self) {
+        let mut offsets = (0..self.data.len()).collect::<Vec<usize>>();
+
+        self.cardinality = match self.data {
+            Data::Bool(ref data) => sort_by_value(&mut offsets, data, |a, b| a.cmp(b)),
+            Data::Int(ref data) => sort_by_value(&mut offsets, data, |a, b| a.cmp(b)),
+            Data::String(ref data) => sort_by_value(&mut offsets, data, |a, b| a.cmp(b)),
+            Data::Float(ref data) => {
+                sort_by_value(&mut offsets,
+                              data,
+                              |a, b| a.partial_cmp(b).unwrap_or(cmp::Ordering::Equal))
             }
-            Data::String(ref mut data) => data.push(Datum::new(id, value, time)),
+            Data::Timestamp(ref data) => sort_by_value(&mut offsets, data, |a, b| a.cmp(b)),
+            Data::Bytes(ref data) => sort_by_value(&mut offsets, data, |a, b| a.cmp(b)),
         };
+
+        self.value_index = if self.value_indexed { Some(offsets) } else { None };
+    }
+
+    fn index_by_hash(&mut self) {
+        if !self.hash_indexed {
+            return;
+        }
+
+        let mut index: HashMap<Value, Vec<usize>> = HashMap::new();
+        for offset in 0..self.data.len() {
+            index.entry(self.data.value(offset).unwrap()).or_insert_with(Vec::new).push(offset);
+        }
+
+        self.hash_index = Some(index);
+    }
+
+    /// Remove the datums of `ids` and rebuild the indexes over what's left,
+    /// removing never changes the order of the remaining datums
+    fn delete(&mut self, ids: &Ids) -> usize {
+        let removed = self.data.remove_ids(ids);
+        if removed > 0 {
+            self.reindex();
+        }
+        removed
+    }
+
+    fn reindex(&mut self) {
+        self.sort();
+        self.index_by_time();
+        self.index_by_value();
+        self.index_by_hash();
+    }
+
+    /// Parse a raw value, as written in a CSV, into this column's type
+    fn parse_value(&self, value: String) -> Result<Value, Error> {
+        let t = self.data.column_type();
+
+        let parsed = match t {
+            ColumnType::Bool => value.parse::<bool>().ok().map(Value::Bool),
+            ColumnType::Int => value.parse::<i64>().ok().map(Value::Int),
+            ColumnType::String => return Ok(Value::String(value)),
+            ColumnType::Float => {
+                value.parse::<f64>().ok().and_then(|v| if v.is_nan() { None } else { Some(v) })
+                     .map(Value::Float)
+            }
+            ColumnType::Timestamp => timestamp::parse(&value).map(Value::Timestamp),
+            ColumnType::Bytes => {
+                let hex = if value.starts_with("0x") { &value[2..] } else { &value[..] };
+                hex.from_hex().ok().map(Value::Bytes)
+            }
+        };
+
+        match parsed {
+            Some(parsed) => Ok(parsed),
+            None => Err(Error::ParseError(self.name.clone(), t, value)),
+        }
+    }
+
+    /// Whether `value` is of this column's type
+    pub fn holds(&self, value: &Value) -> bool {
+        match (&self.data, value) {
+            (&Data::Bool(_), &Value::Bool(_)) |
+            (&Data::Int(_), &Value::Int(_)) |
+            (&Data::String(_), &Value::String(_)) |
+            (&Data::Float(_), &Value::Float(_)) |
+            (&Data::Timestamp(_), &Value::Timestamp(_)) |
+            (&Data::Bytes(_), &Value::Bytes(_)) => true,
+            _ => false,
+        }
+    }
+
+    /// Push a value returned by `parse_value`, so of this column's type
+    fn push_value(&mut self, id: usize, value: Value, time: usize) {
+        match (&mut self.data, value) {
+            (&mut Data::Bool(ref mut data), Value::Bool(v)) => data.push(Datum::new(id, v, time)),
+            (&mut Data::Int(ref mut data), Value::Int(v)) => data.push(Datum::new(id, v, time)),
+            (&mut Data::String(ref mut data), Value::String(v)) => {
+                data.push(Datum::new(id, v, time))
+            }
+            (&mut Data::Float(ref mut data), Value::Float(v)) => data.push(Datum::new(id, v, time)),
+            (&mut Data::Timestamp(ref mut data), Value::Timestamp(v)) => {
+                data.push(Datum::new(id, v, time))
+            }
+            (&mut Data::Bytes(ref mut data), Value::Bytes(v)) => data.push(Datum::new(id, v, time)),
+            _ => unreachable!(),
+        }
+    }
+
+    fn add_datum(&mut self, id: usize, value: String, time: usize) -> Result<(), Error> {
+        let value = try!(self.parse_value(value));
+        self.push_value(id, value, time);
         Ok(())
     }
 }
 
+/// `Data` as the first, headerless, db files store it
+#[derive(RustcDecodable)]
+enum BaselineData {
+    Bool(Vec<Datum<bool>>),
+    Int(Vec<Datum<usize>>),
+    String(Vec<Datum<String>>),
+}
+
+impl From<BaselineData> for Data {
+    /// Ints were unsigned
+    fn from(data: BaselineData) -> Data {
+        match data {
+            BaselineData::Bool(data) => Data::Bool(data),
+            BaselineData::Int(data) => {
+                Data::Int(data.into_iter()
+                              .map(|d| Datum::new(d.id, d.value as i64, d.time))
+                              .collect())
+            }
+            BaselineData::String(data) => Data::String(data),
+        }
+    }
+}
+
+/// `Column` as headerless files store it, with only a time index
+#[derive(RustcDecodable)]
+struct BaselineColumn {
+    name: ColumnName,
+    data: BaselineData,
+    time_index: Option<[usize; 5]>,
+}
+
+/// `Db` as headerless files store it, from before string ids were interned
+#[derive(RustcDecodable)]
+struct BaselineDb {
+    cols: HashMap<ColumnName, BaselineColumn>,
+    ids: HashMap<String, Ids>,
+}
+
+impl From<BaselineDb> for Db {
+    /// None of the indexes added since were stored, they're all built as the
+    /// columns load
+    fn from(baseline: BaselineDb) -> Db {
+        let cols = baseline.cols
+                           .into_iter()
+                           .map(|(name, col)| {
+                               let mut column = Column::from_data(col.name,
+                                                                  Data::from(col.data));
+                               column.time_index = col.time_index;
+                               column.reindex();
+                               (name, column)
+                           })
+                           .collect();
+        Db {
+            cols: cols,
+            ids: baseline.ids,
+            id_maps: HashMap::new(),
+        }
+    }
+}
+
+/// `Column` as files with a codec header but no format version store it,
+/// from before the cardinality was kept
+#[derive(RustcDecodable)]
+struct UnversionedColumn {
+    name: ColumnName,
+    data: Data,
+    time_index: Option<[usize; 5]>,
+    value_indexed: bool,
+    value_index: Option<Vec<usize>>,
+    hash_indexed: bool,
+    hash_index: Option<HashMap<Value, Vec<usize>>>,
+}
+
+/// `Db` as files with a codec header but no format version store it
+#[derive(RustcDecodable)]
+struct UnversionedDb {
+    cols: HashMap<ColumnName, UnversionedColumn>,
+    ids: HashMap<String, Ids>,
+    id_maps: HashMap<String, IdMap>,
+}
+
+impl From<UnversionedDb> for Db {
+    /// The cardinality is counted as the columns load
+    fn from(unversioned: UnversionedDb) -> Db {
+        let cols = unversioned.cols
+                         .into_iter()
+                         .map(|(name, col)| {
+                             let mut column = Column {
+                                 name: col.name,
+                                 data: col.data,
+                                 time_index: col.time_index,
+                                 value_indexed: col.value_indexed,
+                                 value_index: col.value_index,
+                                 hash_indexed: col.hash_indexed,
+                                 hash_index: col.hash_index,
+                                 cardinality: 0,
+                             };
+                             column.index_by_value();
+                             (name, column)
+                         })
+                         .collect();
+        Db {
+            cols: cols,
+            ids: unversioned.ids,
+            id_maps: unversioned.id_maps,
+        }
+    }
+}
+
 #[derive(Debug, RustcEncodable, RustcDecodable)]
 pub struct Db {
     pub cols: HashMap<ColumnName, Column>,
     pub ids: HashMap<String, Ids>,
+    pub id_maps: HashMap<String, IdMap>,
 }
 
 impl Db {
-    fn new() -> Db {
+    pub fn new() -> Db {
         Db {
             cols: HashMap::new(),
             ids: HashMap::new(),
+            id_maps: HashMap::new(),
         }
     }
 
+    /// Load the Db at `file_path` along with the segments appended to it
     pub fn from_file(file_path: &str) -> Result<Db, Error> {
+        Db::load(file_path).map(|(db, _)| db)
+    }
+
+    /// `from_file` along with the generation of the file at `file_path`
+    fn load(file_path: &str) -> Result<(Db, u64), Error> {
         if !path::Path::new(file_path).exists() {
             try!(File::create(file_path));
-            return Ok(Db::new());
+            return Ok((Db::new(), 0));
         }
 
-        let file = try!(File::open(file_path));
-        let reader = io::BufReader::new(file);
-        let mut zlib_decoder = ZlibDecoder::new(reader);
-        let decoded = try!(serialize::decode_from(&mut zlib_decoder, SizeLimit::Infinite));
+        let (mut db, generation) = try!(Db::read_file(file_path));
+        let mut merged = HashSet::new();
+        for segment in segment_paths(file_path) {
+            // Appended to an older generation of the Db, which already folded
+            // it in before its rewrite was interrupted
+            let (segment, segment_generation) = try!(Db::read_file(&segment));
+            if segment_generation == generation {
+                merged.extend(try!(db.merge_unindexed(segment)));
+            }
+        }
+        db.reindex_columns(merged);
+        Ok((db, generation))
+    }
+
+    /// The Db in `file_path` and its generation, files from before
+    /// generations are all generation 0
+    fn read_file(file_path: &str) -> Result<(Db, u64), Error> {
+        let mut bytes = vec![];
+        try!(File::open(file_path).and_then(|mut file| file.read_to_end(&mut bytes)));
+
+        let mut decoded = if bytes.len() > MAGIC.len() && bytes.starts_with(MAGIC) {
+            let tag = bytes[MAGIC.len()];
+            let payload = if tag & CHECKSUMMED == CHECKSUMMED {
+                try!(checked_payload(&bytes).ok_or_else(|| Error::Corrupt(file_path.to_owned())))
+            } else {
+                &bytes[MAGIC.len() + 1..]
+            };
+            let codec = tag & !(CHECKSUMMED | VERSIONED);
+            if tag & VERSIONED != VERSIONED {
+                (Db::from(try!(decode_payload::<UnversionedDb>(codec, payload))), 0)
+            } else {
+                match payload.first() {
+                    Some(&1) => (try!(decode_payload(codec, &payload[1..])), 0),
+                    Some(&FORMAT_VERSION) if payload.len() >= 9 => {
+                        let generation = payload[1..9]
+                                             .iter()
+                                             .rev()
+                                             .fold(0, |sum, &byte| (sum << 8) | byte as u64);
+                        (try!(decode_payload(codec, &payload[9..])), generation)
+                    }
+                    Some(&FORMAT_VERSION) | None => {
+                        return Err(Error::Corrupt(file_path.to_owned()))
+                    }
+                    Some(&version) => return Err(Error::UnknownVersion(version)),
+                }
+            }
+        } else {
+            let mut decoder = ZlibDecoder::new(&bytes[..]);
+            let baseline: BaselineDb = try!(serialize::decode_from(&mut decoder,
+                                                                   SizeLimit::Infinite));
+            (Db::from(baseline), 0)
+        };
+        decoded.0.rebuild_indexes();
 
         Ok(decoded)
     }
 
     pub fn write(&self, filename: &str) -> Result<(), Error> {
+        self.write_with_compression(filename, CompressionLevel::Fast)
+    }
+
+    pub fn write_with_compression(&self,
+                                  filename: &str,
+                                  level: CompressionLevel)
+                                  -> Result<(), Error> {
+        self.write_with_codec(filename, Codec::Zlib(level))
+    }
+
+    /// Rewrite the whole Db, the segments of `filename` are folded into it so
+    /// they're removed. The rewrite starts a new generation, segments left by
+    /// an interrupted removal belong to the old one and are skipped on load.
+    pub fn write_with_codec(&self, filename: &str, codec: Codec) -> Result<(), Error> {
+        // Written next to the Db then renamed over it, a failed write leaves
+        // the Db and its segments as they were
+        let tmp_path = format!("{}.tmp", filename);
+        if let Err(e) = self.write_file(&tmp_path, codec, time::precise_time_ns())
+                            .and_then(|_| fs::rename(&tmp_path, filename).map_err(Error::from)) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+        // Last first, what's left after a failure still numbers from 1
+        for segment in segment_paths(filename).into_iter().rev() {
+            try!(fs::remove_file(segment));
+        }
+        Ok(())
+    }
+
+    /// Write only this Db as the next segment of the Db at `file_path`
+    /// instead of rewriting it, `from_file` merges the segments back in.
+    /// Columns the Db already has must keep their types, a segment that
+    /// doesn't merge would stop the Db from loading.
+    pub fn append_to_file(&self, file_path: &str) -> Result<(), Error> {
+        if !path::Path::new(file_path).exists() {
+            return self.write(file_path);
+        }
+        let (existing, generation) = try!(Db::load(file_path));
+        try!(existing.check_types(self));
+
+        let segment = segment_path(file_path, segment_paths(file_path).len() + 1);
+        self.write_file(&segment, Codec::Zlib(CompressionLevel::Fast), generation)
+    }
+
+    fn write_file(&self, filename: &str, codec: Codec, generation: u64) -> Result<(), Error> {
         let path = path::Path::new(filename);
-        let writer = io::BufWriter::new(try!(File::create(path)));
-        let mut encoder = ZlibEncoder::new(writer, Compression::Fast);
+        let mut writer = ChecksumWriter::new(io::BufWriter::new(try!(File::create(path))));
+        try!(writer.write_all(MAGIC));
+        try!(writer.write_all(&[codec.tag() | CHECKSUMMED | VERSIONED, FORMAT_VERSION]));
+        let little_endian = (0..8).map(|i| (generation >> (8 * i)) as u8).collect::<Vec<u8>>();
+        try!(writer.write_all(&little_endian));
 
-        try!(bincode::rustc_serialize::encode_into(self, &mut encoder, SizeLimit::Infinite));
+        let writer = match codec {
+            Codec::None => {
+                try!(serialize::encode_into(self, &mut writer, SizeLimit::Infinite));
+                writer
+            }
+            Codec::Zlib(level) => {
+                let mut encoder = ZlibEncoder::new(writer, level.to_flate2());
+                try!(serialize::encode_into(self, &mut encoder, SizeLimit::Infinite));
+                try!(encoder.finish())
+            }
+        };
+        try!(try!(writer.finish()).flush());
         Ok(())
     }
 
@@ -282,27 +1476,342 @@ impl Db {
         }
     }
 
-    pub fn add_datum(&mut self, name: &ColumnName, id: usize, value: String, time: usize)
+    /// Parse a raw value for a column without adding it, `add_value` adds it
+    pub fn parse_value(&self, name: &ColumnName, value: String) -> Result<Value, Error> {
+        match self.cols.get(name) {
+            Some(col) => col.parse_value(value),
+            None => Err(Error::NameNotFound(name.to_owned())),
+        }
+    }
+
+    pub fn add_value(&mut self, name: &ColumnName, id: usize, value: Value, time: usize)
                      -> Result<(), Error> {
-        let (mut col, mut ids) = match (self.cols.get_mut(name), self.ids.get_mut(&name.table)) {
+        let (col, ids) = match (self.cols.get_mut(name), self.ids.get_mut(&name.table)) {
             (Some(c), Some(i)) => (c, i),
             _ => return Err(Error::NameNotFound(name.to_owned())),
         };
 
-        try!(col.add_datum(id, value, time));
+        if !col.holds(&value) {
+            return Err(Error::TypeMismatch(name.to_owned()));
+        }
+        col.push_value(id, value, time);
         ids.insert(id);
         Ok(())
     }
 
+    /// Remove the datums of `ids` from a column, returning how many were
+    /// removed. Deleting from a table's `id` column removes whole rows, the
+    /// ids are dropped from every column of the table and from the table
+    /// itself, other columns just lose their values for those ids.
+    pub fn delete(&mut self, name: &ColumnName, ids: &Ids) -> Result<usize, Error> {
+        if !self.cols.contains_key(name) {
+            return Err(Error::NameNotFound(name.to_owned()));
+        }
+
+        if name.column != "id" {
+            return Ok(self.cols.get_mut(name).map_or(0, |col| col.delete(ids)));
+        }
+
+        let mut removed = 0;
+        for (col_name, col) in &mut self.cols {
+            if col_name.table == name.table {
+                removed += col.delete(ids);
+            }
+        }
+        if let Some(table_ids) = self.ids.get_mut(&name.table) {
+            *table_ids = table_ids.difference(ids).cloned().collect();
+        }
+        Ok(removed)
+    }
+
+    /// Append a newer value for an id already in the table. Older datums are
+    /// kept, so time bounded queries still see the value as it was.
+    pub fn update(&mut self, name: &ColumnName, id: usize, value: String, time: usize)
+                  -> Result<(), Error> {
+        if !self.ids.get(&name.table).map_or(false, |ids| ids.contains(&id)) {
+            return Err(Error::IdNotFound(name.table.to_owned(), id));
+        }
+        let col = match self.cols.get_mut(name) {
+            Some(col) => col,
+            None => return Err(Error::NameNotFound(name.to_owned())),
+        };
+
+        let latest = (0..col.data.len())
+                         .filter(|&index| col.data.id(index) == id)
+                         .map(|index| col.data.time(index))
+                         .max();
+        if latest.map_or(false, |latest| time <= latest) {
+            return Err(Error::StaleUpdate(name.to_owned(), id));
+        }
+
+        try!(col.add_datum(id, value, time));
+        col.reindex();
+        Ok(())
+    }
+
+    /// Store query results as the columns of a new table, adding the `id`
+    /// and `time` columns every table has. Ids and times are kept as is,
+    /// derived names like `count(id)` are turned into `count_id`.
+    pub fn add_table(&mut self, table: &str, columns: Vec<(ColumnName, Data)>) -> Result<(), Error> {
+        if self.ids.contains_key(table) {
+            return Err(Error::TableAlreadyTaken(table.to_owned()));
+        }
+
+        let mut rows = HashSet::new();
+        let mut new_cols = HashMap::new();
+
+        for (name, data) in columns {
+            let new_name = ColumnName::new(table.to_owned(), to_identifier(&name.column));
+            if new_name.column == "id" || new_name.column == "time" ||
+               new_cols.contains_key(&new_name) {
+                return Err(Error::NameAlreadyTake(new_name));
+            }
+
+            for index in 0..data.len() {
+                if let Some(datum) = data.get(index) {
+                    rows.insert((datum.id, datum.time));
+                }
+            }
+            new_cols.insert(new_name.clone(), Column::from_data(new_name, data));
+        }
+
+        let mut rows = rows.into_iter().collect::<Vec<(usize, usize)>>();
+        rows.sort();
+
+        let id_name = ColumnName::new(table, "id");
+        let id_data = rows.iter().map(|&(id, time)| Datum::new(id, id as i64, time)).collect();
+        new_cols.insert(id_name.clone(), Column::from_data(id_name, Data::Int(id_data)));
+
+        let time_name = ColumnName::new(table, "time");
+        let time_data = rows.iter().map(|&(id, time)| Datum::new(id, time as i64, time)).collect();
+        new_cols.insert(time_name.clone(), Column::from_data(time_name, Data::Int(time_data)));
+
+        self.ids.insert(table.to_owned(), rows.iter().map(|&(id, _)| id).collect());
+        self.cols.extend(new_cols);
+        Ok(())
+    }
+
+    /// Union `other` into this db, columns in both are appended to. String ids
+    /// of `other` are interned again so they can't collide with ours.
+    pub fn merge(&mut self, other: Db) -> Result<(), Error> {
+        let merged = try!(self.merge_unindexed(other));
+        self.reindex_columns(merged);
+        Ok(())
+    }
+
+    fn reindex_columns(&mut self, names: HashSet<ColumnName>) {
+        for name in names {
+            if let Some(col) = self.cols.get_mut(&name) {
+                col.reindex();
+            }
+        }
+    }
+
+    /// Fails on the first column of `other` that this Db has with another type
+    pub fn check_types(&self, other: &Db) -> Result<(), Error> {
+        for (name, col) in &other.cols {
+            if let Some(existing) = self.cols.get(name) {
+                if !existing.data.is_same_type(&col.data) {
+                    return Err(Error::TypeMismatch(name.to_owned()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// `merge` without reindexing, returns the columns that need it so
+    /// merging several dbs only reindexes once
+    fn merge_unindexed(&mut self, other: Db) -> Result<HashSet<ColumnName>, Error> {
+        try!(self.check_types(&other));
+
+        let Db { cols, ids, id_maps } = other;
+
+        let mut remaps = HashMap::new();
+        for (table, id_map) in id_maps {
+            let remap = id_map.external
+                              .iter()
+                              .map(|external| self.intern_id(&table, external))
+                              .collect::<Vec<usize>>();
+            remaps.insert(table, remap);
+        }
+
+        for (table, table_ids) in ids {
+            let table_ids = match remaps.get(&table) {
+                Some(remap) => table_ids.iter().map(|&id| remap[id]).collect(),
+                None => table_ids,
+            };
+            self.ids.entry(table).or_insert_with(HashSet::new).extend(table_ids);
+        }
+
+        let mut merged = HashSet::new();
+        for (name, mut col) in cols {
+            if let Some(remap) = remaps.get(&name.table) {
+                col.data.map_ids(|id| remap[id]);
+            }
+
+            merged.insert(name.clone());
+            match self.cols.entry(name) {
+                Entry::Occupied(mut entry) => {
+                    let existing = entry.get_mut();
+                    existing.data.append(col.data);
+                    existing.value_indexed = existing.value_indexed || col.value_indexed;
+                    existing.hash_indexed = existing.hash_indexed || col.hash_indexed;
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(col);
+                }
+            }
+        }
+        Ok(merged)
+    }
+
+    /// Move every datum of `table` to a run in `run_dir`, a file per column
+    /// sorted by time, to bound memory while adding. `merge_runs` reads them
+    /// back.
+    pub fn spill_table(&mut self, table: &str, run_dir: &path::Path) -> Result<(), Error> {
+        try!(fs::create_dir_all(run_dir));
+        for (name, col) in &mut self.cols {
+            if name.table != table {
+                continue;
+            }
+            let empty = col.data.empty_like();
+            let mut data = mem::replace(&mut col.data, empty);
+            data.sort();
+
+            let path = run_dir.join(format!("{}", name));
+            let mut writer = io::BufWriter::new(try!(File::create(path)));
+            try!(match data {
+                Data::Bool(ref data) => write_run(data, &mut writer),
+                Data::Int(ref data) => write_run(data, &mut writer),
+                Data::String(ref data) => write_run(data, &mut writer),
+                Data::Float(ref data) => write_run(data, &mut writer),
+                Data::Timestamp(ref data) => write_run(data, &mut writer),
+                Data::Bytes(ref data) => write_run(data, &mut writer),
+            });
+        }
+        Ok(())
+    }
+
+    /// Put the runs written by `spill_table` back ahead of the datums added
+    /// since. A column's runs are merged a datum at a time, so only the
+    /// merged column is held in memory.
+    pub fn merge_runs(&mut self, run_dirs: &[path::PathBuf]) -> Result<(), Error> {
+        for (name, col) in &mut self.cols {
+            let runs = run_dirs.iter()
+                               .map(|dir| dir.join(format!("{}", name)))
+                               .filter(|path| path.exists())
+                               .collect::<Vec<path::PathBuf>>();
+            if runs.is_empty() {
+                continue;
+            }
+
+            let empty = col.data.empty_like();
+            col.data = match mem::replace(&mut col.data, empty) {
+                Data::Bool(data) => Data::Bool(try!(merge_run_files(&runs, data))),
+                Data::Int(data) => Data::Int(try!(merge_run_files(&runs, data))),
+                Data::String(data) => Data::String(try!(merge_run_files(&runs, data))),
+                Data::Float(data) => Data::Float(try!(merge_run_files(&runs, data))),
+                Data::Timestamp(data) => Data::Timestamp(try!(merge_run_files(&runs, data))),
+                Data::Bytes(data) => Data::Bytes(try!(merge_run_files(&runs, data))),
+            };
+        }
+        Ok(())
+    }
+
+    /// Ids of every table added up, the same id in two tables counts twice
+    pub fn entity_count(&self) -> usize {
+        self.ids.values().map(|ids| ids.len()).fold(0, |acc, len| acc + len)
+    }
+
+    /// Names of a table's columns, sorted
+    pub fn table_columns(&self, table: &str) -> Vec<&str> {
+        let mut columns = self.cols
+                              .keys()
+                              .filter(|name| name.table == table)
+                              .map(|name| name.column.as_str())
+                              .collect::<Vec<&str>>();
+        columns.sort();
+        columns
+    }
+
+    /// Internal id for a table's external string id, allocating a new one
+    /// the first time it is seen
+    pub fn intern_id(&mut self, table: &str, external: &str) -> usize {
+        self.id_maps
+            .entry(table.to_owned())
+            .or_insert_with(IdMap::default)
+            .intern(external)
+    }
+
+    pub fn external_id(&self, table: &str, internal: usize) -> Option<&str> {
+        self.id_maps.get(table).and_then(|map| map.external(internal))
+    }
+
+    /// Maintain a value sorted index for this column, built alongside the
+    /// time index in `optimize_columns`
+    pub fn add_value_index(&mut self, name: &ColumnName) -> Result<(), Error> {
+        match self.cols.get_mut(name) {
+            Some(col) => {
+                col.value_indexed = true;
+                Ok(())
+            }
+            None => Err(Error::NameNotFound(name.to_owned())),
+        }
+    }
+
+    /// Maintain a value to offsets hash index for this column, answering
+    /// equality predicates without a scan. Best kept for high cardinality
+    /// columns, the index holds every offset.
+    pub fn add_hash_index(&mut self, name: &ColumnName) -> Result<(), Error> {
+        match self.cols.get_mut(name) {
+            Some(col) => {
+                col.hash_indexed = true;
+                Ok(())
+            }
+            None => Err(Error::NameNotFound(name.to_owned())),
+        }
+    }
+
+    /// Sort and reindex the columns with a missing or stale time index
+    #[allow(for_kv_map)]
+    pub fn rebuild_indexes(&mut self) {
+        for (_, col) in &mut self.cols {
+            if col.time_index_is_stale() {
+                col.reindex();
+            }
+        }
+    }
+
+    /// Fails on the first column of `table`, by name, with two datums for an
+    /// id at the same time. Columns have to be sorted already.
+    pub fn check_unique_times(&self, table: &str) -> Result<(), Error> {
+        let mut names = self.cols.keys().filter(|name| name.table == table).collect::<Vec<_>>();
+        names.sort_by(|a, b| a.column.cmp(&b.column));
+
+        for name in names {
+            if let Some((id, time)) = self.cols[name].data.duplicate_time() {
+                return Err(Error::DuplicateTime(name.to_owned(), id, time));
+            }
+        }
+        Ok(())
+    }
+
     #[allow(for_kv_map)]
     pub fn optimize_columns(&mut self) {
         for (_, col) in &mut self.cols {
-            col.sort();
-            col.index_by_time()
+            col.reindex();
         }
     }
 }
 
+fn to_identifier(column: &str) -> String {
+    column.chars()
+          .map(|c| if (c as u32) < 128 && c.is_alphanumeric() { c } else { '_' })
+          .collect::<String>()
+          .trim_matches('_')
+          .to_owned()
+}
+
 impl Default for Db {
     fn default() -> Db {
         Db::new()
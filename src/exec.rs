@@ -1,9 +1,16 @@
 use crossbeam;
+use libc;
+use std::cmp::{self, Ordering};
 use std::collections::{HashMap, HashSet};
-use std::sync::mpsc;
+use std::hash::Hash;
+use std::ops::Range;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::usize;
 
-use data::{ColumnName, Db, Ids, Data, Datum, Value};
-use plan::{Plan, Predicate, PlanNode, Stage};
+use data::{Column, ColumnName, Db, GenericDatum, Ids, Data, Datum, Value};
+use plan::{Aggregate, Direction, JoinKind, Matching, Plan, Predicate, PlanNode, Stage, TimeBound};
 
 struct Cache<'a> {
     db: &'a Db,
@@ -40,15 +47,29 @@ impl<'a> Cache<'a> {
 enum Filtered {
     Data(Data),
     Ids(Ids),
+    /// Ids matched by a where node along with the number of datums it tested
+    Matched(Ids, usize),
+    /// Several aligned result columns, one row per group
+    Columns(Vec<(ColumnName, Data)>),
 }
 
 #[derive(Debug)]
 pub enum Error {
     MissingColumn(ColumnName),
     InvalidJoin(ColumnName),
+    InvalidLike(ColumnName),
+    InvalidModulo(ColumnName),
+    InvalidComparison(ColumnName),
+    InvalidOrderBy(ColumnName),
+    InvalidGroup(ColumnName),
+    InvalidCohort(ColumnName),
 }
 
 fn match_by_predicate(data: &Data, predicate: &Predicate) -> Ids {
+    if let Predicate::In(ref values) = *predicate {
+        return match_by_value_set(data, &values.iter().cloned().collect());
+    }
+
     let mut ids = Ids::new();
 
     match *data {
@@ -73,62 +94,767 @@ fn match_by_predicate(data: &Data, predicate: &Predicate) -> Ids {
                 }
             }
         }
+        Data::Float(ref data) => {
+            for datum in data {
+                if predicate.test(&Value::Float(datum.value)) {
+                    ids.insert(datum.id);
+                }
+            }
+        }
+        Data::Timestamp(ref data) => {
+            for datum in data {
+                if predicate.test(&Value::Timestamp(datum.value)) {
+                    ids.insert(datum.id);
+                }
+            }
+        }
+        Data::Bytes(ref data) => {
+            for datum in data {
+                if predicate.test(&Value::Bytes(datum.value.to_owned())) {
+                    ids.insert(datum.id);
+                }
+            }
+        }
+    }
+
+    ids
+}
+
+fn match_by_value_set(data: &Data, values: &HashSet<Value>) -> Ids {
+    let mut ids = Ids::new();
+
+    match *data {
+        Data::Bool(ref data) => {
+            for datum in data {
+                if values.contains(&Value::Bool(datum.value)) {
+                    ids.insert(datum.id);
+                }
+            }
+        }
+        Data::Int(ref data) => {
+            for datum in data {
+                if values.contains(&Value::Int(datum.value)) {
+                    ids.insert(datum.id);
+                }
+            }
+        }
+        Data::String(ref data) => {
+            for datum in data {
+                if values.contains(&Value::String(datum.value.to_owned())) {
+                    ids.insert(datum.id);
+                }
+            }
+        }
+        Data::Float(ref data) => {
+            for datum in data {
+                if values.contains(&Value::Float(datum.value)) {
+                    ids.insert(datum.id);
+                }
+            }
+        }
+        Data::Timestamp(ref data) => {
+            for datum in data {
+                if values.contains(&Value::Timestamp(datum.value)) {
+                    ids.insert(datum.id);
+                }
+            }
+        }
+        Data::Bytes(ref data) => {
+            for datum in data {
+                if values.contains(&Value::Bytes(datum.value.to_owned())) {
+                    ids.insert(datum.id);
+                }
+            }
+        }
+    }
+
+    ids
+}
+
+fn match_offsets_by_predicate<I>(data: &Data, offsets: I, predicate: &Predicate) -> Ids
+    where I: Iterator<Item = usize>
+{
+    let mut ids = Ids::new();
+
+    match *data {
+        Data::Bool(ref data) => {
+            for offset in offsets {
+                if predicate.test(&Value::Bool(data[offset].value)) {
+                    ids.insert(data[offset].id);
+                }
+            }
+        }
+        Data::Int(ref data) => {
+            for offset in offsets {
+                if predicate.test(&Value::Int(data[offset].value)) {
+                    ids.insert(data[offset].id);
+                }
+            }
+        }
+        Data::String(ref data) => {
+            for offset in offsets {
+                if predicate.test(&Value::String(data[offset].value.to_owned())) {
+                    ids.insert(data[offset].id);
+                }
+            }
+        }
+        Data::Float(ref data) => {
+            for offset in offsets {
+                if predicate.test(&Value::Float(data[offset].value)) {
+                    ids.insert(data[offset].id);
+                }
+            }
+        }
+        Data::Timestamp(ref data) => {
+            for offset in offsets {
+                if predicate.test(&Value::Timestamp(data[offset].value)) {
+                    ids.insert(data[offset].id);
+                }
+            }
+        }
+        Data::Bytes(ref data) => {
+            for offset in offsets {
+                if predicate.test(&Value::Bytes(data[offset].value.to_owned())) {
+                    ids.insert(data[offset].id);
+                }
+            }
+        }
+    }
+
+    ids
+}
+
+/// Values of `data` keyed by the id and time of their row
+fn values_by_row(data: &Data) -> HashMap<(usize, usize), Value> {
+    (0..data.len())
+        .filter_map(|i| data.value(i).map(|value| ((data.id(i), data.time(i)), value)))
+        .collect()
+}
+
+/// Like `match_by_predicate`, for predicates comparing against other columns
+/// of the same table, which are zipped with `data` by id and time. Only the
+/// datums at `offsets` are tested.
+fn match_by_row(data: &Data,
+                offsets: &[usize],
+                predicate: &Predicate,
+                others: &HashMap<&ColumnName, HashMap<(usize, usize), Value>>)
+                -> Ids {
+    let mut ids = Ids::new();
+
+    for &i in offsets {
+        let key = (data.id(i), data.time(i));
+        let row = |name: &ColumnName| others.get(name).and_then(|values| values.get(&key));
+        if let Some(value) = data.value(i) {
+            if predicate.test_row(&value, &row) {
+                ids.insert(key.0);
+            }
+        }
     }
 
     ids
 }
 
-fn match_by_ids(data: &[Datum<usize>], ids: &Ids) -> Ids {
+fn match_by_ids(data: &[Datum<i64>], ids: &Ids) -> Ids {
     data.iter()
         .fold(Ids::new(), |mut acc, datum| {
-            if ids.contains(&datum.value) {
+            if datum.value >= 0 && ids.contains(&(datum.value as usize)) {
                 acc.insert(datum.id);
             }
             acc
         })
 }
 
-fn clone_matching_data<T: Clone>(data: &[Datum<T>], ids: &Ids, limit: usize) -> Vec<Datum<T>> {
+/// Ids of `right` datums whose value matches a `left` datum of one of `ids`,
+/// for joins on columns other than an Int id
+fn match_by_values(left: &Data, ids: &Ids, right: &Data) -> Ids {
+    let values = (0..left.len())
+                     .filter(|&offset| ids.contains(&left.id(offset)))
+                     .filter_map(|offset| left.value(offset))
+                     .collect::<HashSet<Value>>();
+
+    (0..right.len())
+        .filter(|&offset| right.value(offset).map_or(false, |value| values.contains(&value)))
+        .map(|offset| right.id(offset))
+        .collect()
+}
+
+fn clone_matching_data<T: Clone>(data: &[Datum<T>], ids: &Ids, limit: usize, offset: usize)
+                                 -> Vec<Datum<T>> {
     data.iter()
         .filter(|datum| ids.contains(&datum.id))
+        .skip(offset)
         .take(limit)
         .cloned()
         .collect()
 }
 
-fn find_data_by_set(data: &Data, ids: &HashSet<usize>, limit: usize) -> Data {
+/// Like `clone_matching_data` but keeps only the first datum seen with each
+/// value, `limit` and `offset` count distinct values. Values are compared by
+/// `key`, which lets floats be hashed as a `Value`.
+fn clone_distinct_data<T, K, F>(data: &[Datum<T>],
+                                ids: &Ids,
+                                limit: usize,
+                                offset: usize,
+                                key: F)
+                                -> Vec<Datum<T>>
+    where T: Clone,
+          K: Eq + Hash,
+          F: Fn(&T) -> K
+{
+    let mut seen = HashSet::new();
+    data.iter()
+        .filter(|datum| ids.contains(&datum.id) && seen.insert(key(&datum.value)))
+        .skip(offset)
+        .take(limit)
+        .cloned()
+        .collect()
+}
+
+/// Number of distinct times per id, relies on `data` being sorted by time so
+/// repeated `(id, time)` datums are only counted once. Each result datum holds
+/// the id's latest time.
+fn count_times_by_id<T>(data: &[Datum<T>], ids: &Ids, limit: usize) -> Vec<Datum<i64>> {
+    let mut counts: Vec<Datum<i64>> = vec![];
+    let mut positions: HashMap<usize, usize> = HashMap::new();
+
+    for datum in data.iter().filter(|datum| ids.contains(&datum.id)) {
+        let position = positions.get(&datum.id).cloned();
+
+        match position {
+            Some(position) => {
+                let count = &mut counts[position];
+                if count.time != datum.time {
+                    count.value += 1;
+                    count.time = datum.time;
+                }
+            }
+            None => {
+                positions.insert(datum.id, counts.len());
+                counts.push(Datum::new(datum.id, 1, datum.time));
+            }
+        }
+    }
+
+    counts.truncate(limit);
+    counts
+}
+
+/// Partition `ids` by their latest value in `data`, compared by `key`.
+/// Returns one datum per group, its id being the group's index, along with
+/// each id's group.
+fn group_by_value<T, K, F>(data: &[Datum<T>], ids: &Ids, key: F)
+                           -> (Vec<Datum<T>>, HashMap<usize, usize>)
+    where T: Clone,
+          K: Eq + Hash,
+          F: Fn(&T) -> K
+{
+    let mut latest = HashMap::new();
+    for (offset, datum) in data.iter().enumerate().filter(|&(_, d)| ids.contains(&d.id)) {
+        latest.insert(datum.id, offset);
+    }
+
+    let mut keys: Vec<Datum<T>> = vec![];
+    let mut positions: HashMap<K, usize> = HashMap::new();
+    let mut groups = HashMap::new();
+
+    for (offset, datum) in data.iter().enumerate() {
+        if latest.get(&datum.id) != Some(&offset) {
+            continue;
+        }
+
+        let position = positions.get(&key(&datum.value)).cloned();
+        let group = match position {
+            Some(group) => {
+                keys[group].time = datum.time;
+                group
+            }
+            None => {
+                let group = keys.len();
+                positions.insert(key(&datum.value), group);
+                keys.push(Datum::new(group, datum.value.clone(), datum.time));
+                group
+            }
+        };
+        groups.insert(datum.id, group);
+    }
+
+    (keys, groups)
+}
+
+fn count_by_group<T>(data: &[Datum<T>], groups: &HashMap<usize, usize>, totals: &mut [i64]) {
+    for datum in data {
+        if let Some(&group) = groups.get(&datum.id) {
+            totals[group] += 1;
+        }
+    }
+}
+
+/// Keys and aggregates of the groups whose aggregate matches `having`, both
+/// with the group's index as their id
+fn find_data_by_group(key_column: &Column,
+                      column: &Column,
+                      aggregate: Aggregate,
+                      having: Option<&Predicate>,
+                      ids: &Ids,
+                      limit: usize)
+                      -> Result<(Data, Data), Error> {
+    let (mut keys, groups, times) = match key_column.data {
+        Data::Bool(ref data) => {
+            let (keys, groups) = group_by_value(data, ids, |v| v.clone());
+            let times = keys.iter().map(|key| key.time).collect::<Vec<usize>>();
+            (Data::Bool(keys), groups, times)
+        }
+        Data::Int(ref data) => {
+            let (keys, groups) = group_by_value(data, ids, |v| v.clone());
+            let times = keys.iter().map(|key| key.time).collect::<Vec<usize>>();
+            (Data::Int(keys), groups, times)
+        }
+        Data::String(ref data) => {
+            let (keys, groups) = group_by_value(data, ids, |v| v.clone());
+            let times = keys.iter().map(|key| key.time).collect::<Vec<usize>>();
+            (Data::String(keys), groups, times)
+        }
+        Data::Float(ref data) => {
+            let (keys, groups) = group_by_value(data, ids, |v| Value::Float(*v));
+            let times = keys.iter().map(|key| key.time).collect::<Vec<usize>>();
+            (Data::Float(keys), groups, times)
+        }
+        Data::Timestamp(ref data) => {
+            let (keys, groups) = group_by_value(data, ids, |v| *v);
+            let times = keys.iter().map(|key| key.time).collect::<Vec<usize>>();
+            (Data::Timestamp(keys), groups, times)
+        }
+        Data::Bytes(ref data) => {
+            let (keys, groups) = group_by_value(data, ids, |v| v.clone());
+            let times = keys.iter().map(|key| key.time).collect::<Vec<usize>>();
+            (Data::Bytes(keys), groups, times)
+        }
+    };
+
+    let mut totals = vec![0i64; times.len()];
+    match (aggregate, &column.data) {
+        (Aggregate::Count, &Data::Bool(ref data)) => count_by_group(data, &groups, &mut totals),
+        (Aggregate::Count, &Data::Int(ref data)) => count_by_group(data, &groups, &mut totals),
+        (Aggregate::Count, &Data::String(ref data)) => {
+            count_by_group(data, &groups, &mut totals)
+        }
+        (Aggregate::Count, &Data::Float(ref data)) => count_by_group(data, &groups, &mut totals),
+        (Aggregate::Count, &Data::Timestamp(ref data)) => {
+            count_by_group(data, &groups, &mut totals)
+        }
+        (Aggregate::Count, &Data::Bytes(ref data)) => count_by_group(data, &groups, &mut totals),
+        (Aggregate::Sum, &Data::Int(ref data)) => {
+            for datum in data {
+                if let Some(&group) = groups.get(&datum.id) {
+                    totals[group] += datum.value;
+                }
+            }
+        }
+        (Aggregate::Sum, _) => return Err(Error::InvalidGroup(column.name.to_owned())),
+    }
+
+    let aggregates = totals.into_iter()
+                           .zip(times)
+                           .enumerate()
+                           .map(|(group, (total, time))| Datum::new(group, total, time))
+                           .filter(|datum| {
+                               having.map_or(true, |p| p.test(&Value::Int(datum.value)))
+                           })
+                           .collect::<Vec<Datum<i64>>>();
+    if having.is_some() {
+        let kept = aggregates.iter().map(|datum| datum.id).collect::<Ids>();
+        let dropped = (0..keys.len()).filter(|group| !kept.contains(group)).collect::<Ids>();
+        keys.remove_ids(&dropped);
+    }
+    let aggregates = aggregates.into_iter().take(limit).collect();
+    keys.truncate(limit);
+
+    Ok((keys, Data::Int(aggregates)))
+}
+
+fn datum_ids_and_times<T>(data: &[Datum<T>], ids: &Ids) -> Vec<(usize, usize)> {
+    data.iter()
+        .filter(|datum| ids.contains(&datum.id))
+        .map(|datum| (datum.id, datum.time))
+        .collect()
+}
+
+/// Ids of the table without any datum in `data`
+fn find_null_ids(table_ids: &Ids, data: &Data) -> Ids {
+    let present = ids_and_times(data, table_ids)
+                      .into_iter()
+                      .map(|(id, _)| id)
+                      .collect::<Ids>();
+    table_ids.difference(&present).cloned().collect()
+}
+
+fn ids_and_times(data: &Data, ids: &Ids) -> Vec<(usize, usize)> {
+    match *data {
+        Data::Bool(ref data) => datum_ids_and_times(data, ids),
+        Data::Int(ref data) => datum_ids_and_times(data, ids),
+        Data::String(ref data) => datum_ids_and_times(data, ids),
+        Data::Float(ref data) => datum_ids_and_times(data, ids),
+        Data::Timestamp(ref data) => datum_ids_and_times(data, ids),
+        Data::Bytes(ref data) => datum_ids_and_times(data, ids),
+    }
+}
+
+/// Retention matrix, ids are put in a cohort by bucketing their first
+/// `signups` time, then counted in window `n` of that cohort if they have an
+/// activity `n * window` to `(n + 1) * window` after signing up. One row per
+/// non-empty cohort with its start time, its size and a column per window.
+fn find_data_by_cohort(table: &str,
+                       signups: &[(usize, usize)],
+                       bucket: usize,
+                       activities: &[(usize, usize)],
+                       window: usize,
+                       limit: usize)
+                       -> Vec<(ColumnName, Data)> {
+    let mut signed_up = HashMap::new();
+    for &(id, time) in signups {
+        let first = signed_up.entry(id).or_insert(time);
+        if time < *first {
+            *first = time;
+        }
+    }
+
+    let mut cohorts = signed_up.values().map(|time| time / bucket).collect::<Vec<usize>>();
+    cohorts.sort();
+    cohorts.dedup();
+    cohorts.truncate(limit);
+    let rows = cohorts.iter()
+                      .enumerate()
+                      .map(|(row, &cohort)| (cohort, row))
+                      .collect::<HashMap<usize, usize>>();
+
+    let mut active: HashSet<(usize, usize, usize)> = HashSet::new();
+    let mut windows = 0;
+    for &(id, time) in activities {
+        let signup = match signed_up.get(&id) {
+            Some(&signup) if time >= signup => signup,
+            _ => continue,
+        };
+
+        if let Some(&row) = rows.get(&(signup / bucket)) {
+            let index = (time - signup) / window;
+            windows = cmp::max(windows, index + 1);
+            active.insert((row, index, id));
+        }
+    }
+
+    let mut sizes = vec![0; cohorts.len()];
+    for signup in signed_up.values() {
+        if let Some(&row) = rows.get(&(signup / bucket)) {
+            sizes[row] += 1;
+        }
+    }
+
+    let mut counts = vec![vec![0; windows]; cohorts.len()];
+    for &(row, index, _) in &active {
+        counts[row][index] += 1;
+    }
+
+    let starts = cohorts.iter().map(|cohort| cohort * bucket).collect::<Vec<usize>>();
+    let column = |values: Vec<usize>| {
+        Data::Int(values.into_iter()
+                        .zip(&starts)
+                        .enumerate()
+                        .map(|(row, (value, &start))| Datum::new(row, value as i64, start))
+                        .collect())
+    };
+
+    let width = format!("{}", cmp::max(windows, 1) - 1).len();
+    let mut columns = vec![(ColumnName::new(table, "cohort"), column(starts.clone())),
+                           (ColumnName::new(table, "size"), column(sizes))];
+    for index in 0..windows {
+        let values = counts.iter().map(|row| row[index]).collect();
+        columns.push((ColumnName::new(table.to_owned(),
+                                      format!("window_{:01$}", index, width)),
+                      column(values)));
+    }
+
+    columns
+}
+
+/// Offset of the newest datum of each id in `times`, sorted
+fn latest_offsets(data: &Data, times: Range<usize>) -> Vec<usize> {
+    let mut latest = HashMap::new();
+    for offset in times {
+        latest.insert(data.id(offset), offset);
+    }
+
+    let mut offsets = latest.values().cloned().collect::<Vec<usize>>();
+    offsets.sort();
+    offsets
+}
+
+/// Newest datum of each id in `ids` with a time no later than `max_time`,
+/// columns are sorted by time so that's the last one seen before passing it
+fn latest_by_id<T: Clone>(data: &[Datum<T>], ids: &Ids, max_time: Option<i64>) -> Vec<Datum<T>> {
+    let mut latest = HashMap::new();
+    for (offset, datum) in data.iter().enumerate() {
+        if max_time.map_or(false, |max_time| datum.time as i64 > max_time) {
+            break;
+        }
+        if ids.contains(&datum.id) {
+            latest.insert(datum.id, offset);
+        }
+    }
+
+    let mut offsets = latest.values().cloned().collect::<Vec<usize>>();
+    offsets.sort();
+    offsets.into_iter().map(|offset| data[offset].clone()).collect()
+}
+
+fn find_latest_data(data: &Data, ids: &Ids, max_time: Option<i64>) -> Data {
     match *data {
-        Data::Bool(ref data) => Data::Bool(clone_matching_data(data, ids, limit)),
-        Data::Int(ref data) => Data::Int(clone_matching_data(data, ids, limit)),
-        Data::String(ref data) => Data::String(clone_matching_data(data, ids, limit)),
+        Data::Bool(ref data) => Data::Bool(latest_by_id(data, ids, max_time)),
+        Data::Int(ref data) => Data::Int(latest_by_id(data, ids, max_time)),
+        Data::String(ref data) => Data::String(latest_by_id(data, ids, max_time)),
+        Data::Float(ref data) => Data::Float(latest_by_id(data, ids, max_time)),
+        Data::Timestamp(ref data) => Data::Timestamp(latest_by_id(data, ids, max_time)),
+        Data::Bytes(ref data) => Data::Bytes(latest_by_id(data, ids, max_time)),
     }
 }
 
-fn find_data(db: &Db, cache: &Cache, node: &PlanNode) -> Result<(ColumnName, Filtered), Error> {
+fn find_data_by_set(data: &Data,
+                    ids: &HashSet<usize>,
+                    limit: usize,
+                    offset: usize,
+                    distinct: bool)
+                    -> Data {
+    if distinct {
+        return match *data {
+            Data::Bool(ref data) => {
+                Data::Bool(clone_distinct_data(data, ids, limit, offset, |v| *v))
+            }
+            Data::Int(ref data) => {
+                Data::Int(clone_distinct_data(data, ids, limit, offset, |v| *v))
+            }
+            Data::String(ref data) => {
+                Data::String(clone_distinct_data(data, ids, limit, offset, |v| v.clone()))
+            }
+            Data::Float(ref data) => {
+                Data::Float(clone_distinct_data(data, ids, limit, offset, |v| Value::Float(*v)))
+            }
+            Data::Timestamp(ref data) => {
+                Data::Timestamp(clone_distinct_data(data, ids, limit, offset, |v| *v))
+            }
+            Data::Bytes(ref data) => {
+                Data::Bytes(clone_distinct_data(data, ids, limit, offset, |v| v.clone()))
+            }
+        };
+    }
+
+    match *data {
+        Data::Bool(ref data) => Data::Bool(clone_matching_data(data, ids, limit, offset)),
+        Data::Int(ref data) => Data::Int(clone_matching_data(data, ids, limit, offset)),
+        Data::String(ref data) => Data::String(clone_matching_data(data, ids, limit, offset)),
+        Data::Float(ref data) => Data::Float(clone_matching_data(data, ids, limit, offset)),
+        Data::Timestamp(ref data) => {
+            Data::Timestamp(clone_matching_data(data, ids, limit, offset))
+        }
+        Data::Bytes(ref data) => Data::Bytes(clone_matching_data(data, ids, limit, offset)),
+    }
+}
+
+fn aggregate_name(aggregate: Aggregate, name: &ColumnName) -> ColumnName {
+    ColumnName::new(name.table.to_owned(),
+                    format!("{}({})", aggregate, name.column))
+}
+
+fn time_count_name(name: &ColumnName) -> ColumnName {
+    ColumnName::new(name.table.to_owned(),
+                    format!("time_count({})", name.column))
+}
+
+/// Empty results for a node whose required ids were all filtered out by an
+/// earlier stage, or an id filter without any ids, built without scanning
+/// its columns. `None` when the node still has to run, left joins keep every
+/// row so they always do.
+fn find_empty_data(db: &Db,
+                   cache: &Cache,
+                   node: &PlanNode)
+                   -> Option<Result<(ColumnName, Filtered), Error>> {
+    if let PlanNode::WhereId(ref left, ref ids) = *node {
+        if ids.is_empty() {
+            return Some(Ok((left.to_owned(), Filtered::Matched(Ids::new(), 0))));
+        }
+    }
+    if let PlanNode::Where(ref left, _, Some(ref bound)) = *node {
+        if bound.is_empty() {
+            return Some(Ok((left.id(), Filtered::Matched(Ids::new(), 0))));
+        }
+    }
+
+    // A node's rows come from the first ids it requires
+    match node.requires().first() {
+        Some(required) if cache.get(required).map_or(false, |ids| ids.is_empty()) => (),
+        _ => return None,
+    }
+
+    let column = |name: &ColumnName| db.cols.get(name).ok_or(Error::MissingColumn(name.to_owned()));
+    let result = match *node {
+        PlanNode::Select(ref name, _, _, _) |
+        PlanNode::Latest(ref name, _, _, _, _) => {
+            column(name).map(|column| {
+                (name.to_owned(), Filtered::Data(column.data.empty_like()))
+            })
+        }
+        PlanNode::TimeCount(ref name, _) => {
+            Ok((time_count_name(name), Filtered::Data(Data::Int(vec![]))))
+        }
+        PlanNode::Group(ref key, aggregate, ref name, _, _) => {
+            column(key).and_then(|key_column| {
+                match (aggregate, &try!(column(name)).data) {
+                    (Aggregate::Sum, &Data::Int(_)) | (Aggregate::Count, _) => (),
+                    (Aggregate::Sum, _) => return Err(Error::InvalidGroup(name.to_owned())),
+                }
+                Ok((key.to_owned(),
+                    Filtered::Columns(vec![(key.to_owned(), key_column.data.empty_like()),
+                                           (aggregate_name(aggregate, name),
+                                            Data::Int(vec![]))])))
+            })
+        }
+        PlanNode::Cohort(ref signup, bucket, _, window, limit) if bucket > 0 && window > 0 => {
+            let columns = find_data_by_cohort(&signup.table, &[], bucket, &[], window, limit);
+            Ok((signup.to_owned(), Filtered::Columns(columns)))
+        }
+        PlanNode::Join(ref left, ref right, JoinKind::Inner) => {
+            column(left).and_then(|left_column| {
+                if !left_column.data.is_same_type(&try!(column(right)).data) {
+                    return Err(Error::InvalidJoin(right.to_owned()));
+                }
+                Ok((right.id(), Filtered::Ids(Ids::new())))
+            })
+        }
+        _ => return None,
+    };
+
+    Some(result)
+}
+
+/// A where node only tests the datums of `candidates` when it's given, the
+/// ids the where nodes of its table that ran before it matched
+fn find_data(db: &Db,
+             cache: &Cache,
+             node: &PlanNode,
+             candidates: Option<&Ids>)
+             -> Result<(ColumnName, Filtered), Error> {
     match *node {
-        PlanNode::Select(ref name, limit) => {
+        PlanNode::Select(ref name, limit, offset, distinct) => {
             let name_id = name.id();
             let ids = try!(cache.get(&name_id).ok_or(Error::MissingColumn(name_id)));
             let column = try!(db.cols.get(name).ok_or(Error::MissingColumn(name.to_owned())));
 
             Ok((name.to_owned(),
-                Filtered::Data(find_data_by_set(&column.data, &ids, limit))))
+                Filtered::Data(find_data_by_set(&column.data, &ids, limit, offset, distinct))))
         }
-        PlanNode::Join(ref left, ref right) => {
-            let ids = try!(cache.get(left).ok_or(Error::MissingColumn(left.to_owned())));
-            let column = try!(db.cols.get(right).ok_or(Error::MissingColumn(right.to_owned())));
+        PlanNode::Latest(ref name, limit, offset, distinct, max_time) => {
+            let name_id = name.id();
+            let ids = try!(cache.get(&name_id).ok_or(Error::MissingColumn(name_id)));
+            let column = try!(db.cols.get(name).ok_or(Error::MissingColumn(name.to_owned())));
+            let latest = find_latest_data(&column.data, ids, max_time);
+
+            Ok((name.to_owned(),
+                Filtered::Data(find_data_by_set(&latest, &ids, limit, offset, distinct))))
+        }
+        PlanNode::Group(ref key, aggregate, ref name, limit, ref having) => {
+            let key_id = key.id();
+            let ids = try!(cache.get(&key_id).ok_or(Error::MissingColumn(key_id)));
+            let key_column = try!(db.cols.get(key).ok_or(Error::MissingColumn(key.to_owned())));
+            let column = try!(db.cols.get(name).ok_or(Error::MissingColumn(name.to_owned())));
 
-            match column.data {
-                Data::Int(ref data) => Ok((right.id(), Filtered::Ids(match_by_ids(data, ids)))),
-                _ => Err(Error::InvalidJoin(right.to_owned())),
+            let having = having.as_ref();
+            let (keys, aggregates) =
+                try!(find_data_by_group(key_column, column, aggregate, having, ids, limit));
+
+            Ok((key.to_owned(),
+                Filtered::Columns(vec![(key.to_owned(), keys),
+                                       (aggregate_name(aggregate, name), aggregates)])))
+        }
+        PlanNode::Cohort(ref signup, bucket, ref activity, window, limit) => {
+            let signup_id = signup.id();
+            let activity_id = activity.id();
+            let signup_ids = try!(cache.get(&signup_id).ok_or(Error::MissingColumn(signup_id)));
+            let activity_ids = try!(cache.get(&activity_id)
+                                         .ok_or(Error::MissingColumn(activity_id)));
+            let signup_column = try!(db.cols
+                                       .get(signup)
+                                       .ok_or(Error::MissingColumn(signup.to_owned())));
+            let activity_column = try!(db.cols
+                                         .get(activity)
+                                         .ok_or(Error::MissingColumn(activity.to_owned())));
+
+            if bucket == 0 || window == 0 {
+                return Err(Error::InvalidCohort(signup.to_owned()));
             }
+
+            let signups = ids_and_times(&signup_column.data, signup_ids);
+            let activities = ids_and_times(&activity_column.data, activity_ids);
+            let columns = find_data_by_cohort(&signup.table,
+                                              &signups,
+                                              bucket,
+                                              &activities,
+                                              window,
+                                              limit);
+
+            Ok((signup.to_owned(), Filtered::Columns(columns)))
+        }
+        PlanNode::TimeCount(ref name, limit) => {
+            let name_id = name.id();
+            let ids = try!(cache.get(&name_id).ok_or(Error::MissingColumn(name_id)));
+            let column = try!(db.cols.get(name).ok_or(Error::MissingColumn(name.to_owned())));
+
+            let counts = match column.data {
+                Data::Bool(ref data) => count_times_by_id(data, ids, limit),
+                Data::Int(ref data) => count_times_by_id(data, ids, limit),
+                Data::String(ref data) => count_times_by_id(data, ids, limit),
+                Data::Float(ref data) => count_times_by_id(data, ids, limit),
+                Data::Timestamp(ref data) => count_times_by_id(data, ids, limit),
+                Data::Bytes(ref data) => count_times_by_id(data, ids, limit),
+            };
+            Ok((time_count_name(name), Filtered::Data(Data::Int(counts))))
         }
-        PlanNode::Where(ref left, ref predicate, _) => {
+        PlanNode::Join(ref left, ref right, kind) => {
             let left_id = left.id();
-            let column = try!(db.cols.get(left).ok_or(Error::MissingColumn(left.to_owned())));
+            let ids = try!(cache.get(&left_id).ok_or(Error::MissingColumn(left_id)));
+            let left_column = try!(db.cols.get(left).ok_or(Error::MissingColumn(left.to_owned())));
+            let column = try!(db.cols.get(right).ok_or(Error::MissingColumn(right.to_owned())));
+
+            if !left_column.data.is_same_type(&column.data) {
+                return Err(Error::InvalidJoin(right.to_owned()));
+            }
 
-            Ok((left_id,
-                Filtered::Ids(match_by_predicate(&column.data, predicate))))
+            let join = |ids: &Ids| {
+                match column.data {
+                    Data::Int(ref data) if left.column == "id" => match_by_ids(data, ids),
+                    ref data => match_by_values(&left_column.data, ids, data),
+                }
+            };
+            // Unmatched rows of a left join stay, the joined table's columns
+            // just have no datums for them. Rows matching a left row the
+            // where lines dropped don't.
+            let ids = match kind {
+                JoinKind::Inner => join(ids),
+                JoinKind::Left => {
+                    let all_left = try!(db.ids
+                                          .get(&left.table)
+                                          .ok_or(Error::MissingColumn(left.id())));
+                    let all_right = try!(db.ids
+                                           .get(&right.table)
+                                           .ok_or(Error::MissingColumn(right.id())));
+                    let matched_any = join(all_left);
+                    let mut ids = join(ids);
+                    ids.extend(all_right.iter().filter(|id| !matched_any.contains(id)));
+                    ids
+                }
+            };
+
+            Ok((right.id(), Filtered::Ids(ids)))
+        }
+        PlanNode::Where(ref left, ref predicate, ref time_bound) => {
+            find_where_data(db, left, predicate, time_bound.as_ref(), candidates)
         }
         PlanNode::WhereId(ref left, ref ids) => {
             let cache_ids = try!(cache.get(left).ok_or(Error::MissingColumn(left.to_owned())));
@@ -137,44 +863,628 @@ fn find_data(db: &Db, cache: &Cache, node: &PlanNode) -> Result<(ColumnName, Fil
                                  .cloned()
                                  .collect::<HashSet<usize>>();
 
-            Ok((left.to_owned(), Filtered::Ids(matched_ids)))
+            Ok((left.to_owned(), Filtered::Matched(matched_ids, 0)))
         }
     }
 }
 
-fn exec_stage(db: &Db, cache: &Cache, stage: &Stage) -> Result<Vec<(ColumnName, Filtered)>, Error> {
+/// Ids of the datums of `left` matching `predicate`, tested through its
+/// indexes when the predicate and the time bound allow it
+fn find_where_data(db: &Db,
+                   left: &ColumnName,
+                   predicate: &Predicate,
+                   time_bound: Option<&TimeBound>,
+                   candidates: Option<&Ids>)
+                   -> Result<(ColumnName, Filtered), Error> {
+    let left_id = left.id();
+    let column = try!(db.cols.get(left).ok_or(Error::MissingColumn(left.to_owned())));
+
+    match column.data {
+        Data::String(_) => (),
+        _ if predicate.has_like() => return Err(Error::InvalidLike(left.to_owned())),
+        _ => (),
+    }
+    match column.data {
+        Data::Int(_) => (),
+        _ if predicate.has_mod() => return Err(Error::InvalidModulo(left.to_owned())),
+        _ => (),
+    }
+
+    let ranged;
+    let predicate = if column.has_value_index() {
+        ranged = predicate.with_prefix_ranges();
+        &ranged
+    } else {
+        predicate
+    };
+
+    let candidate = |offset: &usize| {
+        candidates.map_or(true, |ids| ids.contains(&column.data.id(*offset)))
+    };
+    let times = time_bound.map(|bound| column.offsets_in_time_range(bound.min(), bound.max()));
+    let latest = match (time_bound, times.as_ref()) {
+        (Some(bound), Some(times)) if bound.latest() => {
+            Some(latest_offsets(&column.data, times.clone()))
+        }
+        _ => None,
+    };
+
+    let compared = predicate.compared_columns();
+    if !compared.is_empty() {
+        let mut others = HashMap::new();
+        for name in compared {
+            let other = try!(db.cols.get(name).ok_or(Error::MissingColumn(name.to_owned())));
+            if !other.data.is_same_type(&column.data) {
+                return Err(Error::InvalidComparison(name.to_owned()));
+            }
+            others.insert(name, values_by_row(&other.data));
+        }
+
+        let offsets: Vec<usize> = match (latest, times) {
+            (Some(latest), _) => latest.into_iter().filter(&candidate).collect(),
+            (None, Some(times)) => times.filter(&candidate).collect(),
+            (None, None) => (0..column.data.len()).filter(&candidate).collect(),
+        };
+        let ids = match_by_row(&column.data, &offsets, predicate, &others);
+        return Ok((left_id, Filtered::Matched(ids, offsets.len())));
+    }
+
+    let hashed = predicate.equal_values().and_then(|values| column.offsets_equal_to(&values));
+    let offsets = match hashed {
+        Some(ref offsets) => Some(offsets.as_slice()),
+        None => {
+            predicate.value_range()
+                     .and_then(|(min, max)| column.offsets_in_range(min, max))
+        }
+    };
+    let tested = tested_offsets(&column.data, offsets, times, latest, candidates);
+    let (mut ids, scanned) = match tested {
+        Some(tested) => {
+            (match_offsets_by_predicate(&column.data, tested.iter().cloned(), predicate),
+             tested.len())
+        }
+        None => (match_by_predicate(&column.data, predicate), column.data.len()),
+    };
+
+    if predicate.test_null() == Some(true) {
+        if let Some(table_ids) = db.ids.get(&left.table) {
+            ids.extend(find_null_ids(table_ids, &column.data));
+        }
+    }
+
+    Ok((left_id, Filtered::Matched(ids, scanned)))
+}
+
+/// Offsets of the datums a where node tests, narrowed by its value index
+/// `offsets`, its time bound and `candidates`, `None` for every datum
+fn tested_offsets(data: &Data,
+                  offsets: Option<&[usize]>,
+                  times: Option<Range<usize>>,
+                  latest: Option<Vec<usize>>,
+                  candidates: Option<&Ids>)
+                  -> Option<Vec<usize>> {
+    let tested = match (offsets, times, latest) {
+        (Some(offsets), _, Some(latest)) => {
+            let latest = latest.into_iter().collect::<HashSet<usize>>();
+            Some(offsets.iter().cloned().filter(|offset| latest.contains(offset)).collect())
+        }
+        (None, _, Some(latest)) => Some(latest),
+        (Some(offsets), Some(times), None) => {
+            Some(offsets.iter()
+                        .cloned()
+                        .filter(|&offset| offset >= times.start && offset < times.end)
+                        .collect())
+        }
+        (Some(offsets), None, None) => Some(offsets.to_vec()),
+        (None, Some(times), None) => Some(times.collect()),
+        (None, None, _) => None,
+    };
+    let candidate = |offset: &usize| candidates.map_or(true, |ids| ids.contains(&data.id(*offset)));
+    match (tested, candidates) {
+        (Some(tested), Some(_)) => Some(tested.into_iter().filter(&candidate).collect()),
+        (None, Some(_)) => Some((0..data.len()).filter(&candidate).collect()),
+        (tested, None) => tested,
+    }
+}
+
+/// Runs every node of a stage, along with how many were skipped because an
+/// earlier stage left them no ids
+fn exec_stage(db: &Db,
+              cache: &Cache,
+              stage: &Stage,
+              matching: Matching,
+              options: ExecOptions)
+              -> Result<(Vec<(ColumnName, Filtered)>, usize), Error> {
     let (tx, rx) = mpsc::channel();
+    // Workers take nodes most selective first. With `match all` the where
+    // nodes of a table are one job, each only testing the ids the ones
+    // before it matched.
+    let mut jobs: Vec<Vec<&PlanNode>> = vec![];
+    let mut chains: HashMap<&String, usize> = HashMap::new();
+    for node in stage.sorted_nodes() {
+        let table = match (node, matching) {
+            (&PlanNode::Where(ref col_name, _, _), Matching::All) |
+            (&PlanNode::WhereId(ref col_name, _), Matching::All) => Some(&col_name.table),
+            _ => None,
+        };
+        if let Some(table) = table {
+            if let Some(&job) = chains.get(table) {
+                jobs[job].push(node);
+                continue;
+            }
+            chains.insert(table, jobs.len());
+        }
+        jobs.push(vec![node]);
+    }
+    let workers = cmp::min(cmp::max(options.threads, 1), jobs.len());
+    let queue = Mutex::new(jobs.into_iter());
 
     crossbeam::scope(|scope| {
-        for query_node in &stage.nodes {
+        for _ in 0..workers {
             let t_tx = tx.clone();
+            let queue = &queue;
             scope.spawn(move || {
-                let (name, filtered) = find_data(&db, &cache, &query_node).unwrap();
-                t_tx.send((name, filtered)).unwrap();
+                loop {
+                    // Release the lock before running the node
+                    let next = queue.lock().expect("Work queue poisoned").next();
+                    let job = match next {
+                        Some(job) => job,
+                        None => break,
+                    };
+
+                    let mut candidates: Option<Ids> = None;
+                    for query_node in job {
+                        let sent = match find_empty_data(&db, &cache, query_node) {
+                            Some(result) => (true, result),
+                            None => {
+                                (false, find_data(&db, &cache, query_node, candidates.as_ref()))
+                            }
+                        };
+                        if let Ok((_, Filtered::Matched(ref ids, _))) = sent.1 {
+                            candidates = Some(match candidates {
+                                Some(candidates) => {
+                                    ids.intersection(&candidates).cloned().collect()
+                                }
+                                None => ids.clone(),
+                            });
+                        }
+                        // `rx` outlives the scope, so the send can't fail
+                        let _ = t_tx.send(sent);
+                    }
+                }
             });
         }
     });
 
+    // Every worker has finished, dropping the last sender ends the iterator
+    drop(tx);
     let mut results = vec![];
-    for _ in 0..stage.len() {
-        results.push(rx.recv().unwrap())
+    let mut skipped = 0;
+    for (was_skipped, result) in rx.iter() {
+        if was_skipped {
+            skipped += 1;
+        }
+        results.push(try!(result));
+    }
+    Ok((results, skipped))
+}
+
+/// Sort by value, equal values fall back to ascending `(id, time)` in either
+/// direction so ordered results don't depend on scan order. Stored floats are
+/// never NaN so the partial ordering is total here.
+fn sort_by_value<T: PartialOrd>(data: &mut Vec<Datum<T>>, direction: Direction) {
+    data.sort_by(|l, r| {
+        let ordering = match direction {
+            Direction::Asc => l.value.partial_cmp(&r.value),
+            Direction::Desc => r.value.partial_cmp(&l.value),
+        };
+        match ordering.unwrap_or(Ordering::Equal) {
+            Ordering::Equal => (l.id, l.time).cmp(&(r.id, r.time)),
+            ordering => ordering,
+        }
+    })
+}
+
+fn sort_by_rank<T>(data: &mut Vec<Datum<T>>, ranks: &HashMap<usize, usize>) {
+    data.sort_by_key(|datum| {
+        (ranks.get(&datum.id).cloned().unwrap_or(usize::MAX), datum.id, datum.time)
+    })
+}
+
+fn rank_ids<T>(data: &[Datum<T>]) -> HashMap<usize, usize> {
+    let mut ranks = HashMap::new();
+    for datum in data {
+        let rank = ranks.len();
+        ranks.entry(datum.id).or_insert(rank);
+    }
+    ranks
+}
+
+/// Selects of `stage` without their limit and offset, which are put in
+/// `windows` to apply once an ordered result is sorted
+fn unlimited_selects(stage: &Stage, windows: &mut HashMap<ColumnName, (usize, usize)>) -> Stage {
+    let nodes = stage.nodes
+                     .iter()
+                     .map(|node| {
+                         match *node {
+                             PlanNode::Select(ref name, limit, offset, distinct) => {
+                                 windows.insert(name.to_owned(), (limit, offset));
+                                 PlanNode::Select(name.to_owned(), usize::MAX, 0, distinct)
+                             }
+                             PlanNode::Latest(ref name, limit, offset, distinct, max_time) => {
+                                 windows.insert(name.to_owned(), (limit, offset));
+                                 PlanNode::Latest(name.to_owned(),
+                                                  usize::MAX,
+                                                  0,
+                                                  distinct,
+                                                  max_time)
+                             }
+                             ref node => node.clone(),
+                         }
+                     })
+                     .collect();
+    Stage::new(nodes)
+}
+
+/// Sort the `name` column by value, then permute every other column so ids
+/// come out in the same order. Ids missing from `name` are left at the end.
+fn order_result(result: &mut Vec<(ColumnName, Data)>,
+                name: &ColumnName,
+                direction: Direction)
+                -> Result<(), Error> {
+    let ranks = match result.iter_mut().find(|&&mut (ref n, _)| n == name) {
+        Some(&mut (_, ref mut data)) => {
+            match *data {
+                Data::Bool(ref mut data) => {
+                    sort_by_value(data, direction);
+                    rank_ids(data)
+                }
+                Data::Int(ref mut data) => {
+                    sort_by_value(data, direction);
+                    rank_ids(data)
+                }
+                Data::String(ref mut data) => {
+                    sort_by_value(data, direction);
+                    rank_ids(data)
+                }
+                Data::Float(ref mut data) => {
+                    sort_by_value(data, direction);
+                    rank_ids(data)
+                }
+                Data::Timestamp(ref mut data) => {
+                    sort_by_value(data, direction);
+                    rank_ids(data)
+                }
+                Data::Bytes(ref mut data) => {
+                    sort_by_value(data, direction);
+                    rank_ids(data)
+                }
+            }
+        }
+        None => return Err(Error::InvalidOrderBy(name.to_owned())),
+    };
+
+    for &mut (ref n, ref mut data) in result.iter_mut() {
+        if n == name {
+            continue;
+        }
+
+        match *data {
+            Data::Bool(ref mut data) => sort_by_rank(data, &ranks),
+            Data::Int(ref mut data) => sort_by_rank(data, &ranks),
+            Data::String(ref mut data) => sort_by_rank(data, &ranks),
+            Data::Float(ref mut data) => sort_by_rank(data, &ranks),
+            Data::Timestamp(ref mut data) => sort_by_rank(data, &ranks),
+            Data::Bytes(ref mut data) => sort_by_rank(data, &ranks),
+        }
+    }
+
+    Ok(())
+}
+
+/// Reported after each stage of a plan finishes executing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub stage: usize,
+    pub stages: usize,
+    pub rows: usize,
+    pub scanned: usize,
+    pub skipped: usize,
+}
+
+/// Controls how many worker threads run the nodes of each stage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExecOptions {
+    pub threads: usize,
+}
+
+impl Default for ExecOptions {
+    fn default() -> Self {
+        ExecOptions { threads: cpu_count() }
+    }
+}
+
+/// The columns selected by a plan, in the order `exec` returned them
+///
+/// ```
+/// use twin_query::{ColumnName, Data, Datum, GenericDatum, QueryResult, Value};
+///
+/// let a = ColumnName::new("foo", "a");
+/// let b = ColumnName::new("foo", "b");
+/// let result = QueryResult::new(vec![(a.clone(), Data::Int(vec![Datum::new(1, 10, 0),
+///                                                                Datum::new(2, 20, 1)])),
+///                                    (b.clone(), Data::Bool(vec![Datum::new(1, true, 0),
+///                                                                Datum::new(2, false, 1)]))]);
+///
+/// assert_eq!(result.columns(), vec![&a, &b]);
+/// assert_eq!(result.get(&a).map(|data| data.len()), Some(2));
+/// assert!(result.get(&ColumnName::new("foo", "c")).is_none());
+///
+/// let rows = result.rows().collect::<Vec<_>>();
+/// assert_eq!(rows.len(), 2);
+/// assert_eq!(rows[1],
+///            vec![GenericDatum::new(2, Value::Int(20), 1),
+///                 GenericDatum::new(2, Value::Bool(false), 1)]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct QueryResult {
+    columns: Vec<(ColumnName, Data)>,
+}
+
+impl QueryResult {
+    pub fn new(columns: Vec<(ColumnName, Data)>) -> Self {
+        QueryResult { columns: columns }
+    }
+
+    pub fn get(&self, name: &ColumnName) -> Option<&Data> {
+        self.columns.iter().find(|&&(ref n, _)| n == name).map(|&(_, ref data)| data)
+    }
+
+    pub fn columns(&self) -> Vec<&ColumnName> {
+        self.columns.iter().map(|&(ref name, _)| name).collect()
+    }
+
+    /// One datum of every column per row, like `zip` the rows stop at the
+    /// shortest column
+    pub fn rows<'a>(&'a self) -> Rows<'a> {
+        Rows {
+            result: self,
+            index: 0,
+        }
+    }
+
+    pub fn into_columns(self) -> Vec<(ColumnName, Data)> {
+        self.columns
+    }
+}
+
+impl From<Vec<(ColumnName, Data)>> for QueryResult {
+    fn from(columns: Vec<(ColumnName, Data)>) -> Self {
+        QueryResult::new(columns)
+    }
+}
+
+/// Iterator over the rows of a `QueryResult`
+pub struct Rows<'a> {
+    result: &'a QueryResult,
+    index: usize,
+}
+
+impl<'a> Iterator for Rows<'a> {
+    type Item = Vec<GenericDatum>;
+
+    fn next(&mut self) -> Option<Vec<GenericDatum>> {
+        if self.result.columns.is_empty() {
+            return None;
+        }
+
+        let mut row = vec![];
+        for &(_, ref data) in &self.result.columns {
+            match data.get(self.index) {
+                Some(datum) => row.push(datum),
+                None => return None,
+            }
+        }
+        self.index += 1;
+        Some(row)
     }
+}
+
+#[cfg(unix)]
+fn cpu_count() -> usize {
+    let count = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    if count < 1 { 1 } else { count as usize }
+}
 
-    Ok(results)
+#[cfg(not(unix))]
+fn cpu_count() -> usize {
+    1
 }
 
-pub fn exec(db: &Db, plan: &Plan) -> Result<Vec<(ColumnName, Data)>, Error> {
+/// Ids of `table` every where and join line of `plan` left, the ids its
+/// selects would read from before their limits. Selects aren't run.
+pub fn matched_ids(db: &Db, plan: &Plan, table: &str, options: ExecOptions) -> Result<Ids, Error> {
     let mut cache = Cache::new(db);
-    let mut result = vec![];
 
     for stage in &plan.stages {
-        for (name, filtered) in try!(exec_stage(db, &cache, stage)) {
+        let filters = stage.nodes
+                           .iter()
+                           .filter(|node| {
+                               match **node {
+                                   PlanNode::Select(_, _, _, _) |
+                                   PlanNode::Latest(_, _, _, _, _) => false,
+                                   _ => true,
+                               }
+                           })
+                           .cloned()
+                           .collect();
+        let stage = Stage::new(filters);
+        let (results, _) = try!(exec_stage(db, &cache, &stage, plan.matching, options));
+
+        let mut matched = HashMap::new();
+        for (name, filtered) in results {
             match filtered {
                 Filtered::Ids(ids) => cache.insert_or_merge(name, ids),
-                Filtered::Data(data) => result.push((name, data)),
+                Filtered::Matched(ids, _) => {
+                    match plan.matching {
+                        Matching::All => cache.insert_or_merge(name, ids),
+                        Matching::Any => matched.entry(name).or_insert_with(Ids::new).extend(ids),
+                    }
+                }
+                Filtered::Data(_) | Filtered::Columns(_) => (),
             }
         }
+        for (name, ids) in matched {
+            cache.insert_or_merge(name, ids);
+        }
     }
 
+    let id = ColumnName::new(table, "id");
+    Ok(cache.get(&id).cloned().unwrap_or_else(Ids::new))
+}
+
+pub fn exec(db: &Db, plan: &Plan, options: ExecOptions) -> Result<Vec<(ColumnName, Data)>, Error> {
+    exec_with_progress(db, plan, options, |_| ())
+}
+
+/// Like `exec`, but calls `progress` once per stage as it completes, `rows`
+/// counts the datums selected so far, `scanned` the datums tested by where
+/// nodes and `skipped` the nodes left with no ids to read.
+pub fn exec_with_progress<F>(db: &Db,
+                             plan: &Plan,
+                             options: ExecOptions,
+                             progress: F)
+                             -> Result<Vec<(ColumnName, Data)>, Error>
+    where F: FnMut(Progress)
+{
+    let mut result = vec![];
+    try!(exec_streaming_with_progress(db,
+                                      plan,
+                                      options,
+                                      progress,
+                                      |name, data| result.push((name, data))));
     Ok(result)
 }
+
+/// Runs `plan` on its own thread against a `Db` shared with other queries,
+/// the handle joins to the same result as `exec`
+pub fn exec_shared(db: Arc<Db>,
+                   plan: Plan,
+                   options: ExecOptions)
+                   -> thread::JoinHandle<Result<Vec<(ColumnName, Data)>, Error>> {
+    thread::spawn(move || exec(&db, &plan, options))
+}
+
+/// Like `exec_with_progress`, also returning how long each of the plan's
+/// stages took, in the order of `plan.stages`
+pub fn exec_timed<F>(db: &Db,
+                     plan: &Plan,
+                     options: ExecOptions,
+                     mut progress: F)
+                     -> Result<(Vec<(ColumnName, Data)>, Vec<Duration>), Error>
+    where F: FnMut(Progress)
+{
+    let mut durations = vec![];
+    let mut start = Instant::now();
+    let result = try!(exec_with_progress(db, plan, options, |p| {
+        durations.push(start.elapsed());
+        progress(p);
+        start = Instant::now();
+    }));
+    Ok((result, durations))
+}
+
+/// Passes each selected column to `chunk` as soon as its stage completes
+/// instead of collecting them. Ordered plans need every column before they
+/// can be sorted, so their chunks all arrive after the last stage.
+pub fn exec_streaming<G>(db: &Db, plan: &Plan, options: ExecOptions, chunk: G) -> Result<(), Error>
+    where G: FnMut(ColumnName, Data)
+{
+    exec_streaming_with_progress(db, plan, options, |_| (), chunk)
+}
+
+pub fn exec_streaming_with_progress<F, G>(db: &Db,
+                                          plan: &Plan,
+                                          options: ExecOptions,
+                                          mut progress: F,
+                                          mut chunk: G)
+                                          -> Result<(), Error>
+    where F: FnMut(Progress),
+          G: FnMut(ColumnName, Data)
+{
+    let mut cache = Cache::new(db);
+    let mut ordered = vec![];
+    let mut rows = 0;
+    let mut scanned = 0;
+    let mut skipped = 0;
+
+    let mut windows = HashMap::new();
+    for (index, stage) in plan.stages.iter().enumerate() {
+        let unlimited;
+        let stage = if plan.order.is_some() {
+            unlimited = unlimited_selects(stage, &mut windows);
+            &unlimited
+        } else {
+            stage
+        };
+        let (results, stage_skipped) =
+            try!(exec_stage(db, &cache, stage, plan.matching, options));
+        skipped += stage_skipped;
+
+        // With `match any` the where nodes of a table are unioned before
+        // they're merged with the ids joins left
+        let mut matched = HashMap::new();
+        let mut selected = vec![];
+        for (name, filtered) in results {
+            match filtered {
+                Filtered::Ids(ids) => cache.insert_or_merge(name, ids),
+                Filtered::Matched(ids, count) => {
+                    scanned += count;
+                    match plan.matching {
+                        Matching::All => cache.insert_or_merge(name, ids),
+                        Matching::Any => matched.entry(name).or_insert_with(Ids::new).extend(ids),
+                    }
+                }
+                Filtered::Data(data) => {
+                    rows += data.len();
+                    selected.push((name, data))
+                }
+                Filtered::Columns(columns) => {
+                    rows += columns.first().map_or(0, |&(_, ref data)| data.len());
+                    selected.extend(columns)
+                }
+            }
+        }
+        for (name, ids) in matched {
+            cache.insert_or_merge(name, ids);
+        }
+
+        if plan.order.is_some() {
+            ordered.extend(selected);
+        } else {
+            for (name, data) in selected {
+                chunk(plan.output_name(name), data);
+            }
+        }
+
+        progress(Progress {
+            stage: index,
+            stages: plan.stages.len(),
+            rows: rows,
+            scanned: scanned,
+            skipped: skipped,
+        });
+    }
+
+    if let Some((ref name, direction)) = plan.order {
+        try!(order_result(&mut ordered, name, direction));
+        for (name, mut data) in ordered {
+            if let Some(&(limit, offset)) = windows.get(&name) {
+                data.window(offset, limit);
+            }
+            chunk(plan.output_name(name), data);
+        }
+    }
+
+    Ok(())
+}
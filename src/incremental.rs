@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+
+use data::{ColumnName, Data, Db};
+use plan::{Plan, PlanNode};
+
+#[derive(Debug)]
+pub enum Error {
+    UnsupportedPlan,
+    MissingColumn(ColumnName),
+}
+
+/// Running aggregates of a selected column, `sum` only counts Int columns
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Aggregate {
+    pub count: usize,
+    pub sum: i64,
+}
+
+impl Aggregate {
+    fn from_data(data: &Data) -> Aggregate {
+        let sum = match *data {
+            Data::Int(ref data) => data.iter().fold(0, |acc, datum| acc + datum.value),
+            _ => 0,
+        };
+
+        Aggregate {
+            count: data.len(),
+            sum: sum,
+        }
+    }
+
+    fn merge(&mut self, other: &Aggregate) {
+        self.count += other.count;
+        self.sum += other.sum;
+    }
+}
+
+/// Keeps the aggregates of a query up to date from newly appended datums
+/// rather than re-running it over the whole `Db`.
+///
+/// Only plans made entirely of `Select` nodes are supported, their counts and
+/// sums only ever grow with appends. A `Where` or `Join` could start matching
+/// older datums once new ones arrive, so those plans are rejected. Select
+/// limits are ignored, aggregates always cover every datum. Distinct selects
+/// are rejected too, their counts would need every value seen so far.
+///
+/// ```
+/// # use std::fs;
+/// use std::str::FromStr;
+/// use twin_query::{exec, insert, ColumnName, Db, ExecOptions, IncrementalQuery, Plan, Value};
+///
+/// let dir = std::env::temp_dir();
+/// let (first, second) = (dir.join("twin-query-incremental-1.db"),
+///                        dir.join("twin-query-incremental-2.db"));
+/// let (first, second) = (first.to_str().unwrap(), second.to_str().unwrap());
+/// for path in &[first, second] {
+///     let _ = fs::remove_file(path);
+///     insert::add_to_db(path, "data/qux.schema", Some("data/qux.csv"), Default::default())
+///         .unwrap();
+/// }
+///
+/// let mut db = Db::from_file(first).unwrap();
+/// let plan = Plan::from_str("s qux.v").unwrap();
+/// let mut query = IncrementalQuery::new(&db, &plan).unwrap();
+///
+/// let appended = Db::from_file(second).unwrap();
+/// query.update(&appended);
+/// db.merge(appended).unwrap();
+///
+/// // Recompute over the whole merged db
+/// let result = exec(&db, &plan, ExecOptions::default()).unwrap();
+/// let data = &result[0].1;
+/// let sum = (0..data.len()).fold(0, |acc, i| {
+///     match data.value(i) {
+///         Some(Value::Int(v)) => acc + v,
+///         _ => acc,
+///     }
+/// });
+///
+/// let aggregate = query.get(&ColumnName::new("qux", "v")).unwrap();
+/// assert_eq!(aggregate.count, data.len());
+/// assert_eq!(aggregate.sum, sum);
+///
+/// let distinct = Plan::from_str("s distinct qux.v").unwrap();
+/// assert!(IncrementalQuery::new(&db, &distinct).is_err());
+/// # fs::remove_file(first).unwrap();
+/// # fs::remove_file(second).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct IncrementalQuery {
+    columns: Vec<ColumnName>,
+    aggregates: HashMap<ColumnName, Aggregate>,
+}
+
+impl IncrementalQuery {
+    pub fn new(db: &Db, plan: &Plan) -> Result<IncrementalQuery, Error> {
+        let mut columns = vec![];
+
+        for stage in &plan.stages {
+            for node in &stage.nodes {
+                match *node {
+                    PlanNode::Select(_, _, _, true) => return Err(Error::UnsupportedPlan),
+                    PlanNode::Select(ref name, _, _, _) => columns.push(name.to_owned()),
+                    _ => return Err(Error::UnsupportedPlan),
+                }
+            }
+        }
+
+        for name in &columns {
+            if !db.cols.contains_key(name) {
+                return Err(Error::MissingColumn(name.to_owned()));
+            }
+        }
+
+        let mut query = IncrementalQuery {
+            columns: columns,
+            aggregates: HashMap::new(),
+        };
+        query.update(db);
+        Ok(query)
+    }
+
+    /// Fold in a `Db` holding only the datums appended since the last update
+    pub fn update(&mut self, appended: &Db) {
+        for name in &self.columns {
+            let delta = match appended.cols.get(name) {
+                Some(column) => Aggregate::from_data(&column.data),
+                None => continue,
+            };
+
+            self.aggregates
+                .entry(name.to_owned())
+                .or_insert_with(Aggregate::default)
+                .merge(&delta);
+        }
+    }
+
+    pub fn get(&self, name: &ColumnName) -> Option<&Aggregate> {
+        self.aggregates.get(name)
+    }
+}
@@ -1,16 +1,86 @@
 use csv;
-use std::collections::{HashMap, HashSet};
-use std::fs::File;
-use std::io::Read;
+use flate2::read::GzDecoder;
+use libc;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use rustc_serialize::json::Json;
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use time;
 use toml;
 
-use data::{ColumnName, ColumnType, Db};
+use data::{self, ColumnName, ColumnType, Data, Db, Ids};
 
+/// Why adding to a db failed
 #[derive(Debug)]
-enum Error {
+pub enum Error {
+    Io(io::Error),
+    Csv(csv::Error),
+    Db(data::Error),
+    /// The schema file doesn't describe tables that can be added
+    InvalidSchema(String),
+    /// The first row doesn't name the schema's `csv_ordering`
+    InvalidHeader(String),
+    /// A row that doesn't fit the schema, `skip_bad_rows` counts these instead
+    BadRow(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "{}", e),
+            Error::Csv(ref e) => write!(f, "{}", e),
+            Error::Db(ref e) => write!(f, "{:?}", e),
+            Error::InvalidSchema(ref message) |
+            Error::InvalidHeader(ref message) |
+            Error::BadRow(ref message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(err: csv::Error) -> Error {
+        Error::Csv(err)
+    }
+}
+
+impl From<data::Error> for Error {
+    fn from(err: data::Error) -> Error {
+        Error::Db(err)
+    }
+}
+
+#[derive(Debug)]
+enum SchemaError {
     MissingId,
     MissingTime,
     InvalidOrdering,
+    InvalidIndex,
+    InvalidIdType,
+    InvalidType(String),
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SchemaError::MissingId => write!(f, "csv_ordering has no id"),
+            SchemaError::MissingTime => write!(f, "csv_ordering has no time"),
+            SchemaError::InvalidOrdering => {
+                write!(f, "csv_ordering has to name every column once")
+            }
+            SchemaError::InvalidIndex => write!(f, "an index names an unknown column"),
+            SchemaError::InvalidIdType => write!(f, "id_type has to be Int or String"),
+            SchemaError::InvalidType(ref t) => write!(f, "unknown column type {}", t),
+        }
+    }
 }
 
 #[derive(Debug, RustcEncodable, RustcDecodable)]
@@ -18,6 +88,17 @@ struct RawSchema {
     table: String,
     columns: HashMap<String, String>,
     csv_ordering: Vec<String>,
+    indexes: Option<Vec<String>>,
+    hash_indexes: Option<Vec<String>>,
+    id_type: Option<String>,
+    unique_times: Option<bool>,
+    csv: Option<String>,
+}
+
+/// A schema file describing several tables, each naming its own CSV
+#[derive(Debug, RustcDecodable)]
+struct RawSchemaSet {
+    tables: Option<Vec<RawSchema>>,
 }
 
 impl RawSchema {
@@ -31,40 +112,62 @@ struct Schema {
     table: String,
     columns: HashMap<ColumnName, ColumnType>,
     csv_ordering: Vec<ColumnName>,
+    indexes: Vec<ColumnName>,
+    hash_indexes: Vec<ColumnName>,
+    string_ids: bool,
+    unique_times: bool,
+    csv: Option<String>,
 }
 
 impl Schema {
-    fn from_raw(mut raw: RawSchema) -> Result<Schema, Error> {
-        raw.add_column("id", "Int");
+    fn from_raw(mut raw: RawSchema) -> Result<Schema, SchemaError> {
+        let string_ids = match raw.id_type.as_ref().map(|t| t.as_str()) {
+            None | Some("Int") => false,
+            Some("String") => true,
+            _ => return Err(SchemaError::InvalidIdType),
+        };
+
+        raw.add_column("id", if string_ids { "String" } else { "Int" });
         raw.add_column("time", "Int");
         let ordering_set = raw.csv_ordering.iter().map(|s| s.as_str()).collect::<HashSet<&str>>();
 
         for col in &raw.csv_ordering {
             if !raw.columns.contains_key(col) {
-                return Err(Error::InvalidOrdering);
+                return Err(SchemaError::InvalidOrdering);
             }
         }
 
         if raw.csv_ordering.len() != raw.columns.len() {
-            return Err(Error::InvalidOrdering);
+            return Err(SchemaError::InvalidOrdering);
         }
 
         if raw.csv_ordering.len() != ordering_set.len() {
-            return Err(Error::InvalidOrdering);
+            return Err(SchemaError::InvalidOrdering);
         }
 
         if !ordering_set.contains("id") {
-            return Err(Error::MissingId);
+            return Err(SchemaError::MissingId);
         }
 
         if !ordering_set.contains("time") {
-            return Err(Error::MissingTime);
+            return Err(SchemaError::MissingTime);
+        }
+
+        let indexes = raw.indexes.clone().unwrap_or_else(Vec::new);
+        let hash_indexes = raw.hash_indexes.clone().unwrap_or_else(Vec::new);
+        if indexes.iter().chain(&hash_indexes).any(|col| !raw.columns.contains_key(col)) {
+            return Err(SchemaError::InvalidIndex);
         }
 
         Ok(Schema {
             table: raw.table.to_owned(),
-            columns: Self::column_names_and_types(&raw.table, raw.columns),
+            columns: try!(Self::column_names_and_types(&raw.table, raw.columns)),
             csv_ordering: Self::ordering(&raw.table, raw.csv_ordering.clone()),
+            indexes: Self::ordering(&raw.table, indexes),
+            hash_indexes: Self::ordering(&raw.table, hash_indexes),
+            string_ids: string_ids,
+            unique_times: raw.unique_times.unwrap_or(false),
+            csv: raw.csv.clone(),
         })
     }
 
@@ -73,17 +176,20 @@ impl Schema {
     }
 
     fn column_names_and_types(table: &str, raw: HashMap<String, String>)
-                              -> HashMap<ColumnName, ColumnType> {
+                              -> Result<HashMap<ColumnName, ColumnType>, SchemaError> {
         raw.iter()
            .map(|(col_name, col_type)| {
                let t = match col_type.as_str() {
                    "Bool" => ColumnType::Bool,
                    "Int" => ColumnType::Int,
                    "String" => ColumnType::String,
-                   _ => panic!("Invalid column type"),
+                   "Float" => ColumnType::Float,
+                   "Timestamp" => ColumnType::Timestamp,
+                   "Bytes" => ColumnType::Bytes,
+                   _ => return Err(SchemaError::InvalidType(col_type.to_owned())),
                };
                let name = ColumnName::new(table.to_owned(), col_name.to_owned());
-               (name, t)
+               Ok((name, t))
            })
            .collect()
     }
@@ -93,43 +199,547 @@ impl Schema {
     }
 }
 
-fn read_schema(schema_path: &str) -> Schema {
+/// Either a single table or a `[[tables]]` array of them
+fn read_schemas(schema_path: &str) -> Result<Vec<Schema>, Error> {
     let mut contents = String::new();
-    File::open(schema_path)
-        .and_then(|mut f| f.read_to_string(&mut contents))
-        .unwrap();
+    try!(File::open(schema_path).and_then(|mut f| f.read_to_string(&mut contents)));
 
-    Schema::from_raw(toml::decode_str(&contents).unwrap()).expect("Invalid schema")
+    let invalid = |reason: String| Error::InvalidSchema(format!("{}: {}", schema_path, reason));
+    let raws = match toml::decode_str::<RawSchemaSet>(&contents) {
+        Some(RawSchemaSet { tables: Some(tables) }) => tables,
+        _ => {
+            vec![try!(toml::decode_str(&contents)
+                          .ok_or_else(|| invalid("not a table or tables".to_owned())))]
+        }
+    };
+    raws.into_iter()
+        .map(|raw| Schema::from_raw(raw).map_err(|e| invalid(e.to_string())))
+        .collect()
 }
 
-pub fn add_to_db(file_path: &str, schema_path: &str, csv_path: &str) {
-    let mut db = Db::from_file(file_path).expect("Failed to load db from file");
+/// Materialize query results as a new table and write the `Db` back to disk
+pub fn add_result_to_db(db: &mut Db,
+                        file_path: &str,
+                        table: &str,
+                        result: Vec<(ColumnName, Data)>)
+                        -> Result<(), data::Error> {
+    try!(db.add_table(table, result));
+    db.optimize_columns();
+    db.write(file_path)
+}
 
-    let schema = read_schema(schema_path);
-    let id_index = schema.column_index("id").expect("`id` column not found");
-    let time_index = schema.column_index("time").expect("`time` column not found");
+pub fn delete_from_db(db: &mut Db,
+                      file_path: &str,
+                      name: &ColumnName,
+                      ids: &Ids)
+                      -> Result<usize, data::Error> {
+    let removed = try!(db.delete(name, ids));
+    try!(db.write(file_path));
+    Ok(removed)
+}
+
+pub fn update_db(db: &mut Db,
+                 file_path: &str,
+                 name: &ColumnName,
+                 id: usize,
+                 value: String,
+                 time: usize)
+                 -> Result<(), data::Error> {
+    try!(db.update(name, id, value, time));
+    db.write(file_path)
+}
+
+pub fn merge_into_db(db: &mut Db, file_path: &str, other: Db) -> Result<(), data::Error> {
+    try!(db.merge(other));
+    db.write(file_path)
+}
+
+/// Rebuilds the rows of `table` as CSV, with a header of its column names in
+/// sorted order. Datums of every column with the same id and time form a row,
+/// and a blank cell is a column without one, the same null a blank cell adds.
+pub fn export_table<W: Write>(db: &Db, table: &str, writer: W) -> csv::Result<()> {
+    let mut cols = db.cols
+                     .iter()
+                     .filter(|&(name, _)| name.table == table)
+                     .collect::<Vec<_>>();
+    cols.sort_by(|a, b| a.0.column.cmp(&b.0.column));
+
+    // Keyed by time first to keep rows in the order they were added, the
+    // last part tells apart datums added twice for one id and time
+    let mut rows: BTreeMap<(usize, usize, usize), Vec<String>> = BTreeMap::new();
+    for (i, &(_, column)) in cols.iter().enumerate() {
+        let mut seen = HashMap::new();
+        for offset in 0..column.data.len() {
+            let (id, time) = (column.data.id(offset), column.data.time(offset));
+            let count = seen.entry((id, time)).or_insert(0);
+            let row = rows.entry((time, id, *count))
+                          .or_insert_with(|| vec![String::new(); cols.len()]);
+            row[i] = column.data.value(offset).unwrap().to_raw_string();
+            *count += 1;
+        }
+    }
+
+    let mut wtr = csv::Writer::from_writer(writer);
+    try!(wtr.write(cols.iter().map(|&(name, _)| name.column.as_str())));
+    for ((time, id, _), mut row) in rows {
+        // Updates add datums at times without an id or time datum
+        for (i, &(name, _)) in cols.iter().enumerate() {
+            if row[i].is_empty() && name.column == "id" {
+                row[i] = db.external_id(table, id).map_or_else(|| id.to_string(), |e| e.to_owned());
+            } else if row[i].is_empty() && name.column == "time" {
+                row[i] = time.to_string();
+            }
+        }
+        try!(wtr.write(row.iter()));
+    }
+    wtr.flush()
+}
+
+/// A `Db` with the schema's columns declared but no datums
+pub fn schema_db(schema_path: &str) -> Result<Db, Error> {
+    let mut db = Db::new();
+
+    for schema in try!(read_schemas(schema_path)) {
+        for (column_name, column_type) in schema.columns {
+            try!(db.add_column(column_name, column_type));
+        }
+    }
+
+    Ok(db)
+}
+
+/// How `add` reads its CSVs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddOptions {
+    pub delimiter: u8,
+    /// Skip the first row of every CSV
+    pub has_headers: bool,
+    /// The first row has to name the columns of `csv_ordering`, in order
+    pub check_headers: bool,
+    /// Decompress every CSV, not only those ending in `.gz`
+    pub gzip: bool,
+    /// Spill the table to a sorted run on disk every this many rows
+    pub batch_rows: Option<usize>,
+    /// Skip and count rows with cells that don't parse instead of stopping
+    pub skip_bad_rows: bool,
+    /// Rows are JSON objects, one per line, instead of CSV
+    pub json_lines: bool,
+    /// Write the new datums to a segment next to the db instead of rewriting
+    /// it, unique times are only checked within the new rows
+    pub append: bool,
+}
+
+impl Default for AddOptions {
+    fn default() -> Self {
+        AddOptions {
+            delimiter: b',',
+            has_headers: false,
+            check_headers: false,
+            gzip: false,
+            batch_rows: None,
+            skip_bad_rows: false,
+            json_lines: false,
+            append: false,
+        }
+    }
+}
+
+/// Sorted runs spilled while adding, kept in a temp dir that's removed when
+/// they're dropped
+struct Runs {
+    dir: PathBuf,
+    paths: Vec<PathBuf>,
+}
+
+impl Runs {
+    fn new() -> Runs {
+        let name = format!("twin-query-runs-{}-{}",
+                           unsafe { libc::getpid() },
+                           time::precise_time_ns());
+        Runs {
+            dir: env::temp_dir().join(name),
+            paths: vec![],
+        }
+    }
+
+    fn spill(&mut self, db: &mut Db, table: &str) -> Result<(), data::Error> {
+        let path = self.dir.join(format!("run{}", self.paths.len()));
+        try!(db.spill_table(table, &path));
+        self.paths.push(path);
+        Ok(())
+    }
+
+    fn merge(&self, db: &mut Db) -> Result<(), data::Error> {
+        db.merge_runs(&self.paths)
+    }
+}
+
+impl Drop for Runs {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Add the CSVs of every table in the schema, `csv_path` is only needed for
+/// schemas that don't name their CSV. Those paths are relative to the schema.
+/// Returns the number of datums added and of bad rows skipped.
+///
+/// ```
+/// # use std::fs;
+/// use twin_query::insert::{self, Error};
+///
+/// let path = std::env::temp_dir().join("twin-query-bad-row.db");
+/// let path = path.to_str().unwrap();
+/// match insert::add_to_db(path, "data/contact.schema", Some("data/foo.csv"), Default::default()) {
+///     Err(Error::BadRow(message)) => {
+///         assert_eq!(message, "row 1, column contact.age: expected Int, got 'true'")
+///     }
+///     result => panic!("expected a bad row, got {:?}", result),
+/// }
+/// # let _ = fs::remove_file(path);
+/// ```
+pub fn add_to_db(file_path: &str,
+                 schema_path: &str,
+                 csv_path: Option<&str>,
+                 options: AddOptions)
+                 -> Result<(usize, usize), Error> {
+    let schemas = try!(read_schemas(schema_path));
+    if csv_path.is_some() && schemas.len() > 1 {
+        return Err(Error::InvalidSchema("schemas with several tables name their own CSVs"
+                                            .to_owned()));
+    }
+
+    let schema_dir = Path::new(schema_path).parent().unwrap_or_else(|| Path::new(""));
+    let mut tables = vec![];
+    for schema in schemas {
+        let path = match (csv_path, schema.csv.as_ref()) {
+            (Some(csv_path), _) => PathBuf::from(csv_path),
+            (None, Some(csv)) => schema_dir.join(csv),
+            (None, None) => {
+                return Err(Error::InvalidSchema(format!("no CSV for table {}", schema.table)))
+            }
+        };
+        tables.push((schema, path));
+    }
+
+    add_tables_to_db(file_path, tables, options)
+}
+
+/// Add a CSV with a header row without a schema file, the table is named
+/// after the file and column types are guessed from the first rows
+pub fn add_inferred_to_db(file_path: &str,
+                          csv_path: &str,
+                          mut options: AddOptions)
+                          -> Result<(usize, usize), Error> {
+    let path = PathBuf::from(csv_path);
+    let schema = try!(infer_schema(&path, options));
+    options.has_headers = true;
+    add_tables_to_db(file_path, vec![(schema, path)], options)
+}
 
-    for (column_name, column_type) in schema.columns {
-        db.add_column(column_name, column_type)
-          .expect("Failed to add column to db");
+/// Files ending in `.gz`, or any with `gzip`, are decompressed as they're read
+fn open_file(path: &Path, gzip: bool) -> io::Result<Box<Read>> {
+    let file = try!(File::open(path));
+    if gzip || path.extension().map_or(false, |ext| ext == "gz") {
+        Ok(Box::new(try!(GzDecoder::new(file))))
+    } else {
+        Ok(Box::new(file))
     }
+}
+
+/// Rows read to guess the type of each column
+const INFER_ROWS: usize = 100;
 
-    let mut rdr = csv::Reader::from_file(csv_path)
-                      .and_then(|r| Ok(r.has_headers(false)))
-                      .unwrap();
+fn infer_schema(csv_path: &Path, options: AddOptions) -> Result<Schema, Error> {
+    let mut rdr = csv::Reader::from_reader(try!(open_file(csv_path, options.gzip)))
+                      .delimiter(options.delimiter);
+    let headers = try!(rdr.headers());
+    let rows = try!(rdr.records()
+                       .take(INFER_ROWS)
+                       .collect::<Result<Vec<Vec<String>>, csv::Error>>());
+
+    // `from_raw` replaces whatever is guessed for `id` and `time` with `Int`
+    let mut columns = HashMap::new();
+    for (index, header) in headers.iter().enumerate() {
+        let values = rows.iter()
+                         .filter_map(|row| row.get(index))
+                         .filter(|value| !value.is_empty())
+                         .collect::<Vec<&String>>();
+        let t = if values.is_empty() {
+            "String"
+        } else if values.iter().all(|value| value.parse::<bool>().is_ok()) {
+            "Bool"
+        } else if values.iter().all(|value| value.parse::<i64>().is_ok()) {
+            "Int"
+        } else {
+            "String"
+        };
+        columns.insert(header.to_owned(), t.to_owned());
+    }
 
+    // Everything before the first `.`, so `person.csv.gz` is `person` too
+    let table = try!(csv_path.file_name()
+                             .and_then(|name| name.to_str())
+                             .and_then(|name| name.split('.').next())
+                             .ok_or_else(|| {
+                                 Error::InvalidSchema(format!("can't name a table after {}",
+                                                              csv_path.display()))
+                             }));
+    let raw = RawSchema {
+        table: table.to_owned(),
+        columns: columns,
+        csv_ordering: headers,
+        indexes: None,
+        hash_indexes: None,
+        id_type: None,
+        unique_times: None,
+        csv: None,
+    };
+    Schema::from_raw(raw).map_err(|e| {
+        Error::InvalidSchema(format!("{}: {}", csv_path.display(), e))
+    })
+}
+
+fn add_tables_to_db(file_path: &str,
+                    tables: Vec<(Schema, PathBuf)>,
+                    options: AddOptions)
+                    -> Result<(usize, usize), Error> {
+    let mut db = if options.append {
+        Db::new()
+    } else {
+        try!(Db::from_file(file_path))
+    };
+    let mut runs = Runs::new();
+    let mut unique_tables = vec![];
     let mut count = 0;
-    for row in rdr.records().map(|r| r.unwrap()) {
-        let id = row.get(id_index).unwrap().parse::<usize>().unwrap();
-        let time = row.get(time_index).unwrap().parse::<usize>().unwrap();
+    let mut skipped = 0;
 
-        for (name, value) in schema.csv_ordering.iter().zip(row.iter()) {
-            db.add_datum(&name, id, value.to_owned(), time).expect("Failed to add datum to db");
-            count += 1;
+    for (schema, path) in tables {
+        if schema.unique_times {
+            unique_tables.push(schema.table.to_owned());
         }
+        let (added, skipped_rows) = try!(add_file_to_db(&mut db, schema, &path, options,
+                                                        &mut runs));
+        count += added;
+        skipped += skipped_rows;
     }
+    try!(runs.merge(&mut db));
 
-    println!("added {:?} datums", count);
     db.optimize_columns();
-    db.write(file_path).expect("Failed to write db to disk");
+    for table in &unique_tables {
+        try!(db.check_unique_times(table));
+    }
+    if options.append {
+        try!(db.append_to_file(file_path));
+    } else {
+        try!(db.write(file_path));
+    }
+    Ok((count, skipped))
+}
+
+fn add_file_to_db(db: &mut Db,
+                  mut schema: Schema,
+                  path: &Path,
+                  options: AddOptions,
+                  runs: &mut Runs)
+                  -> Result<(usize, usize), Error> {
+    // Columns of a table that's already in the db are appended to
+    for (column_name, column_type) in schema.columns.drain() {
+        if db.cols.contains_key(&column_name) {
+            continue;
+        }
+        try!(db.add_column(column_name, column_type));
+    }
+
+    for name in &schema.indexes {
+        try!(db.add_value_index(name));
+    }
+
+    for name in &schema.hash_indexes {
+        try!(db.add_hash_index(name));
+    }
+
+    let file = try!(open_file(path, options.gzip));
+    if options.json_lines {
+        let mut rows = io::BufReader::new(file)
+                           .lines()
+                           .enumerate()
+                           .map(|(index, line)| {
+                               line.map_err(Error::from)
+                                   .and_then(|line| json_row(&schema, index, &line))
+                           });
+        return add_rows_to_db(db, &schema, &mut rows, false, options, runs);
+    }
+
+    let has_headers = options.has_headers || options.check_headers;
+    let mut rdr = csv::Reader::from_reader(file)
+                      .has_headers(has_headers)
+                      .delimiter(options.delimiter)
+                      .flexible(true);
+
+    if options.check_headers {
+        let headers = try!(rdr.headers());
+        let ordering = schema.csv_ordering
+                             .iter()
+                             .map(|name| name.column.to_owned())
+                             .collect::<Vec<String>>();
+        if headers != ordering {
+            let message = format!("header {} doesn't match csv_ordering {} of {}",
+                                  headers.join(","),
+                                  ordering.join(","),
+                                  schema.table);
+            return Err(Error::InvalidHeader(message));
+        }
+    }
+
+    let mut rows = rdr.records().map(|r| r.map_err(Error::from));
+    add_rows_to_db(db, &schema, &mut rows, has_headers, options, runs)
+}
+
+/// A JSON object as a row in `csv_ordering` order, missing keys and nulls
+/// are blank cells like in a CSV
+fn json_row(schema: &Schema, index: usize, line: &str) -> Result<Vec<String>, Error> {
+    let bad = |message: String| Error::BadRow(format!("line {}: {}", index + 1, message));
+
+    let object = match Json::from_str(line) {
+        Ok(Json::Object(object)) => object,
+        Ok(_) => return Err(bad("expected an object".to_owned())),
+        Err(e) => return Err(bad(format!("invalid JSON, {}", e))),
+    };
+
+    for key in object.keys() {
+        if schema.column_index(key).is_none() {
+            return Err(bad(format!("unknown column {}.{}", schema.table, key)));
+        }
+    }
+
+    schema.csv_ordering
+          .iter()
+          .map(|name| {
+              match object.get(&name.column) {
+                  Some(&Json::String(ref v)) => Ok(v.to_owned()),
+                  Some(&Json::I64(v)) => Ok(v.to_string()),
+                  Some(&Json::U64(v)) => Ok(v.to_string()),
+                  Some(&Json::F64(v)) => Ok(v.to_string()),
+                  Some(&Json::Boolean(v)) => Ok(v.to_string()),
+                  Some(&Json::Null) | None if name.column == "id" || name.column == "time" => {
+                      Err(bad(format!("missing {}", name.column)))
+                  }
+                  Some(&Json::Null) | None => Ok(String::new()),
+                  Some(_) => Err(bad(format!("{} can't be an array or object", name))),
+              }
+          })
+          .collect()
+}
+
+fn add_rows_to_db(db: &mut Db,
+                  schema: &Schema,
+                  rows: &mut Iterator<Item = Result<Vec<String>, Error>>,
+                  has_headers: bool,
+                  options: AddOptions,
+                  runs: &mut Runs)
+                  -> Result<(usize, usize), Error> {
+    // `from_raw` checks the ordering has both
+    let id_index = schema.column_index("id").expect("`id` column not found");
+    let time_index = schema.column_index("time").expect("`time` column not found");
+
+    let mut count = 0;
+    let mut skipped = 0;
+    for (index, row) in rows.enumerate() {
+        let row = try!(row);
+        if options.batch_rows.map_or(false, |batch| batch > 0 && index > 0 && index % batch == 0) {
+            try!(runs.spill(db, &schema.table));
+        }
+
+        // Rows are numbered like the lines of the CSV
+        let line = index + if has_headers { 2 } else { 1 };
+
+        if row.len() != schema.csv_ordering.len() {
+            if options.skip_bad_rows {
+                skipped += 1;
+                continue;
+            }
+            return Err(Error::BadRow(format!("row {}: expected {} columns, got {}",
+                                             line,
+                                             schema.csv_ordering.len(),
+                                             row.len())));
+        }
+
+        // Every cell is parsed before any is added, so bad rows are skipped whole
+        let mut values = vec![];
+        let mut error = None;
+        for (name, value) in schema.csv_ordering.iter().zip(row.iter()) {
+            // Blank cells are nulls, stored by leaving the datum out
+            if value.is_empty() {
+                continue;
+            }
+            match db.parse_value(name, value.to_owned()) {
+                Ok(value) => values.push((name, value)),
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+        }
+
+        match error {
+            None => (),
+            Some(data::Error::ParseError(..)) if options.skip_bad_rows => {
+                skipped += 1;
+                continue;
+            }
+            Some(data::Error::ParseError(name, t, value)) => {
+                return Err(Error::BadRow(format!("row {}, column {}: expected {:?}, got '{}'",
+                                                 line,
+                                                 name,
+                                                 t,
+                                                 value)));
+            }
+            Some(e) => return Err(Error::from(e)),
+        }
+
+        // Ids and times are indexes, negative or blank ones make a bad row
+        let raw_id = &row[id_index];
+        let raw_time = &row[time_index];
+        let time = raw_time.parse::<usize>().ok();
+        let numeric_id = if schema.string_ids {
+            None
+        } else {
+            raw_id.parse::<usize>().ok()
+        };
+        let bad = if time.is_none() {
+            Some(("time", "a non-negative Int", raw_time))
+        } else if schema.string_ids && raw_id.is_empty() {
+            Some(("id", "an id", raw_id))
+        } else if !schema.string_ids && numeric_id.is_none() {
+            Some(("id", "a non-negative Int", raw_id))
+        } else {
+            None
+        };
+        if let Some((column, expected, value)) = bad {
+            if options.skip_bad_rows {
+                skipped += 1;
+                continue;
+            }
+            return Err(Error::BadRow(format!("row {}, column {}.{}: expected {}, got '{}'",
+                                             line,
+                                             schema.table,
+                                             column,
+                                             expected,
+                                             value)));
+        }
+
+        let id = match numeric_id {
+            Some(id) => id,
+            None => db.intern_id(&schema.table, raw_id),
+        };
+        let time = time.unwrap();
+
+        count += values.len();
+        for (name, value) in values {
+            try!(db.add_value(name, id, value, time));
+        }
+    }
+
+    Ok((count, skipped))
 }
@@ -0,0 +1,59 @@
+//! A time series query engine. Tables are loaded from CSVs into a `Db`, a
+//! query parses into a `Plan` and `exec` runs it against the `Db`.
+//!
+//! ```
+//! extern crate twin_query;
+//!
+//! use std::fs;
+//! use std::str::FromStr;
+//! use twin_query::{exec, insert, Db, ExecOptions, Plan};
+//!
+//! # fn main() {
+//! let path = std::env::temp_dir().join("twin-query-doc.db");
+//! let path = path.to_str().unwrap();
+//! let _ = fs::remove_file(path);
+//! insert::add_to_db(path, "data/foo.schema", Some("data/foo.csv"), Default::default()).unwrap();
+//!
+//! let db = Db::from_file(path).unwrap();
+//! let plan = Plan::from_str("s foo.a\nw foo.b = true").unwrap();
+//! plan.validate_against(&db).unwrap();
+//!
+//! let result = exec(&db, &plan, ExecOptions::default()).unwrap();
+//! assert_eq!(result.len(), 1);
+//! assert_eq!(result[0].1.len(), 2);
+//! fs::remove_file(path).unwrap();
+//! # }
+//! ```
+
+#![feature(plugin)]
+#![plugin(peg_syntax_ext)]
+#![plugin(clippy)]
+#![allow(len_zero)] // for pegile macro
+#![allow(len_without_is_empty)]
+
+extern crate bincode;
+extern crate csv;
+extern crate crossbeam;
+extern crate flate2;
+extern crate libc;
+extern crate petgraph;
+extern crate prettytable;
+extern crate rustc_serialize;
+extern crate time;
+extern crate toml;
+
+pub mod data;
+pub mod exec;
+pub mod insert;
+pub mod plan;
+pub mod query;
+pub mod table;
+pub mod validate;
+mod incremental;
+mod timestamp;
+
+pub use data::{Column, ColumnName, ColumnType, Data, Datum, Db, GenericDatum, Value};
+pub use exec::{exec, exec_shared, ExecOptions, QueryResult};
+pub use incremental::{Aggregate, Error as IncrementalError, IncrementalQuery};
+pub use insert::AddOptions;
+pub use plan::{Plan, PlanBuilder};
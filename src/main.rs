@@ -1,72 +1,670 @@
 #![feature(plugin)]
-#![plugin(peg_syntax_ext)]
 #![plugin(clippy)]
-#![allow(len_zero)] // for pegile macro
-#![allow(len_without_is_empty)]
 
-extern crate bincode;
 extern crate clap;
 extern crate csv;
-extern crate crossbeam;
-extern crate flate2;
-extern crate petgraph;
+extern crate libc;
 extern crate prettytable;
 extern crate rl_sys;
 extern crate rustc_serialize;
 extern crate time;
-extern crate toml;
+extern crate twin_query;
 
-mod data;
-mod exec;
-mod insert;
-mod plan;
 mod repl;
 
 use clap::{App, SubCommand};
-use std::str::FromStr;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
 
-use data::Db;
-use plan::Plan;
+use twin_query::{data, exec, insert, plan, table, validate};
+use twin_query::data::{Codec, ColumnName, CompressionLevel, Data, Db};
+use twin_query::plan::Plan;
 
-fn exec_query(file_path: &str, query_raw: &str) {
-    let query = query_raw.replace("\\n", "\n");
+/// Why `query` failed, each kind exits with its own code so scripts can tell
+/// them apart
+enum QueryError {
+    /// The arguments don't make sense, like clap's own errors
+    Usage(String),
+    /// The query doesn't parse or doesn't fit the db
+    Parse(String),
+    /// Running the plan failed
+    Exec(String),
+    /// The db or the query file couldn't be read or written
+    Io(String),
+}
+
+impl QueryError {
+    fn exit_code(&self) -> i32 {
+        match *self {
+            QueryError::Usage(_) => 1,
+            QueryError::Parse(_) => 2,
+            QueryError::Exec(_) => 3,
+            QueryError::Io(_) => 4,
+        }
+    }
+
+    fn message(&self) -> &str {
+        match *self {
+            QueryError::Usage(ref message) |
+            QueryError::Parse(ref message) |
+            QueryError::Exec(ref message) |
+            QueryError::Io(ref message) => message,
+        }
+    }
+}
+
+/// Parse `query` and check it against `db`, an unknown column also lists the
+/// columns its table does have
+fn checked_plan(db: &Db, query: &str, default_limit: usize) -> Result<Plan, QueryError> {
+    let plan = try!(Plan::parse_against(query, db, default_limit)
+                        .map_err(|e| QueryError::Parse(format!("{}", e))));
+    if let Err(e) = plan.validate_against(db) {
+        let mut message = format!("{}", e);
+        if let plan::Error::UnknownColumn(ref col_name) = e {
+            message.push_str(&format!("\ncolumns in {}: {}",
+                                      col_name.table,
+                                      db.table_columns(&col_name.table).join(", ")));
+        }
+        return Err(QueryError::Parse(message));
+    }
+    for col_name in plan.unused_filters() {
+        writeln!(io::stderr(),
+                 "warning: the filter on {} isn't used by anything selected",
+                 col_name)
+            .expect("Failed to write error");
+    }
+    Ok(plan)
+}
+
+/// `checked_plan`, printing the error and exiting if it fails
+fn plan_or_exit(db: &Db, query: &str) -> Plan {
+    match checked_plan(db, query, plan::DEFAULT_LIMIT) {
+        Ok(plan) => plan,
+        Err(e) => {
+            writeln!(io::stderr(), "{}", e.message()).expect("Failed to write error");
+            process::exit(1);
+        }
+    }
+}
+
+/// Rows per column of queries without an `l` line, from `--default-limit`
+fn default_limit(value: Option<&str>) -> usize {
+    value.map_or(plan::DEFAULT_LIMIT, |limit| {
+        match limit.parse() {
+            Ok(limit) if limit > 0 => limit,
+            _ => {
+                writeln!(io::stderr(), "default limit must be a positive integer")
+                    .expect("Failed to write error");
+                process::exit(1);
+            }
+        }
+    })
+}
+
+/// Workers per stage from `--threads`, one per CPU without it
+fn exec_options(threads: Option<&str>) -> Result<exec::ExecOptions, QueryError> {
+    let mut options = exec::ExecOptions::default();
+    if let Some(threads) = threads {
+        options.threads = match threads.parse() {
+            Ok(threads) if threads > 0 => threads,
+            _ => return Err(QueryError::Usage("threads has to be a positive integer".to_owned())),
+        };
+    }
+    Ok(options)
+}
+
+/// The query of the `query` subcommand, from `--file` or the command line
+/// where its lines are separated by a literal `\n`
+fn read_query(path: Option<&str>, args: Option<Vec<&str>>) -> Result<String, QueryError> {
+    match (path, args) {
+        (Some(path), _) => {
+            let mut query = String::new();
+            try!(File::open(path)
+                     .and_then(|mut f| f.read_to_string(&mut query))
+                     .map_err(|e| {
+                         QueryError::Io(format!("failed to read query from {}: {}", path, e))
+                     }));
+            Ok(query.trim_right().to_owned())
+        }
+        (None, Some(args)) => Ok(args.join(",").replace("\\n", "\n")),
+        (None, None) => Err(QueryError::Usage("either a QUERY or --file is required".to_owned())),
+    }
+}
+
+/// Delete the datums of `column` for every id matched by `filter`, a query
+/// of where and join lines. Deleting `table.id` removes whole rows.
+fn delete_query(file_path: &str, column: &str, filter: &str) {
+    let mut db = Db::from_file(file_path).expect("Failed to load db from file");
+    let name = match ColumnName::from_dotted(column) {
+        Ok(name) => name,
+        Err(e) => {
+            writeln!(io::stderr(), "{:?}", e).expect("Failed to write error");
+            process::exit(1);
+        }
+    };
+
+    let query = format!("s {}\n{}", name, filter.replace("\\n", "\n"));
+    let plan = plan_or_exit(&db, &query);
+
+    let ids = match exec::matched_ids(&db, &plan, &name.table, exec::ExecOptions::default()) {
+        Ok(ids) => ids,
+        Err(e) => {
+            writeln!(io::stderr(), "{:?}", e).expect("Failed to write error");
+            process::exit(1);
+        }
+    };
+
+    let removed = insert::delete_from_db(&mut db, file_path, &name, &ids)
+                      .expect("Failed to delete from db");
+    println!("deleted {:?} datums", removed);
+}
+
+/// Append a newer `value` of `column` for an existing id, string ids are
+/// looked up the same way as when the table was added
+fn update_query(file_path: &str, column: &str, raw_id: &str, value: &str, time: &str) {
+    let mut db = Db::from_file(file_path).expect("Failed to load db from file");
+    let name = match ColumnName::from_dotted(column) {
+        Ok(name) => name,
+        Err(e) => {
+            writeln!(io::stderr(), "{:?}", e).expect("Failed to write error");
+            process::exit(1);
+        }
+    };
+
+    let id = match db.id_maps.get(&name.table) {
+        Some(id_map) => id_map.internal(raw_id),
+        None => raw_id.parse::<usize>().ok(),
+    };
+    let (id, time) = match (id, time.parse::<usize>()) {
+        (Some(id), Ok(time)) => (id, time),
+        _ => {
+            writeln!(io::stderr(), "invalid id {} or time {}", raw_id, time)
+                .expect("Failed to write error");
+            process::exit(1);
+        }
+    };
 
+    if let Err(e) = insert::update_db(&mut db, file_path, &name, id, value.to_owned(), time) {
+        writeln!(io::stderr(), "{:?}", e).expect("Failed to write error");
+        process::exit(1);
+    }
+    println!("updated {} for id {}", name, raw_id);
+}
+
+/// Union the db at `other_path` into the one at `file_path`
+fn merge_dbs(file_path: &str, other_path: &str) {
+    let mut db = Db::from_file(file_path).expect("Failed to load db from file");
+    let other = Db::from_file(other_path).expect("Failed to load db from file");
+
+    if let Err(e) = insert::merge_into_db(&mut db, file_path, other) {
+        writeln!(io::stderr(), "{:?}", e).expect("Failed to write error");
+        process::exit(1);
+    }
+}
+
+/// Rewrite the db at another compression level, `raw` skips zlib entirely
+fn compress_db(file_path: &str, level: &str) {
+    let codec = match level {
+        "raw" => Codec::None,
+        "none" => Codec::Zlib(CompressionLevel::None),
+        "fast" => Codec::Zlib(CompressionLevel::Fast),
+        "default" => Codec::Zlib(CompressionLevel::Default),
+        "best" => Codec::Zlib(CompressionLevel::Best),
+        _ => {
+            writeln!(io::stderr(),
+                     "unknown compression level {}, expected raw, none, fast, default or best",
+                     level)
+                .expect("Failed to write error");
+            process::exit(1);
+        }
+    };
+
+    let db = Db::from_file(file_path).expect("Failed to load db from file");
+    db.write_with_codec(file_path, codec).expect("Failed to write db to disk");
+}
+
+/// Fold the segments appended to the db at `file_path` back into it
+fn compact_db(file_path: &str) {
+    let segments = data::segment_paths(file_path).len();
+    let db = Db::from_file(file_path).expect("Failed to load db from file");
+    db.write(file_path).expect("Failed to write db to disk");
+    println!("compacted {} segments", segments);
+}
+
+/// Columns of every table in the db at `file_path`, or only of `table`
+fn print_db_schema(file_path: &str, table: Option<&str>) {
+    // Loading a missing file gives an empty db, which isn't worth describing
+    if !Path::new(file_path).exists() {
+        writeln!(io::stderr(), "no db at {}", file_path).expect("Failed to write error");
+        process::exit(1);
+    }
     let db = Db::from_file(file_path).expect("Failed to load db from file");
-    let plan = Plan::from_str(&query).expect("Failed to parse query");
-    let result = exec::exec(&db, &plan).expect("Failed to exec query");
+    if !repl::print_columns(&db, table) {
+        writeln!(io::stderr(), "unknown table {}", table.unwrap_or(""))
+            .expect("Failed to write error");
+        process::exit(1);
+    }
+}
+
+/// Write `table` of the db at `file_path` to stdout as CSV
+fn export_table(file_path: &str, table: &str) {
+    let db = Db::from_file(file_path).expect("Failed to load db from file");
+    if db.table_columns(table).is_empty() {
+        writeln!(io::stderr(), "unknown table {}", table).expect("Failed to write error");
+        process::exit(1);
+    }
+    insert::export_table(&db, table, io::stdout()).expect("Failed to write CSV");
+}
+
+/// Parse `query` and check it against the Db at `file_path` without running it
+fn validate_query(file_path: &str, query: &str) -> Result<(), QueryError> {
+    let db = try!(Db::from_file(file_path).map_err(|e| {
+        QueryError::Io(format!("failed to load {}: {:?}", file_path, e))
+    }));
+    let plan = try!(checked_plan(&db, query, plan::DEFAULT_LIMIT));
+    // Type errors only show up when `query` runs the plan, so they exit the
+    // same way
+    try!(validate::validate_plan(&db, &plan).map_err(|e| QueryError::Exec(format!("{:?}", e))));
+    println!("query is valid");
+    Ok(())
+}
+
+/// What `query` prints besides its results, and how
+#[derive(Debug, Clone, Copy)]
+struct QueryOptions {
+    format: repl::Format,
+    /// Print the plan instead of running it
+    explain: bool,
+    /// Print the plan as JSON instead of running it
+    plan_json: bool,
+    /// Print each column as soon as its stage is done
+    stream: bool,
+    /// Write each finished stage to stderr
+    progress: bool,
+    /// Write how long each stage took to stderr
+    timings: bool,
+    /// Rows kept of columns the query doesn't limit
+    default_limit: usize,
+}
+
+fn exec_query(file_path: &str,
+              query: &str,
+              query_options: QueryOptions,
+              options: exec::ExecOptions)
+              -> Result<(), QueryError> {
+    let mut db = try!(Db::from_file(file_path).map_err(|e| {
+        QueryError::Io(format!("failed to load {}: {:?}", file_path, e))
+    }));
+    let plan = try!(checked_plan(&db, query, query_options.default_limit));
+
+    if query_options.explain {
+        println!("{}", plan.explain());
+        return Ok(());
+    }
+    if query_options.plan_json {
+        println!("{}", plan.to_json());
+        return Ok(());
+    }
+
+    let progress = |p: exec::Progress| {
+        if query_options.progress {
+            writeln!(io::stderr(),
+                     "stage {}/{} done, {} rows, {} scanned, {} skipped",
+                     p.stage + 1,
+                     p.stages,
+                     p.rows,
+                     p.scanned,
+                     p.skipped)
+                .expect("Failed to write progress");
+        }
+    };
+    if query_options.stream && plan.create_table.is_none() {
+        let print_chunk = |name: ColumnName, data: Data| {
+            match query_options.format {
+                repl::Format::Json => repl::print_json_lines(vec![(&name, &data)],
+                                                             repl::DISPLAY_LIMIT),
+                format => repl::print_results(vec![(&name, &data)], repl::DISPLAY_LIMIT, format),
+            }
+        };
+        return exec::exec_streaming_with_progress(&db, &plan, options, progress, print_chunk)
+                   .map_err(|e| QueryError::Exec(format!("{:?}", e)));
+    }
+
+    let (result, durations) = try!(exec::exec_timed(&db, &plan, options, progress)
+                                       .map_err(|e| QueryError::Exec(format!("{:?}", e))));
+    if query_options.timings {
+        for (i, duration) in durations.iter().enumerate() {
+            writeln!(io::stderr(),
+                     "stage {}/{} time: {:.4}",
+                     i + 1,
+                     durations.len(),
+                     repl::seconds(*duration))
+                .expect("Failed to write timings");
+        }
+    }
+
+    repl::print_results(result.iter()
+                              .map(|&(ref n, ref e)| (n, e))
+                              .collect(),
+                        repl::DISPLAY_LIMIT,
+                        query_options.format);
+
+    if let Some(ref table) = plan.create_table {
+        try!(insert::add_result_to_db(&mut db, file_path, table, result).map_err(|e| {
+            QueryError::Io(format!("failed to create table {}: {:?}", table, e))
+        }));
+    }
+    Ok(())
+}
+
+/// Runs every query in the file, separated by blank lines, on its own thread
+/// against one loaded `Db` and prints their results in the file's order
+fn exec_queries(file_path: &str, queries_path: &str) -> Result<(), QueryError> {
+    let db = Arc::new(try!(Db::from_file(file_path).map_err(|e| {
+        QueryError::Io(format!("failed to load {}: {:?}", file_path, e))
+    })));
+    let contents = try!(read_query(Some(queries_path), None));
+
+    let mut plans = vec![];
+    for query in contents.split("\n\n").map(|q| q.trim()).filter(|q| !q.is_empty()) {
+        let plan = try!(checked_plan(&db, query, plan::DEFAULT_LIMIT));
+        if plan.create_table.is_some() {
+            return Err(QueryError::Usage(format!("query-all can't create tables: {}",
+                                                 query.replace("\n", "\\n"))));
+        }
+        plans.push(plan);
+    }
+
+    let options = exec::ExecOptions::default();
+    let handles = plans.into_iter()
+                       .map(|plan| exec::exec_shared(db.clone(), plan, options))
+                       .collect::<Vec<_>>();
+    for (i, handle) in handles.into_iter().enumerate() {
+        let result = try!(try!(handle.join().map_err(|_| {
+                                   QueryError::Exec("query thread panicked".to_owned())
+                               }))
+                              .map_err(|e| QueryError::Exec(format!("{:?}", e))));
+        if i > 0 {
+            println!("");
+        }
+        repl::print_results(result.iter()
+                                  .map(|&(ref n, ref e)| (n, e))
+                                  .collect(),
+                            repl::DISPLAY_LIMIT,
+                            repl::Format::Table);
+    }
+    Ok(())
+}
 
-    repl::print_table(result.iter()
-                            .map(|&(ref n, ref e)| (n, e))
-                            .collect(),
-                      2000);
+/// Report what `add` did, a failed add exits with 1
+fn print_added(added: Result<(usize, usize), insert::Error>, options: insert::AddOptions) {
+    match added {
+        Ok((count, skipped)) => {
+            println!("added {:?} datums", count);
+            if options.skip_bad_rows {
+                println!("skipped {} bad rows", skipped);
+            }
+        }
+        Err(e) => {
+            writeln!(io::stderr(), "{}", e).expect("Failed to write error");
+            process::exit(1);
+        }
+    }
 }
 
 fn main() {
     let matches = App::new("twin-query")
                       .version("0.1")
                       .subcommand(SubCommand::with_name("repl")
-                                      .arg_from_usage("<FILE> 'Path to DB file'"))
+                                      .arg_from_usage("<FILE> 'Path to DB file'")
+                                      .arg_from_usage("--max-history=[N] 'History entries kept'")
+                                      .arg_from_usage("--default-limit=[N] 'Rows with no l line'"))
                       .subcommand(SubCommand::with_name("query")
                                       .arg_from_usage("<FILE> 'Path to DB file'")
-                                      .arg_from_usage("<QUERY> 'Full query string'"))
+                                      .arg_from_usage("[QUERY] 'Full query string'")
+                                      .arg_from_usage("--file=[PATH] 'Read the query from PATH'")
+                                      .arg_from_usage("-p --progress 'Print progress to stderr'")
+                                      .arg_from_usage("-e --explain 'Only print the plan'")
+                                      .arg_from_usage("-f --format=[FORMAT] 'table, csv or json'")
+                                      .arg_from_usage("-j --plan-json 'Print the plan as JSON'")
+                                      .arg_from_usage("-s --stream 'Print columns as stages end'")
+                                      .arg_from_usage("-t --threads=[N] 'Workers per stage'")
+                                      .arg_from_usage("--default-limit=[N] 'Rows with no l line'")
+                                      .arg_from_usage("--timings 'Print stage times to stderr'"))
+                      .subcommand(SubCommand::with_name("query-all")
+                                      .arg_from_usage("<FILE> 'Path to DB file'")
+                                      .arg_from_usage("<QUERIES> 'Path to queries file'"))
                       .subcommand(SubCommand::with_name("add")
                                       .arg_from_usage("<FILE> 'Path to DB file'")
+                                      .arg_from_usage("<SCHEMA> 'Schema file, or CSV with -i'")
+                                      .arg_from_usage("[DATA] 'Path to data, stored in CSV'")
+                                      .arg_from_usage("-i --infer 'Infer schema from CSV header'")
+                                      .arg_from_usage("-z --gzip 'CSVs are gzipped'")
+                                      .arg_from_usage("-b --batch=[ROWS] 'Spill runs every ROWS'")
+                                      .arg_from_usage("-k --skip-bad-rows 'Skip unparsable rows'")
+                                      .arg_from_usage("-d --delimiter=[CHAR] 'Cell separator'")
+                                      .arg_from_usage("-H --has-headers 'Skip the first row'")
+                                      .arg_from_usage("--check-headers 'Match header to schema'")
+                                      .arg_from_usage("-a --append 'Write a segment, no rewrite'"))
+                      .subcommand(SubCommand::with_name("add-json")
+                                      .arg_from_usage("<FILE> 'Path to DB file'")
+                                      .arg_from_usage("<SCHEMA> 'Path to schema file'")
+                                      .arg_from_usage("<DATA> 'Path to data, a JSON object a line'")
+                                      .arg_from_usage("-k --skip-bad-rows 'Skip unparsable rows'")
+                                      .arg_from_usage("-a --append 'Write a segment, no rewrite'"))
+                      .subcommand(SubCommand::with_name("delete")
+                                      .arg_from_usage("<FILE> 'Path to DB file'")
+                                      .arg_from_usage("<COLUMN> 'Column to delete from'")
+                                      .arg_from_usage("<FILTER> 'Where and join lines'"))
+                      .subcommand(SubCommand::with_name("update")
+                                      .arg_from_usage("<FILE> 'Path to DB file'")
+                                      .arg_from_usage("<COLUMN> 'Column to update'")
+                                      .arg_from_usage("<ID> 'Id of the row'")
+                                      .arg_from_usage("<VALUE> 'New value, as in a CSV'")
+                                      .arg_from_usage("<TIME> 'Time of the new value'"))
+                      .subcommand(SubCommand::with_name("merge")
+                                      .arg_from_usage("<FILE> 'Path to DB file'")
+                                      .arg_from_usage("<OTHER> 'Path to DB file to merge in'"))
+                      .subcommand(SubCommand::with_name("compress")
+                                      .arg_from_usage("<FILE> 'Path to DB file'")
+                                      .arg_from_usage("<LEVEL> 'raw, none, fast, default or best'"))
+                      .subcommand(SubCommand::with_name("compact")
+                                      .arg_from_usage("<FILE> 'Path to DB file'"))
+                      .subcommand(SubCommand::with_name("schema")
+                                      .arg_from_usage("<FILE> 'Path to DB file'")
+                                      .arg_from_usage("[TABLE] 'Only describe this table'"))
+                      .subcommand(SubCommand::with_name("export")
+                                      .arg_from_usage("<FILE> 'Path to DB file'")
+                                      .arg_from_usage("<TABLE> 'Table to write as CSV'"))
+                      .subcommand(SubCommand::with_name("complete")
+                                      .arg_from_usage("<FILE> 'Path to DB file'")
+                                      .arg_from_usage("[TOKEN] 'Partial keyword or name'"))
+                      .subcommand(SubCommand::with_name("validate")
+                                      .arg_from_usage("<FILE> 'Path to DB file'")
+                                      .arg_from_usage("[QUERY] 'Full query string'")
+                                      .arg_from_usage("--file=[PATH] 'Read the query from PATH'"))
+                      .subcommand(SubCommand::with_name("validate-queries")
                                       .arg_from_usage("<SCHEMA> 'Path to schema file'")
-                                      .arg_from_usage("<DATA> 'Path to data, stored in CSV'"))
+                                      .arg_from_usage("<QUERIES> 'Path to queries file'"))
                       .get_matches();
 
     if let Some(matches) = matches.subcommand_matches("repl") {
-        repl::start_repl(matches.value_of("FILE").unwrap());
+        let max_history = matches.value_of("max-history").map_or(repl::DEFAULT_MAX_HISTORY, |n| {
+            n.parse().expect("Max history must be a positive integer")
+        });
+        repl::start_repl(matches.value_of("FILE").unwrap(),
+                         max_history,
+                         default_limit(matches.value_of("default-limit")));
     }
 
     if let Some(matches) = matches.subcommand_matches("query") {
-        let vals: Vec<&str> = matches.values_of("QUERY").unwrap().collect();
-        exec_query(matches.value_of("FILE").unwrap(), &vals.join(","));
+        let format = match matches.value_of("format") {
+            Some("table") | None => repl::Format::Table,
+            Some("csv") => repl::Format::Csv,
+            Some("json") => repl::Format::Json,
+            Some(format) => {
+                writeln!(io::stderr(),
+                         "unknown format {}, expected table, csv or json",
+                         format)
+                    .expect("Failed to write error");
+                process::exit(1);
+            }
+        };
+        let query_options = QueryOptions {
+            format: format,
+            explain: matches.is_present("explain"),
+            plan_json: matches.is_present("plan-json"),
+            stream: matches.is_present("stream"),
+            progress: matches.is_present("progress"),
+            timings: matches.is_present("timings"),
+            default_limit: default_limit(matches.value_of("default-limit")),
+        };
+        let result = exec_options(matches.value_of("threads")).and_then(|options| {
+            let query = try!(read_query(matches.value_of("file"),
+                                        matches.values_of("QUERY").map(|vals| vals.collect())));
+            exec_query(matches.value_of("FILE").unwrap(), &query, query_options, options)
+        });
+        if let Err(e) = result {
+            writeln!(io::stderr(), "{}", e.message()).expect("Failed to write error");
+            process::exit(e.exit_code());
+        }
     }
 
     if let Some(matches) = matches.subcommand_matches("add") {
-        insert::add_to_db(matches.value_of("FILE").unwrap(),
-                          matches.value_of("SCHEMA").unwrap(),
-                          matches.value_of("DATA").unwrap());
+        let mut options = insert::AddOptions::default();
+        options.gzip = matches.is_present("gzip");
+        options.skip_bad_rows = matches.is_present("skip-bad-rows");
+        options.has_headers = matches.is_present("has-headers");
+        options.check_headers = matches.is_present("check-headers");
+        options.append = matches.is_present("append");
+        if let Some(delimiter) = matches.value_of("delimiter") {
+            options.delimiter = match delimiter {
+                "\\t" | "tab" => b'\t',
+                _ if delimiter.len() == 1 => delimiter.as_bytes()[0],
+                _ => {
+                    writeln!(io::stderr(), "delimiter has to be a single byte")
+                        .expect("Failed to write error");
+                    process::exit(1);
+                }
+            };
+        }
+        if let Some(batch) = matches.value_of("batch") {
+            options.batch_rows = Some(batch.parse().expect("Batch must be a positive integer"));
+        }
+
+        let added = if matches.is_present("infer") {
+            insert::add_inferred_to_db(matches.value_of("FILE").unwrap(),
+                                       matches.value_of("SCHEMA").unwrap(),
+                                       options)
+        } else {
+            insert::add_to_db(matches.value_of("FILE").unwrap(),
+                              matches.value_of("SCHEMA").unwrap(),
+                              matches.value_of("DATA"),
+                              options)
+        };
+        print_added(added, options);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("add-json") {
+        let mut options = insert::AddOptions::default();
+        options.json_lines = true;
+        options.skip_bad_rows = matches.is_present("skip-bad-rows");
+        options.append = matches.is_present("append");
+        let added = insert::add_to_db(matches.value_of("FILE").unwrap(),
+                                      matches.value_of("SCHEMA").unwrap(),
+                                      matches.value_of("DATA"),
+                                      options);
+        print_added(added, options);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("delete") {
+        delete_query(matches.value_of("FILE").unwrap(),
+                     matches.value_of("COLUMN").unwrap(),
+                     matches.value_of("FILTER").unwrap());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("update") {
+        update_query(matches.value_of("FILE").unwrap(),
+                     matches.value_of("COLUMN").unwrap(),
+                     matches.value_of("ID").unwrap(),
+                     matches.value_of("VALUE").unwrap(),
+                     matches.value_of("TIME").unwrap());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("merge") {
+        merge_dbs(matches.value_of("FILE").unwrap(),
+                  matches.value_of("OTHER").unwrap());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("compress") {
+        compress_db(matches.value_of("FILE").unwrap(),
+                    matches.value_of("LEVEL").unwrap());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("compact") {
+        compact_db(matches.value_of("FILE").unwrap());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("query-all") {
+        if let Err(e) = exec_queries(matches.value_of("FILE").unwrap(),
+                                     matches.value_of("QUERIES").unwrap()) {
+            writeln!(io::stderr(), "{}", e.message()).expect("Failed to write error");
+            process::exit(e.exit_code());
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("schema") {
+        print_db_schema(matches.value_of("FILE").unwrap(), matches.value_of("TABLE"));
+    }
+
+    if let Some(matches) = matches.subcommand_matches("export") {
+        export_table(matches.value_of("FILE").unwrap(), matches.value_of("TABLE").unwrap());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("complete") {
+        let db = Db::from_file(matches.value_of("FILE").unwrap())
+                     .expect("Failed to load db from file");
+        let completer = repl::Completer::new(&db);
+        for word in completer.complete(matches.value_of("TOKEN").unwrap_or("")) {
+            println!("{}", word);
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("validate") {
+        let result = read_query(matches.value_of("file"),
+                                matches.values_of("QUERY").map(|vals| vals.collect()))
+                         .and_then(|query| {
+                             validate_query(matches.value_of("FILE").unwrap(), &query)
+                         });
+        if let Err(e) = result {
+            writeln!(io::stderr(), "{}", e.message()).expect("Failed to write error");
+            process::exit(e.exit_code());
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("validate-queries") {
+        let results = validate::validate_queries(matches.value_of("SCHEMA").unwrap(),
+                                                 matches.value_of("QUERIES").unwrap())
+                          .unwrap_or_else(|e| {
+                              writeln!(io::stderr(), "{}", e).expect("Failed to write error");
+                              process::exit(1);
+                          });
+
+        let mut valid = true;
+        for (query, result) in results {
+            match result {
+                Ok(()) => println!("ok: {}", query.replace("\n", "\\n")),
+                Err(e) => {
+                    println!("error: {}: {:?}", query.replace("\n", "\\n"), e);
+                    valid = false;
+                }
+            }
+        }
+        if !valid {
+            process::exit(1);
+        }
     }
 }
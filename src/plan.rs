@@ -1,18 +1,23 @@
-use petgraph::{Dfs, EdgeDirection, Graph};
-use petgraph::dot::Dot;
+use petgraph::{algo, EdgeDirection, Graph};
 use petgraph::graph::NodeIndex;
-use std::cmp;
+use rustc_serialize::json;
+use std::char;
+use std::cmp::{self, Ordering};
 use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::iter;
 use std::str;
 
-use data::{ColumnName, Value};
+use data::{Column, ColumnName, Db, Value};
+use query;
 
-peg_file! grammar("grammar.rustpeg");
+/// Rows selected per column when a query has no `l` line
+pub const DEFAULT_LIMIT: usize = 20;
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, RustcEncodable)]
 pub enum Comparator {
     Equal,
+    NotEqual,
     Greater,
     GreaterOrEqual,
     Less,
@@ -20,22 +25,56 @@ pub enum Comparator {
 }
 
 impl Comparator {
+    /// Values that can't be ordered, i.e. a NaN float, never match
     fn test(&self, left: &Value, right: &Value) -> bool {
+        let ordering = match left.partial_cmp(right) {
+            Some(ordering) => ordering,
+            None => return false,
+        };
+
+        match *self {
+            Comparator::Equal => ordering == Ordering::Equal,
+            Comparator::NotEqual => ordering != Ordering::Equal,
+            Comparator::Greater => ordering == Ordering::Greater,
+            Comparator::GreaterOrEqual => ordering != Ordering::Less,
+            Comparator::Less => ordering == Ordering::Less,
+            Comparator::LessOrEqual => ordering != Ordering::Greater,
+        }
+    }
+}
+
+impl fmt::Display for Comparator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            Comparator::Equal => left == right,
-            Comparator::Greater => left > right,
-            Comparator::GreaterOrEqual => left >= right,
-            Comparator::Less => left < right,
-            Comparator::LessOrEqual => left <= right,
+            Comparator::Equal => write!(f, "="),
+            Comparator::NotEqual => write!(f, "!="),
+            Comparator::Greater => write!(f, ">"),
+            Comparator::GreaterOrEqual => write!(f, ">="),
+            Comparator::Less => write!(f, "<"),
+            Comparator::LessOrEqual => write!(f, "<="),
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, RustcEncodable)]
 pub enum Predicate {
     Constant(Comparator, Value),
+    /// Compares against the value of another column of the same table, the
+    /// one with the same id and time
+    Column(Comparator, ColumnName),
+    /// Compares an Int value's remainder after dividing by the modulus
+    Mod(usize, Comparator, Value),
     And(Box<Predicate>, Box<Predicate>),
     Or(Box<Predicate>, Box<Predicate>),
+    Not(Box<Predicate>),
+    In(Vec<Value>),
+    /// Case sensitive substring match, only valid against String columns
+    Like(String),
+    /// Strings starting with the prefix, from `like "prefix%"`, only valid
+    /// against String columns
+    Prefix(String),
+    /// Matches ids with no value in the column, e.g. from a blank CSV cell
+    IsNull,
 }
 
 impl Predicate {
@@ -54,82 +93,705 @@ impl Predicate {
                       Box::new(Self::or_from_vec(predicates)))
     }
 
+    pub fn and_from_vec(mut predicates: Vec<Predicate>) -> Predicate {
+        if predicates.len() == 1 {
+            return predicates.pop().unwrap();
+        }
+
+        let first = predicates.remove(0);
+        Predicate::And(Box::new(first), Box::new(Self::and_from_vec(predicates)))
+    }
+
+    /// Inclusive on both ends, `low <= value <= high`
+    pub fn between(low: Value, high: Value) -> Predicate {
+        Predicate::And(Box::new(Predicate::Constant(Comparator::GreaterOrEqual, low)),
+                       Box::new(Predicate::Constant(Comparator::LessOrEqual, high)))
+    }
+
+    /// The range `>= prefix and < successor(prefix)`, the strings starting
+    /// with `prefix`
+    pub fn from_prefix(prefix: String) -> Predicate {
+        let lower = Predicate::Constant(Comparator::GreaterOrEqual, Value::String(prefix.clone()));
+
+        match prefix_successor(&prefix) {
+            Some(upper) => {
+                Predicate::And(Box::new(lower),
+                               Box::new(Predicate::Constant(Comparator::Less, Value::String(upper))))
+            }
+            None => lower,
+        }
+    }
+
+    /// Rewrites every `Prefix` as the range from `from_prefix`, which a value
+    /// index answers without testing every string. Without one comparing both
+    /// bounds costs more than testing the prefix, so only indexed columns do.
+    pub fn with_prefix_ranges(&self) -> Predicate {
+        match *self {
+            Predicate::Prefix(ref prefix) => Predicate::from_prefix(prefix.to_owned()),
+            Predicate::And(ref left, ref right) => {
+                Predicate::And(Box::new(left.with_prefix_ranges()),
+                               Box::new(right.with_prefix_ranges()))
+            }
+            Predicate::Or(ref left, ref right) => {
+                Predicate::Or(Box::new(left.with_prefix_ranges()),
+                              Box::new(right.with_prefix_ranges()))
+            }
+            Predicate::Not(ref inner) => Predicate::Not(Box::new(inner.with_prefix_ranges())),
+            ref predicate => predicate.clone(),
+        }
+    }
+
+    /// Bounds `(min, max)` containing every value this predicate can match,
+    /// or `None` if it can't be narrowed to a single range
+    pub fn value_range(&self) -> Option<(Option<&Value>, Option<&Value>)> {
+        match *self {
+            Predicate::Constant(ref comp, ref value) => {
+                Some(match *comp {
+                    Comparator::Equal => (Some(value), Some(value)),
+                    Comparator::NotEqual => return None,
+                    Comparator::Greater | Comparator::GreaterOrEqual => (Some(value), None),
+                    Comparator::Less | Comparator::LessOrEqual => (None, Some(value)),
+                })
+            }
+            Predicate::And(ref left, ref right) => {
+                match (left.value_range(), right.value_range()) {
+                    (Some((left_min, left_max)), Some((right_min, right_max))) => {
+                        let min = match (left_min, right_min) {
+                            (Some(l), Some(r)) => Some(if l > r { l } else { r }),
+                            (l, r) => l.or(r),
+                        };
+                        let max = match (left_max, right_max) {
+                            (Some(l), Some(r)) => Some(if l < r { l } else { r }),
+                            (l, r) => l.or(r),
+                        };
+                        Some((min, max))
+                    }
+                    (Some(range), None) | (None, Some(range)) => Some(range),
+                    (None, None) => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Compact description for logs, chains of `And` over constants fold into
+    /// a single range like `in (5, 15]` and chains of `Or` equalities into a
+    /// set like `in {1, 2}`
+    pub fn summarize(&self) -> String {
+        if let Some((low, high)) = self.range_bounds() {
+            return match (low, high) {
+                (Some((l, true)), Some((h, true))) if l == h => format!("= {}", l),
+                (Some((l, l_inc)), Some((h, h_inc))) => {
+                    format!("in {}{}, {}{}",
+                            if l_inc { "[" } else { "(" },
+                            l,
+                            h,
+                            if h_inc { "]" } else { ")" })
+                }
+                (Some((l, true)), None) => format!(">= {}", l),
+                (Some((l, false)), None) => format!("> {}", l),
+                (None, Some((h, true))) => format!("<= {}", h),
+                (None, Some((h, false))) => format!("< {}", h),
+                (None, None) => "any".to_owned(),
+            };
+        }
+
+        if let Some(values) = self.equal_values() {
+            let values = values.iter().map(|v| format!("{}", v)).collect::<Vec<String>>();
+            return format!("in {{{}}}", values.join(", "));
+        }
+
+        match *self {
+            Predicate::And(ref left, ref right) => {
+                format!("({}) and ({})", left.summarize(), right.summarize())
+            }
+            Predicate::Or(ref left, ref right) => {
+                format!("({}) or ({})", left.summarize(), right.summarize())
+            }
+            Predicate::Not(ref inner) => format!("not ({})", inner.summarize()),
+            Predicate::Like(ref pattern) => format!("~ {:?}", pattern),
+            Predicate::Prefix(ref prefix) => format!("like {:?}", format!("{}%", prefix)),
+            Predicate::IsNull => "is null".to_owned(),
+            Predicate::Constant(ref comp, ref value) => format!("{} {}", comp, value),
+            Predicate::Column(ref comp, ref col_name) => format!("{} {}", comp, col_name),
+            Predicate::Mod(modulus, ref comp, ref value) => {
+                format!("% {} {} {}", modulus, comp, value)
+            }
+            Predicate::In(_) => unreachable!(),
+        }
+    }
+
+    /// Lower and upper bounds with their inclusiveness, for predicates made
+    /// only of constant comparisons joined by `And`
+    fn range_bounds(&self) -> Option<(Option<(&Value, bool)>, Option<(&Value, bool)>)> {
+        match *self {
+            Predicate::Constant(ref comp, ref value) => {
+                Some(match *comp {
+                    Comparator::Equal => (Some((value, true)), Some((value, true))),
+                    Comparator::NotEqual => return None,
+                    Comparator::Greater => (Some((value, false)), None),
+                    Comparator::GreaterOrEqual => (Some((value, true)), None),
+                    Comparator::Less => (None, Some((value, false))),
+                    Comparator::LessOrEqual => (None, Some((value, true))),
+                })
+            }
+            Predicate::And(ref left, ref right) => {
+                let (left_low, left_high) = match left.range_bounds() {
+                    Some(bounds) => bounds,
+                    None => return None,
+                };
+                let (right_low, right_high) = match right.range_bounds() {
+                    Some(bounds) => bounds,
+                    None => return None,
+                };
+
+                let low = match (left_low, right_low) {
+                    (Some(l), Some(r)) => {
+                        Some(if l.0 > r.0 || (l.0 == r.0 && !l.1) { l } else { r })
+                    }
+                    (l, r) => l.or(r),
+                };
+                let high = match (left_high, right_high) {
+                    (Some(l), Some(r)) => {
+                        Some(if l.0 < r.0 || (l.0 == r.0 && !l.1) { l } else { r })
+                    }
+                    (l, r) => l.or(r),
+                };
+                Some((low, high))
+            }
+            _ => None,
+        }
+    }
+
+    /// Values matched by a predicate made only of `=` comparisons joined by
+    /// `Or`, or an `In` list
+    pub fn equal_values(&self) -> Option<Vec<&Value>> {
+        match *self {
+            Predicate::Constant(Comparator::Equal, ref value) => Some(vec![value]),
+            Predicate::In(ref values) => Some(values.iter().collect()),
+            Predicate::Or(ref left, ref right) => {
+                match (left.equal_values(), right.equal_values()) {
+                    (Some(mut l), Some(r)) => {
+                        l.extend(r);
+                        Some(l)
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Comparisons against other columns never match, see `test_row`
     pub fn test(&self, value: &Value) -> bool {
+        self.test_row(value, &|_| None)
+    }
+
+    /// Like `test`, with `row` looking up the value of a compared column in
+    /// the same row, comparisons against a missing value don't match
+    pub fn test_row<'a>(&self,
+                        value: &Value,
+                        row: &Fn(&ColumnName) -> Option<&'a Value>)
+                        -> bool {
         #![allow(unconditional_recursion)]
         match *self {
             Predicate::Constant(ref comp, ref right) => comp.test(value, right),
-            Predicate::And(ref left, ref right) => left.test(value) && right.test(value),
-            Predicate::Or(ref left, ref right) => left.test(value) || right.test(value),
+            Predicate::Column(ref comp, ref col_name) => {
+                row(col_name).map_or(false, |right| comp.test(value, right))
+            }
+            Predicate::Mod(modulus, ref comp, ref right) => {
+                match *value {
+                    Value::Int(i) => comp.test(&Value::Int(i % modulus as i64), right),
+                    _ => false,
+                }
+            }
+            Predicate::And(ref left, ref right) => {
+                left.test_row(value, row) && right.test_row(value, row)
+            }
+            Predicate::Or(ref left, ref right) => {
+                left.test_row(value, row) || right.test_row(value, row)
+            }
+            Predicate::Not(ref inner) => !inner.test_row(value, row),
+            Predicate::In(ref values) => values.contains(value),
+            Predicate::Like(ref pattern) => {
+                match *value {
+                    Value::String(ref s) => s.contains(pattern.as_str()),
+                    _ => false,
+                }
+            }
+            Predicate::Prefix(ref prefix) => {
+                match *value {
+                    Value::String(ref s) => s.starts_with(prefix.as_str()),
+                    _ => false,
+                }
+            }
+            Predicate::IsNull => false,
+        }
+    }
+
+    /// Result of testing a missing value with three-valued logic, `None`
+    /// being unknown. Comparisons against null are unknown, so only
+    /// predicates that are true for a null regardless of them match it.
+    pub fn test_null(&self) -> Option<bool> {
+        match *self {
+            Predicate::IsNull => Some(true),
+            Predicate::Not(ref inner) => inner.test_null().map(|b| !b),
+            Predicate::And(ref left, ref right) => {
+                match (left.test_null(), right.test_null()) {
+                    (Some(false), _) | (_, Some(false)) => Some(false),
+                    (Some(true), Some(true)) => Some(true),
+                    _ => None,
+                }
+            }
+            Predicate::Or(ref left, ref right) => {
+                match (left.test_null(), right.test_null()) {
+                    (Some(true), _) | (_, Some(true)) => Some(true),
+                    (Some(false), Some(false)) => Some(false),
+                    _ => None,
+                }
+            }
+            Predicate::Constant(_, _) |
+            Predicate::Column(_, _) |
+            Predicate::Mod(_, _, _) |
+            Predicate::In(_) |
+            Predicate::Like(_) |
+            Predicate::Prefix(_) => None,
+        }
+    }
+
+    /// Every constant value compared against, `Like` patterns are not values
+    pub fn values(&self) -> Vec<&Value> {
+        match *self {
+            Predicate::Constant(_, ref value) |
+            Predicate::Mod(_, _, ref value) => vec![value],
+            Predicate::And(ref left, ref right) |
+            Predicate::Or(ref left, ref right) => {
+                let mut values = left.values();
+                values.extend(right.values());
+                values
+            }
+            Predicate::Not(ref inner) => inner.values(),
+            Predicate::In(ref values) => values.iter().collect(),
+            Predicate::Column(_, _) |
+            Predicate::Like(_) |
+            Predicate::Prefix(_) |
+            Predicate::IsNull => vec![],
+        }
+    }
+
+    pub fn has_like(&self) -> bool {
+        match *self {
+            Predicate::Like(_) |
+            Predicate::Prefix(_) => true,
+            Predicate::And(ref left, ref right) |
+            Predicate::Or(ref left, ref right) => left.has_like() || right.has_like(),
+            Predicate::Not(ref inner) => inner.has_like(),
+            Predicate::Constant(_, _) |
+            Predicate::Column(_, _) |
+            Predicate::Mod(_, _, _) |
+            Predicate::In(_) |
+            Predicate::IsNull => false,
+        }
+    }
+
+    /// Rough estimate of how many values a predicate lets through, lower is
+    /// more selective: equality, then ranges, then everything else
+    pub fn selectivity(&self) -> usize {
+        match *self {
+            Predicate::Constant(Comparator::Equal, _) |
+            Predicate::In(_) |
+            Predicate::IsNull => 1,
+            Predicate::Constant(Comparator::NotEqual, _) => 3,
+            Predicate::Constant(_, _) |
+            Predicate::Prefix(_) => 2,
+            Predicate::And(ref left, ref right) => {
+                cmp::min(left.selectivity(), right.selectivity())
+            }
+            Predicate::Or(ref left, ref right) => {
+                cmp::max(left.selectivity(), right.selectivity())
+            }
+            Predicate::Column(_, _) |
+            Predicate::Mod(_, _, _) |
+            Predicate::Not(_) |
+            Predicate::Like(_) => 3,
+        }
+    }
+
+    pub fn has_mod(&self) -> bool {
+        match *self {
+            Predicate::Mod(_, _, _) => true,
+            Predicate::And(ref left, ref right) |
+            Predicate::Or(ref left, ref right) => left.has_mod() || right.has_mod(),
+            Predicate::Not(ref inner) => inner.has_mod(),
+            Predicate::Constant(_, _) |
+            Predicate::Column(_, _) |
+            Predicate::In(_) |
+            Predicate::Like(_) |
+            Predicate::Prefix(_) |
+            Predicate::IsNull => false,
+        }
+    }
+
+    /// Every other column compared against
+    pub fn compared_columns(&self) -> Vec<&ColumnName> {
+        match *self {
+            Predicate::Column(_, ref col_name) => vec![col_name],
+            Predicate::And(ref left, ref right) |
+            Predicate::Or(ref left, ref right) => {
+                let mut columns = left.compared_columns();
+                columns.extend(right.compared_columns());
+                columns
+            }
+            Predicate::Not(ref inner) => inner.compared_columns(),
+            Predicate::Constant(_, _) |
+            Predicate::Mod(_, _, _) |
+            Predicate::In(_) |
+            Predicate::Like(_) |
+            Predicate::Prefix(_) |
+            Predicate::IsNull => vec![],
         }
     }
 }
 
-#[derive(Debug)]
+/// Smallest string greater than every string starting with `prefix`
+fn prefix_successor(prefix: &str) -> Option<String> {
+    let mut chars = prefix.chars().collect::<Vec<char>>();
+
+    while let Some(last) = chars.pop() {
+        let next = match last as u32 {
+            0xD7FF => 0xE000,
+            code => code + 1,
+        };
+
+        if let Some(c) = char::from_u32(next) {
+            chars.push(c);
+            return Some(chars.into_iter().collect());
+        }
+    }
+
+    None
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, RustcEncodable)]
+pub enum Direction {
+    Asc,
+    Desc,
+}
+
+/// How the where lines of a table combine, `All` keeps the ids every line
+/// matched and `Any` the ids matched by at least one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, RustcEncodable)]
+pub enum Matching {
+    All,
+    Any,
+}
+
+/// Inner joins keep only the rows with a match, left joins keep every row
+/// of the joining table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, RustcEncodable)]
+pub enum JoinKind {
+    Inner,
+    Left,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, RustcEncodable)]
+pub enum Aggregate {
+    Count,
+    Sum,
+}
+
+impl fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Aggregate::Count => write!(f, "count"),
+            Aggregate::Sum => write!(f, "sum"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Selection {
+    Column(ColumnName),
+    TimeCount(ColumnName),
+    /// Every column of a table, `Plan::parse_against` expands it with the
+    /// columns of a Db
+    All(String),
+    /// A column renamed in the results
+    Alias(ColumnName, String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum QueryLine {
-    Select(Vec<ColumnName>),
-    Join(String, ColumnName),
+    /// Selections, distinct, latest
+    Select(Vec<Selection>, bool, bool),
+    Join(ColumnName, ColumnName, JoinKind),
     Where(ColumnName, Predicate),
     Limit(usize),
+    Offset(usize),
+    OrderBy(ColumnName, Direction),
+    GroupBy(ColumnName),
+    Aggregate(Aggregate, ColumnName),
+    Cohort(ColumnName, usize, ColumnName, usize),
+    CreateTable(String),
+    Match(Matching),
+    Having(Aggregate, Predicate),
+    AsOf(i64),
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, RustcEncodable)]
 /// Open-closed interval
 /// min < time <= max
+/// With `latest` only the newest datum of each id in the interval is tested
 pub struct TimeBound {
-    min: Option<usize>,
-    max: Option<usize>,
+    min: Option<i64>,
+    max: Option<i64>,
+    latest: bool,
 }
 
 impl TimeBound {
-    fn from_predicate(predicate: &Predicate) -> Self {
+    /// Bound containing every time `predicate` matches, and whether it
+    /// matches exactly those times so the predicate itself can be dropped
+    fn from_predicate(predicate: &Predicate) -> (Self, bool) {
         match *predicate {
             Predicate::Constant(ref comp, ref value) => {
                 let int_val = match *value {
-                    Value::Int(i) => i,
-                    _ => panic!("TimeBounds must be built with int predicates"),
+                    Value::Int(i) | Value::Timestamp(i) => i,
+                    _ => return (Self::unbounded(), false),
                 };
 
+                // Signed so `>= 0` becomes `-1 < time` instead of underflowing
                 let (min, max) = match *comp {
-                    Comparator::Equal => (Some(int_val - 1), Some(int_val)),
+                    Comparator::Equal => (Some(int_val.saturating_sub(1)), Some(int_val)),
+                    Comparator::NotEqual => return (Self::unbounded(), false),
                     Comparator::Greater => (Some(int_val), None),
-                    Comparator::GreaterOrEqual => (Some(int_val - 1), None),
-                    Comparator::Less => (None, Some(int_val - 1)),
+                    Comparator::GreaterOrEqual => (Some(int_val.saturating_sub(1)), None),
+                    Comparator::Less => (None, Some(int_val.saturating_sub(1))),
                     Comparator::LessOrEqual => (None, Some(int_val)),
                 };
 
-                TimeBound {
+                (TimeBound {
                     min: min,
                     max: max,
-                }
+                    latest: false,
+                },
+                 true)
             }
             Predicate::And(ref left, ref right) => {
-                Self::from_predicate(left).combine(&Self::from_predicate(right))
+                let (left, left_exact) = Self::from_predicate(left);
+                let (right, right_exact) = Self::from_predicate(right);
+                (left.combine(&right), left_exact && right_exact)
             }
-            Predicate::Or(_, _) => unimplemented!(),
+            Predicate::Or(ref left, ref right) => {
+                let (left, left_exact) = Self::from_predicate(left);
+                let (right, right_exact) = Self::from_predicate(right);
+                let exact = left_exact && right_exact && left.union_is_exact(&right);
+                (left.union(&right), exact)
+            }
+            Predicate::Not(_) |
+            Predicate::Column(_, _) |
+            Predicate::Mod(_, _, _) |
+            Predicate::In(_) |
+            Predicate::Like(_) |
+            Predicate::Prefix(_) |
+            Predicate::IsNull => (Self::unbounded(), false),
+        }
+    }
+
+    fn unbounded() -> Self {
+        TimeBound {
+            min: None,
+            max: None,
+            latest: false,
         }
     }
 
+    pub fn min(&self) -> Option<i64> {
+        self.min
+    }
+
+    pub fn max(&self) -> Option<i64> {
+        self.max
+    }
+
+    pub fn latest(&self) -> bool {
+        self.latest
+    }
+
+    /// No time is both after min and at or before max
+    pub fn is_empty(&self) -> bool {
+        match (self.min, self.max) {
+            (Some(min), Some(max)) => min >= max,
+            _ => false,
+        }
+    }
+
+    /// Bounds both hold in, the later min and earlier max when both sides
+    /// have one, which leaves no times when they don't overlap
     fn combine(&self, bound: &TimeBound) -> TimeBound {
+        let tighter = |left: Option<i64>, right: Option<i64>, pick: fn(i64, i64) -> i64| {
+            match (left, right) {
+                (Some(left), Some(right)) => Some(pick(left, right)),
+                _ => left.or(right),
+            }
+        };
+
+        TimeBound {
+            min: tighter(self.min, bound.min, cmp::max),
+            max: tighter(self.max, bound.max, cmp::min),
+            latest: self.latest || bound.latest,
+        }
+    }
+
+    /// Bound no later than `time`, testing each id's newest datum in it
+    fn as_of(bound: Option<&TimeBound>, time: i64) -> TimeBound {
+        let min = bound.and_then(|bound| bound.min);
+        let max = bound.and_then(|bound| bound.max).map_or(time, |max| cmp::min(max, time));
         TimeBound {
-            min: self.min.or(bound.min),
-            max: self.max.or(bound.max),
+            min: min,
+            max: Some(max),
+            latest: true,
+        }
+    }
+
+    /// Bounds containing both, unbounded on a side either is unbounded on. An
+    /// empty bound contains no times so only the other one is kept.
+    fn union(&self, bound: &TimeBound) -> TimeBound {
+        if self.is_empty() {
+            return bound.clone();
+        }
+        if bound.is_empty() {
+            return self.clone();
+        }
+
+        TimeBound {
+            min: self.min.and_then(|min| bound.min.map(|other| cmp::min(min, other))),
+            max: self.max.and_then(|max| bound.max.map(|other| cmp::max(max, other))),
+            latest: self.latest || bound.latest,
+        }
+    }
+
+    /// Whether `union` contains only times in either bound, i.e. one is empty
+    /// or they overlap or touch, leaving no gap between them
+    fn union_is_exact(&self, bound: &TimeBound) -> bool {
+        if self.is_empty() || bound.is_empty() {
+            return true;
+        }
+
+        let later_min = match (self.min, bound.min) {
+            (Some(left), Some(right)) => Some(cmp::max(left, right)),
+            (min, None) | (None, min) => min,
+        };
+        let earlier_max = match (self.max, bound.max) {
+            (Some(left), Some(right)) => Some(cmp::min(left, right)),
+            (max, None) | (None, max) => max,
+        };
+
+        match (later_min, earlier_max) {
+            (Some(min), Some(max)) => min <= max,
+            _ => true,
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+impl fmt::Display for TimeBound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.min, self.max) {
+            (Some(min), Some(max)) => try!(write!(f, "{} < time <= {}", min, max)),
+            (Some(min), None) => try!(write!(f, "{} < time", min)),
+            (None, Some(max)) => try!(write!(f, "time <= {}", max)),
+            (None, None) => try!(write!(f, "any time")),
+        }
+        if self.latest {
+            try!(write!(f, ", newest per id"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, RustcEncodable)]
 pub enum PlanNode {
-    Select(ColumnName, usize),
-    Join(ColumnName, ColumnName),
+    Select(ColumnName, usize, usize, bool),
+    /// Like `Select` but only the newest datum of each id, at or before the
+    /// max time bound of the table if the query has one
+    Latest(ColumnName, usize, usize, bool, Option<i64>),
+    TimeCount(ColumnName, usize),
+    /// Key, aggregate of a column, limit and the `having` predicate groups'
+    /// aggregates have to match
+    Group(ColumnName, Aggregate, ColumnName, usize, Option<Predicate>),
+    /// Signup column and bucket size, activity column and window size, limit
+    Cohort(ColumnName, usize, ColumnName, usize, usize),
+    Join(ColumnName, ColumnName, JoinKind),
     Where(ColumnName, Predicate, Option<TimeBound>),
     WhereId(ColumnName, Vec<usize>),
 }
 
 impl PlanNode {
+    /// Every column this node reads
+    fn columns(&self) -> Vec<&ColumnName> {
+        match *self {
+            PlanNode::Select(ref col_name, _, _, _) |
+            PlanNode::Latest(ref col_name, _, _, _, _) |
+            PlanNode::TimeCount(ref col_name, _) |
+            PlanNode::WhereId(ref col_name, _) => vec![col_name],
+            PlanNode::Where(ref col_name, ref predicate, _) => {
+                let mut columns = vec![col_name];
+                columns.extend(predicate.compared_columns());
+                columns
+            }
+            PlanNode::Group(ref key, _, ref col_name, _, _) => vec![key, col_name],
+            PlanNode::Cohort(ref signup, _, ref activity, _, _) => vec![signup, activity],
+            PlanNode::Join(ref left, ref right, _) => vec![left, right],
+        }
+    }
+
+    /// Where nodes that should match the fewest ids come first, id lookups
+    /// before any scan, and every other node after them
+    fn cost(&self) -> usize {
+        match *self {
+            PlanNode::WhereId(_, _) => 0,
+            PlanNode::Where(_, ref predicate, _) => predicate.selectivity(),
+            _ => 4,
+        }
+    }
+
+    /// Id columns that have to be filtered before this node can run
+    pub fn requires(&self) -> Vec<ColumnName> {
+        match *self {
+            PlanNode::Select(ref col_name, _, _, _) |
+            PlanNode::Latest(ref col_name, _, _, _, _) |
+            PlanNode::TimeCount(ref col_name, _) |
+            PlanNode::Group(ref col_name, _, _, _, _) => vec![col_name.id()],
+            PlanNode::Cohort(ref signup, _, ref activity, _, _) => {
+                vec![signup.id(), activity.id()]
+            }
+            PlanNode::Join(ref left, _, _) => vec![left.id()],
+            PlanNode::Where(_, _, _) |
+            PlanNode::WhereId(_, _) => vec![],
+        }
+    }
+
+    /// Id column this node filters
+    fn provides(&self) -> Option<ColumnName> {
+        match *self {
+            PlanNode::Join(_, ref right, _) => Some(right.id()),
+            PlanNode::Where(ref col_name, _, _) |
+            PlanNode::WhereId(ref col_name, _) => Some(col_name.id()),
+            PlanNode::Select(_, _, _, _) |
+            PlanNode::Latest(_, _, _, _, _) |
+            PlanNode::TimeCount(_, _) |
+            PlanNode::Group(_, _, _, _, _) |
+            PlanNode::Cohort(_, _, _, _, _) => None,
+        }
+    }
+
     fn table(&self) -> &str {
         match *self {
-            PlanNode::Select(ref col_name, _) |
-            PlanNode::Join(ref col_name, _) |
+            PlanNode::Select(ref col_name, _, _, _) |
+            PlanNode::Latest(ref col_name, _, _, _, _) |
+            PlanNode::TimeCount(ref col_name, _) |
+            PlanNode::Group(ref col_name, _, _, _, _) |
+            PlanNode::Cohort(ref col_name, _, _, _, _) |
+            PlanNode::Join(ref col_name, _, _) |
             PlanNode::Where(ref col_name, _, _) |
             PlanNode::WhereId(ref col_name, _) => &col_name.table,
         }
@@ -139,8 +801,51 @@ impl PlanNode {
 impl fmt::Display for PlanNode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            PlanNode::Select(ref col_name, limit) => write!(f, "Select({}, {})", col_name, limit),
-            PlanNode::Join(ref left, ref right) => write!(f, "Join({}, {})", left, right),
+            PlanNode::Select(ref col_name, limit, offset, distinct) => {
+                write!(f,
+                       "Select({}{}, {}, {})",
+                       if distinct { "distinct " } else { "" },
+                       col_name,
+                       limit,
+                       offset)
+            }
+            PlanNode::Latest(ref col_name, limit, offset, distinct, max_time) => {
+                try!(write!(f,
+                            "Latest({}{}, {}, {}",
+                            if distinct { "distinct " } else { "" },
+                            col_name,
+                            limit,
+                            offset));
+                match max_time {
+                    Some(max_time) => write!(f, ", time <= {})", max_time),
+                    None => write!(f, ")"),
+                }
+            }
+            PlanNode::TimeCount(ref col_name, limit) => {
+                write!(f, "TimeCount({}, {})", col_name, limit)
+            }
+            PlanNode::Group(ref key, aggregate, ref col_name, limit, ref having) => {
+                try!(write!(f, "Group({}, {}({}), {}", key, aggregate, col_name, limit));
+                match *having {
+                    Some(ref predicate) => write!(f, ", having {:?})", predicate),
+                    None => write!(f, ")"),
+                }
+            }
+            PlanNode::Cohort(ref signup, bucket, ref activity, window, limit) => {
+                write!(f,
+                       "Cohort({} by {}, {} by {}, {})",
+                       signup,
+                       bucket,
+                       activity,
+                       window,
+                       limit)
+            }
+            PlanNode::Join(ref left, ref right, JoinKind::Inner) => {
+                write!(f, "Join({}, {})", left, right)
+            }
+            PlanNode::Join(ref left, ref right, JoinKind::Left) => {
+                write!(f, "LeftJoin({}, {})", left, right)
+            }
             PlanNode::Where(ref col_name, ref pred, ref time_bound) => {
                 write!(f, "Where({}, {:?}, {:?})", col_name, pred, time_bound)
             }
@@ -151,12 +856,15 @@ impl fmt::Display for PlanNode {
     }
 }
 
-type Requires = Option<ColumnName>;
-type Provides = Option<ColumnName>;
 
+/// Ids matched by a predicate on an id column, negative ids can't match
+/// anything so they're left out, possibly leaving no ids at all
 fn extract_ids(predicate: &Predicate) -> Option<Vec<usize>> {
     match *predicate {
-        Predicate::Constant(Comparator::Equal, Value::Int(val)) => Some(vec![val]),
+        Predicate::Constant(Comparator::Equal, Value::Int(val)) if val >= 0 => {
+            Some(vec![val as usize])
+        }
+        Predicate::Constant(Comparator::Equal, Value::Int(_)) => Some(vec![]),
         Predicate::Or(ref left, ref right) => {
             match (extract_ids(&left), extract_ids(&right)) {
                 (Some(mut left_ids), Some(mut right_ids)) => {
@@ -166,19 +874,46 @@ fn extract_ids(predicate: &Predicate) -> Option<Vec<usize>> {
                 _ => None,
             }
         }
+        Predicate::In(ref values) => {
+            let mut ids = vec![];
+            for value in values {
+                match *value {
+                    Value::Int(id) if id >= 0 => ids.push(id as usize),
+                    Value::Int(_) => (),
+                    _ => return None,
+                }
+            }
+            Some(ids)
+        }
+        // Not equal and ranges match ids that can't be listed
         _ => None,
     }
 }
 
-fn parse_line(line: QueryLine, limit: usize) -> Vec<(PlanNode, Requires, Provides)> {
+fn parse_line(line: QueryLine,
+              limit: usize,
+              offset: usize,
+              group: Option<&ColumnName>,
+              having: Option<&Predicate>)
+              -> Vec<PlanNode> {
     match line {
-        QueryLine::Select(cols) => {
-            cols.into_iter()
-                .map(|col| {
-                    let col_id = col.id();
-                    (PlanNode::Select(col, limit), Some(col_id), None)
-                })
-                .collect()
+        QueryLine::Select(selections, distinct, latest) => {
+            selections.into_iter()
+                      .filter_map(|selection| {
+                          match selection {
+                              Selection::Column(col) |
+                              Selection::Alias(col, _) if latest => {
+                                  Some(PlanNode::Latest(col, limit, offset, distinct, None))
+                              }
+                              Selection::Column(col) |
+                              Selection::Alias(col, _) => {
+                                  Some(PlanNode::Select(col, limit, offset, distinct))
+                              }
+                              Selection::TimeCount(col) => Some(PlanNode::TimeCount(col, limit)),
+                              Selection::All(_) => None,
+                          }
+                      })
+                      .collect()
         }
         QueryLine::Where(left, pred) => {
             let left_id = left.id();
@@ -191,16 +926,28 @@ fn parse_line(line: QueryLine, limit: usize) -> Vec<(PlanNode, Requires, Provide
                 PlanNode::Where(left, pred, None)
             };
 
-            vec![(node, None, Some(left_id))]
+            vec![node]
         }
-        QueryLine::Join(left, right) => {
-            let left_id = ColumnName::new(left, "id".to_owned());
-            let right_id = right.id();
-            vec![(PlanNode::Join(left_id.clone(), right),
-                  Some(left_id),
-                  Some(right_id))]
+        QueryLine::Join(left, right, kind) => vec![PlanNode::Join(left, right, kind)],
+        QueryLine::Aggregate(aggregate, col) => {
+            match group {
+                Some(key) => {
+                    vec![PlanNode::Group(key.to_owned(), aggregate, col, limit, having.cloned())]
+                }
+                None => vec![],
+            }
         }
-        QueryLine::Limit(_) => vec![],
+        QueryLine::Cohort(signup, bucket, activity, window) => {
+            vec![PlanNode::Cohort(signup, bucket, activity, window, limit)]
+        }
+        QueryLine::Limit(_) |
+        QueryLine::Offset(_) |
+        QueryLine::CreateTable(_) |
+        QueryLine::OrderBy(_, _) |
+        QueryLine::GroupBy(_) |
+        QueryLine::Having(_, _) |
+        QueryLine::AsOf(_) |
+        QueryLine::Match(_) => vec![],
     }
 }
 
@@ -226,6 +973,15 @@ impl Stage {
         self.nodes.len()
     }
 
+    /// Nodes in the order they run, the most selective where nodes first so later ones of their
+    /// table only test the ids they matched, ties are ordered by how they print so identical
+    /// queries display, combine and run their nodes the same way
+    pub fn sorted_nodes(&self) -> Vec<&PlanNode> {
+        let mut nodes = self.nodes.iter().collect::<Vec<&PlanNode>>();
+        nodes.sort_by_key(|node| (node.cost(), format!("{}", node)));
+        nodes
+    }
+
     fn insert(&mut self, node: PlanNode) {
         self.nodes.insert(node);
     }
@@ -242,25 +998,29 @@ impl Stage {
 
     fn group_where_nodes_by_column(&self) -> Vec<Vec<&PlanNode>> {
         let mut map = HashMap::new();
+        let mut columns = vec![];
 
-        for node in &self.nodes {
+        for node in self.sorted_nodes() {
             match *node {
                 PlanNode::Where(ref col_name, _, _) => {
-                    let mut nodes = map.entry(col_name).or_insert_with(Vec::new);
+                    let mut nodes = map.entry(col_name).or_insert_with(|| {
+                        columns.push(col_name);
+                        Vec::new()
+                    });
                     nodes.push(node)
                 }
                 _ => continue,
             }
         }
 
-        map.into_iter()
-           .map(|(_, v)| v)
-           .collect()
+        columns.into_iter()
+               .filter_map(|col_name| map.remove(col_name))
+               .collect()
     }
 
     fn find_where_time_nodes(&self) -> Vec<&PlanNode> {
-        self.nodes
-            .iter()
+        self.sorted_nodes()
+            .into_iter()
             .filter(|&node| {
                 match *node {
                     PlanNode::Where(ref col_name, _, _) => &col_name.column == "time",
@@ -286,28 +1046,236 @@ impl Default for Stage {
 
 #[derive(Debug)]
 pub enum Error {
-    ParseError(grammar::ParseError),
+    /// Line and column of the bad token, both starting at 1, and the text of
+    /// the line it's on
+    ParseError(usize, usize, String),
     NoStages,
     EmptyStages,
     InvalidStageOrder,
     EmptyNodeInStages,
+    InvalidGroup,
+    /// A `having` for another aggregate than the query's, or comparing it to
+    /// something other than ints
+    InvalidHaving,
+    /// A `l 0`, which would never select anything
+    ZeroLimit,
+    /// Two `l` lines with different sizes
+    ConflictingLimits(usize, usize),
+    /// Both a `match all` and a `match any` line
+    ConflictingMatching,
+    /// Two `as of` lines with different times
+    ConflictingAsOf(i64, i64),
+    /// A where line compares against a column of another table
+    InvalidComparison(ColumnName),
+    /// A where line on a time column compares it to something other than
+    /// ints or timestamps
+    InvalidTimeValue(ColumnName, Value),
+    CyclicPlan,
+    UnknownTable(String),
+    UnknownColumn(ColumnName),
+    /// Joined columns of different types, left and right
+    InvalidJoin(ColumnName, ColumnName),
+    /// A where line compares a column to a value of another type
+    InvalidValue(ColumnName, Value),
+    /// A `<table>.*` in a query that wasn't parsed against a Db
+    UnexpandedWildcard(String),
+    /// Two selections renamed to the same alias
+    DuplicateAlias(String),
+    /// A column selected under more than one name
+    ConflictingAlias(ColumnName),
+}
+
+/// Borrowed view of a plan for `Plan::to_json`, the nodes of each stage are
+/// sorted so the output doesn't depend on hash order
+#[derive(RustcEncodable)]
+struct EncodablePlan<'a> {
+    stages: Vec<Vec<&'a PlanNode>>,
+    order: &'a Option<(ColumnName, Direction)>,
+    create_table: &'a Option<String>,
 }
 
 #[derive(Debug)]
 pub struct Plan {
     pub stages: Vec<Stage>,
+    pub order: Option<(ColumnName, Direction)>,
+    /// Table to store the results in
+    pub create_table: Option<String>,
+    /// Selected columns renamed in the results
+    pub aliases: HashMap<ColumnName, ColumnName>,
+    pub matching: Matching,
+    /// Time the query sees the Db as of
+    pub as_of: Option<i64>,
 }
 
 impl Plan {
-    pub fn new(lines: Vec<QueryLine>) -> Plan {
-        let graph = Self::build_graph(lines);
-        let stages = Self::build_stages(&graph);
+    pub fn builder() -> PlanBuilder {
+        PlanBuilder::default()
+    }
 
-        println!("{}", Dot::new(&graph));
+    /// Plans `lines` as they are, failing only when their joins form a cycle
+    pub fn new(lines: Vec<QueryLine>, default_limit: usize) -> Result<Plan, Error> {
+        let order = lines.iter().fold(None, |acc, line| {
+            match *line {
+                QueryLine::OrderBy(ref name, direction) => Some((name.to_owned(), direction)),
+                _ => acc,
+            }
+        });
+        let create_table = lines.iter().fold(None, |acc, line| {
+            match *line {
+                QueryLine::CreateTable(ref table) => Some(table.to_owned()),
+                _ => acc,
+            }
+        });
+        let matching = lines.iter().fold(Matching::All, |acc, line| {
+            match *line {
+                QueryLine::Match(matching) => matching,
+                _ => acc,
+            }
+        });
+        let as_of = lines.iter().fold(None, |acc, line| {
+            match *line {
+                QueryLine::AsOf(time) => Some(time),
+                _ => acc,
+            }
+        });
+        let mut aliases = HashMap::new();
+        for line in &lines {
+            if let QueryLine::Select(ref selections, _, _) = *line {
+                for selection in selections {
+                    if let Selection::Alias(ref col, ref alias) = *selection {
+                        aliases.insert(col.to_owned(), ColumnName::alias(&alias[..]));
+                    }
+                }
+            }
+        }
+        let graph = Self::build_graph(lines, default_limit);
+        // Joins can't require ids they provide themselves, directly or through
+        // other joins, `build_stages` can only order an acyclic graph
+        if algo::is_cyclic_directed(&graph) {
+            return Err(Error::CyclicPlan);
+        }
+        let stages = Self::build_stages(&graph);
 
-        let mut plan = Plan { stages: stages };
+        let mut plan = Plan {
+            stages: stages,
+            order: order,
+            create_table: create_table,
+            aliases: aliases,
+            matching: matching,
+            as_of: as_of,
+        };
         plan.optimize();
-        plan
+        plan.set_as_of_time_bounds();
+        plan.set_latest_time_bounds();
+        Ok(plan)
+    }
+
+    /// Parse `query` like `from_str`, expanding each `<table>.*` into the
+    /// table's columns in `db`. Columns of a query without an `l` line keep
+    /// `default_limit` rows
+    pub fn parse_against(query: &str, db: &Db, default_limit: usize) -> Result<Plan, Error> {
+        let query_lines = try!(query::parse(query)
+                                   .map_err(|err| Error::from_parse_error(query, err)));
+        Plan::from_lines_with_limit(try!(expand_wildcards(query_lines, db)), default_limit)
+    }
+
+    /// Name `name` is returned under, its alias if it has one
+    pub fn output_name(&self, name: ColumnName) -> ColumnName {
+        match self.aliases.get(&name) {
+            Some(alias) => alias.to_owned(),
+            None => name,
+        }
+    }
+
+    /// Checks query lines, parsed or built, before planning them
+    pub fn from_lines(lines: Vec<QueryLine>) -> Result<Plan, Error> {
+        Plan::from_lines_with_limit(lines, DEFAULT_LIMIT)
+    }
+
+    fn from_lines_with_limit(lines: Vec<QueryLine>, default_limit: usize) -> Result<Plan, Error> {
+        let lines = try!(time_values_as_ints(lines));
+        try!(check_wildcards(&lines));
+        try!(check_aliases(&lines));
+        try!(check_groups(&lines));
+        try!(check_limits(&lines));
+        try!(check_settings(&lines));
+        try!(check_comparisons(&lines));
+        let plan = try!(Plan::new(lines, default_limit));
+        try!(plan.is_valid());
+        Ok(plan)
+    }
+
+    /// Check that every column the plan reads exists in `db` and that joined
+    /// columns have the same type, so bad queries are caught before anything
+    /// is executed
+    pub fn validate_against(&self, db: &Db) -> Result<(), Error> {
+        let nodes = self.stages.iter().flat_map(|stage| stage.nodes.iter()).collect::<Vec<_>>();
+        let order = self.order.iter().map(|&(ref col_name, _)| col_name);
+
+        for col_name in nodes.iter().flat_map(|node| node.columns()).chain(order) {
+            if !db.ids.contains_key(&col_name.table) {
+                return Err(Error::UnknownTable(col_name.table.to_owned()));
+            }
+            if !db.cols.contains_key(col_name) {
+                return Err(Error::UnknownColumn(col_name.to_owned()));
+            }
+        }
+
+        for node in nodes {
+            match *node {
+                PlanNode::Join(ref left, ref right, _) => {
+                    if !db.cols[left].data.is_same_type(&db.cols[right].data) {
+                        return Err(Error::InvalidJoin(left.to_owned(), right.to_owned()));
+                    }
+                }
+                // Values of different types don't compare by what they hold,
+                // an int compared to a float column would match every row
+                PlanNode::Where(ref col_name, ref predicate, _) => {
+                    if let Some(value) = mismatched_value(predicate, &db.cols[col_name]) {
+                        return Err(Error::InvalidValue(col_name.to_owned(), value.to_owned()));
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// One line per filter the plan applies, e.g. `foo.a in [1, 3)`
+    pub fn filter_summaries(&self) -> Vec<String> {
+        self.stages
+            .iter()
+            .flat_map(|stage| stage.nodes.iter())
+            .filter_map(|node| {
+                match *node {
+                    PlanNode::Where(ref col_name, ref predicate, _) => {
+                        Some(format!("{} {}", col_name, predicate.summarize()))
+                    }
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    /// Columns of where nodes whose ids no other node requires, their
+    /// filtering would be thrown away
+    pub fn unused_filters(&self) -> Vec<&ColumnName> {
+        let nodes = self.stages.iter().flat_map(|stage| stage.nodes.iter()).collect::<Vec<_>>();
+        let required = nodes.iter().flat_map(|node| node.requires()).collect::<HashSet<_>>();
+
+        let mut unused = nodes.iter()
+                              .filter_map(|node| {
+                                  match **node {
+                                      PlanNode::Where(ref col_name, _, _) |
+                                      PlanNode::WhereId(ref col_name, _) => Some(col_name),
+                                      _ => None,
+                                  }
+                              })
+                              .filter(|col_name| !required.contains(&col_name.id()))
+                              .collect::<Vec<&ColumnName>>();
+        unused.sort_by_key(|col_name| format!("{}", col_name));
+        unused
     }
 
     pub fn is_valid(&self) -> Result<(), Error> {
@@ -321,6 +1289,9 @@ impl Plan {
 
         let stage_query_types = self.stage_query_types();
         let stages_len = stage_query_types.len();
+        if !stage_query_types.iter().any(|types| types.contains(&1)) {
+            return Err(Error::NoStages);
+        }
 
         for (index, types) in stage_query_types.iter().enumerate() {
             if types.contains(&0) {
@@ -340,30 +1311,155 @@ impl Plan {
         Ok(())
     }
 
-    fn build_graph(lines: Vec<QueryLine>) -> Graph<PlanNode, ColumnName> {
+    /// Readable listing of every stage and its nodes, with the time bound
+    /// of each where node that has one
+    pub fn explain(&self) -> String {
+        let mut lines = vec![];
+        for (index, stage) in self.stages.iter().enumerate() {
+            lines.push(format!("stage {}/{}", index + 1, self.stages.len()));
+
+            for node in stage.sorted_nodes() {
+                Self::explain_node(node, "  ", &mut lines);
+            }
+        }
+
+        let mut aliases = self.aliases
+                              .iter()
+                              .map(|(name, alias)| format!("alias {} as {}", name, alias))
+                              .collect::<Vec<String>>();
+        aliases.sort();
+        lines.extend(aliases);
+        if let Some((ref name, direction)) = self.order {
+            lines.push(format!("order {} {:?}", name, direction));
+        }
+        if let Some(ref table) = self.create_table {
+            lines.push(format!("create table {}", table));
+        }
+        if self.matching == Matching::Any {
+            lines.push("match any".to_owned());
+        }
+        if let Some(time) = self.as_of {
+            lines.push(format!("as of {}", time));
+        }
+        lines.join("\n")
+    }
+
+    /// Nodes as an indented tree, each with the nodes whose ids it takes
+    /// under it
+    ///
+    /// ```
+    /// use std::str::FromStr;
+    /// use twin_query::Plan;
+    ///
+    /// let plan = Plan::from_str("s bar.c\nj foo on bar.foo\nw foo.b = true\nw bar.c > 50")
+    ///                .unwrap();
+    /// assert_eq!(plan.pretty(),
+    ///            ["Select(bar.c, 20, 0)",
+    ///             "  Where(bar.c, Constant(Greater, Int(50)))",
+    ///             "  Join(foo.id, bar.foo)",
+    ///             "    Where(foo.b, Constant(Equal, Bool(true)))"]
+    ///                .join("\n"));
+    /// ```
+    pub fn pretty(&self) -> String {
+        let nodes = self.stages
+                        .iter()
+                        .flat_map(|stage| stage.sorted_nodes())
+                        .collect::<Vec<&PlanNode>>();
+        let mut lines = vec![];
+        for node in nodes.iter().rev() {
+            let taken = node.provides().map_or(false, |provided| {
+                nodes.iter().any(|other| other.requires().contains(&provided))
+            });
+            if !taken {
+                Self::pretty_node(node, &nodes, 0, &mut lines);
+            }
+        }
+        lines.join("\n")
+    }
+
+    /// A where node's time bound goes on its own line under it
+    fn explain_node(node: &PlanNode, indent: &str, lines: &mut Vec<String>) {
+        match *node {
+            PlanNode::Where(ref col_name, ref predicate, ref bound) => {
+                lines.push(format!("{}Where({}, {:?})", indent, col_name, predicate));
+                if let Some(ref bound) = *bound {
+                    lines.push(format!("{}  time bound: {}", indent, bound));
+                }
+            }
+            _ => lines.push(format!("{}{}", indent, node)),
+        }
+    }
+
+    fn pretty_node(node: &PlanNode, nodes: &[&PlanNode], depth: usize, lines: &mut Vec<String>) {
+        let indent = iter::repeat("  ").take(depth).collect::<String>();
+        Self::explain_node(node, &indent, lines);
+
+        for required in node.requires() {
+            for input in nodes.iter().filter(|input| input.provides().as_ref() == Some(&required)) {
+                Self::pretty_node(input, nodes, depth + 1, lines);
+            }
+        }
+    }
+
+    /// Stages in execution order as JSON, each node encoded as
+    /// `{"variant": ..., "fields": [...]}`
+    pub fn to_json(&self) -> String {
+        let stages = self.stages
+                         .iter()
+                         .map(|stage| stage.sorted_nodes())
+                         .collect();
+        let plan = EncodablePlan {
+            stages: stages,
+            order: &self.order,
+            create_table: &self.create_table,
+        };
+        json::encode(&plan).expect("Failed to encode plan")
+    }
+
+    fn build_graph(lines: Vec<QueryLine>, default_limit: usize) -> Graph<PlanNode, ColumnName> {
         let mut graph = Graph::new();
 
-        let limit = lines.iter().fold(20, |acc, line| {
+        let limit = lines.iter().fold(default_limit, |acc, line| {
             match *line {
                 QueryLine::Limit(size) => size,
                 _ => acc,
             }
         });
+        let offset = lines.iter().fold(0, |acc, line| {
+            match *line {
+                QueryLine::Offset(size) => size,
+                _ => acc,
+            }
+        });
+        let group = lines.iter().fold(None, |acc, line| {
+            match *line {
+                QueryLine::GroupBy(ref key) => Some(key.to_owned()),
+                _ => acc,
+            }
+        });
+        let having = lines.iter().fold(None, |acc, line| {
+            match *line {
+                QueryLine::Having(_, ref predicate) => Some(predicate.to_owned()),
+                _ => acc,
+            }
+        });
         let node_indices =
             lines.into_iter()
-                 .flat_map(|line| parse_line(line, limit))
-                 .map(|(node, require, provide)| (graph.add_node(node.clone()), require, provide))
-                 .collect::<Vec<(NodeIndex, Option<ColumnName>, Option<ColumnName>)>>();
+                 .flat_map(|line| {
+                     parse_line(line, limit, offset, group.as_ref(), having.as_ref())
+                 })
+                 .map(|node| {
+                     let (require, provide) = (node.requires(), node.provides());
+                     (graph.add_node(node), require, provide)
+                 })
+                 .collect::<Vec<(NodeIndex, Vec<ColumnName>, Option<ColumnName>)>>();
 
         for &(node_index, ref req, _) in &node_indices {
             for &(inner_index, _, ref prov) in &node_indices {
-                match (req, prov) {
-                    (&Some(ref r), &Some(ref p)) => {
-                        if r == p {
-                            graph.add_edge(node_index, inner_index, prov.clone().unwrap());
-                        }
+                if let Some(ref p) = *prov {
+                    if req.contains(p) {
+                        graph.add_edge(node_index, inner_index, p.clone());
                     }
-                    _ => continue,
                 }
             }
         }
@@ -371,50 +1467,66 @@ impl Plan {
         graph
     }
 
+    /// Edges point from a node to the nodes providing its ids, so a node's
+    /// depth is the longest chain of nodes requiring it. Stages are built
+    /// deepest first, every provider runs in an earlier stage than the nodes
+    /// that need it.
     fn build_stages(graph: &Graph<PlanNode, ColumnName>) -> Vec<Stage> {
-        let mut stages = vec![];
-
-        for external in graph.externals(EdgeDirection::Incoming) {
-            let mut dfs = Dfs::new(graph, external);
-            while let Some(node) = dfs.next(graph) {
-                let mut max_depth = -1;
-                let provides = graph.neighbors_directed(node, EdgeDirection::Incoming);
-
-                for provide in provides {
-                    match Self::find_stage_index(&stages, &graph[provide]) {
-                        Some(stage_index) => max_depth = cmp::max(max_depth, stage_index as isize),
-                        _ => continue,
-                    }
-                }
+        let mut depths = HashMap::new();
+
+        for node in algo::toposort(graph) {
+            let depth = graph.neighbors_directed(node, EdgeDirection::Incoming)
+                             .filter_map(|require| depths.get(&require).map(|depth| depth + 1))
+                             .max()
+                             .unwrap_or(0);
+            // A where node nothing requires still runs before the selects,
+            // its ids just aren't read
+            let depth = match graph[node] {
+                PlanNode::Where(_, _, _) |
+                PlanNode::WhereId(_, _) => cmp::max(depth, 1),
+                _ => depth,
+            };
+            depths.insert(node, depth);
+        }
 
-                let stage_index = (max_depth + 1) as usize;
+        let mut stages = vec![];
 
-                if stage_index >= stages.len() {
-                    stages.push(Stage::default())
-                }
-                stages[stage_index].insert(graph[node].clone());
+        for node in graph.node_indices() {
+            let depth = depths[&node];
+            while depth >= stages.len() {
+                stages.push(Stage::default());
             }
+            stages[depth].insert(graph[node].clone());
         }
 
+        stages.retain(|stage| !stage.is_empty());
         stages.reverse();
         stages
     }
 
+    /// Time bounds from one where line only narrow the others when every line
+    /// has to match, with `match any` they're left unbounded
     fn optimize(&mut self) {
+        let matching = self.matching;
         self.stages = self.stages
                           .iter()
-                          .map(Self::combine_where_nodes_on_same_column)
-                          .map(|s| Self::set_time_bounds_on_where_nodes(&s))
+                          .map(|s| Self::combine_where_nodes_on_same_column(s, matching))
+                          .map(|s| {
+                              match matching {
+                                  Matching::All => Self::set_time_bounds_on_where_nodes(&s),
+                                  Matching::Any => s,
+                              }
+                          })
                           .collect::<Vec<Stage>>();
     }
 
-    fn combine_where_nodes_on_same_column(stage: &Stage) -> Stage {
+    fn combine_where_nodes_on_same_column(stage: &Stage, matching: Matching) -> Stage {
         let mut new = stage.clone();
         let groups = stage.group_where_nodes_by_column();
 
         for group in groups {
             if group.len() > 1 {
-                new.replace(&group, vec![Self::group_nodes_into_and_predicate(&group)])
+                new.replace(&group, vec![Self::group_nodes_into_predicate(&group, matching)])
             }
         }
 
@@ -430,7 +1542,7 @@ impl Plan {
                 PlanNode::Where(ref col_name, ref predicate, _) => (col_name, predicate),
                 _ => panic!("Invalid time_node"),
             };
-            let bound = TimeBound::from_predicate(predicate);
+            let (bound, exact) = TimeBound::from_predicate(predicate);
             let group = stage.find_by_table(&col_name.table)
                              .into_iter()
                              .filter(|&node| {
@@ -441,34 +1553,89 @@ impl Plan {
                              })
                              .collect::<Vec<&PlanNode>>();
 
-            if group.len() == 1 {
-                new.replace(&[time_node],
-                            vec![PlanNode::Where(col_name.to_owned(),
-                                                 predicate.to_owned(),
-                                                 Some(bound))]);
-            } else {
-                let new_nodes = group.iter()
-                                     .filter(|&n| *n != time_node)
-                                     .map(|&node| {
-                                         match *node {
-                                             PlanNode::Where(ref c, ref p, _) => {
-                                                 PlanNode::Where(c.to_owned(),
-                                                                 p.to_owned(),
-                                                                 Some(bound.clone()))
-                                             }
-                                             _ => panic!(),
+            // The time node's predicate is only dropped when the bound put on
+            // the other where nodes of its table matches exactly the same times
+            let keep_time_node = group.len() == 1 || !exact;
+            let new_nodes = group.iter()
+                                 .filter(|&n| keep_time_node || *n != time_node)
+                                 .map(|&node| {
+                                     match *node {
+                                         PlanNode::Where(ref c, ref p, _) => {
+                                             PlanNode::Where(c.to_owned(),
+                                                             p.to_owned(),
+                                                             Some(bound.clone()))
                                          }
-                                     })
-                                     .collect();
+                                         _ => panic!(),
+                                     }
+                                 })
+                                 .collect();
 
-                new.replace(&group, new_nodes)
-            }
+            new.replace(&group, new_nodes)
         }
 
         new
     }
 
-    fn group_nodes_into_and_predicate(group: &[&PlanNode]) -> PlanNode {
+    /// With `as of` every where node is bounded by its time and only tests
+    /// each id's newest datum, and selects become latest nodes
+    fn set_as_of_time_bounds(&mut self) {
+        let time = match self.as_of {
+            Some(time) => time,
+            None => return,
+        };
+
+        for stage in &mut self.stages {
+            stage.nodes = stage.nodes
+                               .drain()
+                               .map(|node| {
+                                   match node {
+                                       PlanNode::Where(name, predicate, bound) => {
+                                           let bound = TimeBound::as_of(bound.as_ref(), time);
+                                           PlanNode::Where(name, predicate, Some(bound))
+                                       }
+                                       PlanNode::Select(name, limit, offset, distinct) => {
+                                           PlanNode::Latest(name, limit, offset, distinct, None)
+                                       }
+                                       node => node,
+                                   }
+                               })
+                               .collect();
+        }
+    }
+
+    /// Latest nodes take the max time bound that `optimize` put on the where
+    /// nodes of their table, which can be in any earlier stage, or the `as of`
+    /// time
+    fn set_latest_time_bounds(&mut self) {
+        let as_of = self.as_of;
+        let mut max_times = HashMap::new();
+        for node in self.stages.iter().flat_map(|stage| stage.nodes.iter()) {
+            if let PlanNode::Where(ref col_name, _, Some(ref bound)) = *node {
+                if let Some(max) = bound.max() {
+                    max_times.insert(col_name.table.to_owned(), max);
+                }
+            }
+        }
+
+        for stage in &mut self.stages {
+            stage.nodes = stage.nodes
+                               .drain()
+                               .map(|node| {
+                                   match node {
+                                       PlanNode::Latest(name, limit, offset, distinct, _) => {
+                                           let max = max_times.get(&name.table)
+                                                              .cloned()
+                                                              .or(as_of);
+                                           PlanNode::Latest(name, limit, offset, distinct, max)
+                                       }
+                                       node => node,
+                                   }
+                               })
+                               .collect();
+        }
+    }
+
+    fn group_nodes_into_predicate(group: &[&PlanNode], matching: Matching) -> PlanNode {
         let mut col_name = None;
         let mut predicate = None;
 
@@ -483,9 +1650,15 @@ impl Plan {
                 None => Some(inner_col_name.to_owned()),
             };
 
-            predicate = match predicate {
-                Some(pred) => Some(Predicate::And(Box::new(pred), Box::new(inner_pred.to_owned()))),
-                None => Some(inner_pred.to_owned()),
+            let inner_pred = inner_pred.to_owned();
+            predicate = match (predicate, matching) {
+                (Some(pred), Matching::All) => {
+                    Some(Predicate::And(Box::new(pred), Box::new(inner_pred)))
+                }
+                (Some(pred), Matching::Any) => {
+                    Some(Predicate::Or(Box::new(pred), Box::new(inner_pred)))
+                }
+                (None, _) => Some(inner_pred),
             }
         }
 
@@ -502,8 +1675,12 @@ impl Plan {
                 let mut stage_types = HashSet::new();
                 for node in &stage.nodes {
                     match *node {
-                        PlanNode::Select(_, _) => stage_types.insert(1),
-                        PlanNode::Join(_, _) => stage_types.insert(2),
+                        PlanNode::Select(_, _, _, _) |
+                        PlanNode::Latest(_, _, _, _, _) |
+                        PlanNode::TimeCount(_, _) |
+                        PlanNode::Group(_, _, _, _, _) |
+                        PlanNode::Cohort(_, _, _, _, _) => stage_types.insert(1),
+                        PlanNode::Join(_, _, _) => stage_types.insert(2),
                         PlanNode::Where(_, _, _) => stage_types.insert(3),
                         PlanNode::WhereId(_, _) => stage_types.insert(4),
                     };
@@ -512,25 +1689,356 @@ impl Plan {
             })
             .collect()
     }
+}
+
+/// Builds the query lines of a plan without going through the grammar, the
+/// lines are checked like a parsed query's when the plan is built
+///
+/// ```
+/// use twin_query::{ColumnName, Plan, Value};
+/// use twin_query::plan::{Comparator, Predicate};
+///
+/// let plan = Plan::builder()
+///                .select(ColumnName::new("bar", "b"))
+///                .join("foo", ColumnName::new("bar", "foo"))
+///                .where_(ColumnName::new("foo", "a"),
+///                        Predicate::Constant(Comparator::Less, Value::Int(2)))
+///                .limit(5)
+///                .build()
+///                .unwrap();
+/// let parsed = "s bar.b\nj foo on bar.foo\nw foo.a < 2\nl 5".parse::<Plan>().unwrap();
+///
+/// assert_eq!(format!("{}", plan), format!("{}", parsed));
+/// assert!(Plan::builder().limit(0).build().is_err());
+/// ```
+#[derive(Debug, Default)]
+pub struct PlanBuilder {
+    lines: Vec<QueryLine>,
+}
+
+impl PlanBuilder {
+    pub fn select(self, col_name: ColumnName) -> Self {
+        self.line(QueryLine::Select(vec![Selection::Column(col_name)], false, false))
+    }
+
+    pub fn where_(self, col_name: ColumnName, predicate: Predicate) -> Self {
+        self.line(QueryLine::Where(col_name, predicate))
+    }
+
+    /// Joins the ids of `table` on the values of `on`, like `j <table> on <on>`
+    pub fn join(self, table: &str, on: ColumnName) -> Self {
+        self.line(QueryLine::Join(ColumnName::new(table, "id"), on, JoinKind::Inner))
+    }
+
+    pub fn limit(self, size: usize) -> Self {
+        self.line(QueryLine::Limit(size))
+    }
+
+    pub fn offset(self, size: usize) -> Self {
+        self.line(QueryLine::Offset(size))
+    }
+
+    pub fn order(self, col_name: ColumnName, direction: Direction) -> Self {
+        self.line(QueryLine::OrderBy(col_name, direction))
+    }
+
+    /// Any other line, e.g. a left join or a distinct select
+    pub fn line(mut self, line: QueryLine) -> Self {
+        self.lines.push(line);
+        self
+    }
+
+    pub fn build(self) -> Result<Plan, Error> {
+        Plan::from_lines(self.lines)
+    }
+}
+
+/// Replaces every `<table>.*` selection with one selection per column of the
+/// table in `db`, sorted by name
+fn expand_wildcards(lines: Vec<QueryLine>, db: &Db) -> Result<Vec<QueryLine>, Error> {
+    let mut expanded = vec![];
+    for line in lines {
+        let line = match line {
+            QueryLine::Select(selections, distinct, latest) => {
+                let mut columns = vec![];
+                for selection in selections {
+                    match selection {
+                        Selection::All(table) => {
+                            if !db.ids.contains_key(&table) {
+                                return Err(Error::UnknownTable(table));
+                            }
+                            for column in db.table_columns(&table) {
+                                columns.push(Selection::Column(ColumnName::new(&table[..],
+                                                                               column)));
+                            }
+                        }
+                        selection => columns.push(selection),
+                    }
+                }
+                QueryLine::Select(columns, distinct, latest)
+            }
+            line => line,
+        };
+        expanded.push(line);
+    }
+    Ok(expanded)
+}
+
+fn check_wildcards(lines: &[QueryLine]) -> Result<(), Error> {
+    for line in lines {
+        if let QueryLine::Select(ref selections, _, _) = *line {
+            for selection in selections {
+                if let Selection::All(ref table) = *selection {
+                    return Err(Error::UnexpandedWildcard(table.to_owned()));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// An alias can only name one column, and an aliased column can't also be
+/// selected under another name
+fn check_aliases(lines: &[QueryLine]) -> Result<(), Error> {
+    let mut names = HashMap::new();
+    let mut aliases = HashSet::new();
+    for line in lines {
+        if let QueryLine::Select(ref selections, _, _) = *line {
+            for selection in selections {
+                let (col, name) = match *selection {
+                    Selection::Column(ref col) => (col, None),
+                    Selection::Alias(ref col, ref alias) => {
+                        if !aliases.insert(alias) {
+                            return Err(Error::DuplicateAlias(alias.to_owned()));
+                        }
+                        (col, Some(alias))
+                    }
+                    _ => continue,
+                };
+                if *names.entry(col).or_insert(name) != name {
+                    return Err(Error::ConflictingAlias(col.to_owned()));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A query can have at most one `group`, which must come with exactly one
+/// aggregate over a column of the same table, and at most one `having` for
+/// that aggregate comparing it to ints
+fn check_groups(lines: &[QueryLine]) -> Result<(), Error> {
+    let keys = lines.iter()
+                    .filter_map(|line| {
+                        match *line {
+                            QueryLine::GroupBy(ref key) => Some(key),
+                            _ => None,
+                        }
+                    })
+                    .collect::<Vec<&ColumnName>>();
+    let cols = lines.iter()
+                    .filter_map(|line| {
+                        match *line {
+                            QueryLine::Aggregate(_, ref col) => Some(col),
+                            _ => None,
+                        }
+                    })
+                    .collect::<Vec<&ColumnName>>();
+
+    let aggregates = lines.iter()
+                          .filter_map(|line| {
+                              match *line {
+                                  QueryLine::Aggregate(aggregate, _) => Some(aggregate),
+                                  _ => None,
+                              }
+                          })
+                          .collect::<Vec<Aggregate>>();
+    let havings = lines.iter()
+                       .filter_map(|line| {
+                           match *line {
+                               QueryLine::Having(aggregate, ref predicate) => {
+                                   Some((aggregate, predicate))
+                               }
+                               _ => None,
+                           }
+                       })
+                       .collect::<Vec<(Aggregate, &Predicate)>>();
+
+    match (keys.len(), cols.len()) {
+        (0, 0) if havings.is_empty() => (),
+        (1, 1) if keys[0].table == cols[0].table => (),
+        _ => return Err(Error::InvalidGroup),
+    }
+
+    match havings.first() {
+        Some(_) if havings.len() > 1 => Err(Error::InvalidHaving),
+        Some(&(aggregate, _)) if aggregate != aggregates[0] => Err(Error::InvalidHaving),
+        Some(&(_, predicate)) => {
+            let ints = predicate.values().iter().all(|value| {
+                match **value {
+                    Value::Int(_) => true,
+                    _ => false,
+                }
+            });
+            if ints && !predicate.has_like() && predicate.compared_columns().is_empty() {
+                Ok(())
+            } else {
+                Err(Error::InvalidHaving)
+            }
+        }
+        None => Ok(()),
+    }
+}
+
+/// Limits have to select at least one row and can't disagree with each other
+fn check_limits(lines: &[QueryLine]) -> Result<(), Error> {
+    if lines.contains(&QueryLine::Limit(0)) {
+        return Err(Error::ZeroLimit);
+    }
+
+    let limit = |line: &QueryLine| {
+        match *line {
+            QueryLine::Limit(size) => Some(size),
+            _ => None,
+        }
+    };
+    match conflicting(lines, limit) {
+        Some((first, second)) => Err(Error::ConflictingLimits(first, second)),
+        None => Ok(()),
+    }
+}
+
+/// A query can only match all or any of its where lines, and only be run as of
+/// one time
+fn check_settings(lines: &[QueryLine]) -> Result<(), Error> {
+    let matching = |line: &QueryLine| {
+        match *line {
+            QueryLine::Match(matching) => Some(matching),
+            _ => None,
+        }
+    };
+    if conflicting(lines, matching).is_some() {
+        return Err(Error::ConflictingMatching);
+    }
 
-    fn find_stage_index(stages: &[Stage], node: &PlanNode) -> Option<usize> {
-        for (idx, stage) in stages.iter().enumerate() {
-            if stage.contains(node) {
-                return Some(idx);
+    let as_of = |line: &QueryLine| {
+        match *line {
+            QueryLine::AsOf(time) => Some(time),
+            _ => None,
+        }
+    };
+    match conflicting(lines, as_of) {
+        Some((first, second)) => Err(Error::ConflictingAsOf(first, second)),
+        None => Ok(()),
+    }
+}
+
+/// First value `value` picks out of the lines and the first one differing
+/// from it, if any does
+fn conflicting<T, F>(lines: &[QueryLine], value: F) -> Option<(T, T)>
+    where T: Copy + PartialEq,
+          F: Fn(&QueryLine) -> Option<T>
+{
+    let mut values = lines.iter().filter_map(value);
+    values.next().and_then(|first| values.find(|&other| other != first).map(|other| (first, other)))
+}
+
+fn check_comparisons(lines: &[QueryLine]) -> Result<(), Error> {
+    for line in lines {
+        if let QueryLine::Where(ref left, ref predicate) = *line {
+            if let Some(other) = predicate.compared_columns()
+                                          .into_iter()
+                                          .find(|other| other.table != left.table) {
+                return Err(Error::InvalidComparison(other.to_owned()));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// First value `predicate` compares that isn't of `column`'s type, remainders
+/// of a modulo are always compared to ints
+fn mismatched_value<'a>(predicate: &'a Predicate, column: &Column) -> Option<&'a Value> {
+    match *predicate {
+        Predicate::Constant(_, ref value) if !column.holds(value) => Some(value),
+        Predicate::Mod(_, _, ref value) => {
+            match *value {
+                Value::Int(_) => None,
+                _ => Some(value),
             }
         }
-        None
+        Predicate::And(ref left, ref right) |
+        Predicate::Or(ref left, ref right) => {
+            mismatched_value(left, column).or_else(|| mismatched_value(right, column))
+        }
+        Predicate::Not(ref inner) => mismatched_value(inner, column),
+        Predicate::In(ref values) => values.iter().find(|value| !column.holds(value)),
+        Predicate::Constant(_, _) |
+        Predicate::Column(_, _) |
+        Predicate::Like(_) |
+        Predicate::Prefix(_) |
+        Predicate::IsNull => None,
     }
 }
 
+/// Time columns hold ints, timestamps compared against them are taken as
+/// their seconds and any other value is an error
+fn time_values_as_ints(lines: Vec<QueryLine>) -> Result<Vec<QueryLine>, Error> {
+    lines.into_iter()
+         .map(|line| {
+             match line {
+                 QueryLine::Where(left, predicate) => {
+                     if left.column != "time" {
+                         return Ok(QueryLine::Where(left, predicate));
+                     }
+                     let predicate = try!(time_predicate(&left, predicate));
+                     Ok(QueryLine::Where(left, predicate))
+                 }
+                 line => Ok(line),
+             }
+         })
+         .collect()
+}
+
+fn time_predicate(col_name: &ColumnName, predicate: Predicate) -> Result<Predicate, Error> {
+    let as_int = |value: Value| {
+        match value {
+            Value::Int(i) | Value::Timestamp(i) => Ok(Value::Int(i)),
+            value => Err(Error::InvalidTimeValue(col_name.to_owned(), value)),
+        }
+    };
+
+    Ok(match predicate {
+        Predicate::Constant(comp, value) => Predicate::Constant(comp, try!(as_int(value))),
+        Predicate::Mod(modulus, comp, value) => {
+            Predicate::Mod(modulus, comp, try!(as_int(value)))
+        }
+        Predicate::And(left, right) => {
+            Predicate::And(Box::new(try!(time_predicate(col_name, *left))),
+                           Box::new(try!(time_predicate(col_name, *right))))
+        }
+        Predicate::Or(left, right) => {
+            Predicate::Or(Box::new(try!(time_predicate(col_name, *left))),
+                          Box::new(try!(time_predicate(col_name, *right))))
+        }
+        Predicate::Not(inner) => Predicate::Not(Box::new(try!(time_predicate(col_name, *inner)))),
+        Predicate::In(values) => {
+            Predicate::In(try!(values.into_iter().map(as_int).collect()))
+        }
+        predicate @ Predicate::Column(_, _) |
+        predicate @ Predicate::Like(_) |
+        predicate @ Predicate::Prefix(_) |
+        predicate @ Predicate::IsNull => predicate,
+    })
+}
+
 impl str::FromStr for Plan {
     type Err = Error;
 
     fn from_str(query: &str) -> Result<Self, Self::Err> {
-        let query_lines = try!(grammar::query(query));
-        let plan = Plan::new(query_lines);
-        try!(plan.is_valid());
-        Ok(plan)
+        let query_lines = try!(query::parse(query)
+                                   .map_err(|err| Error::from_parse_error(query, err)));
+        Plan::from_lines(query_lines)
     }
 }
 
@@ -538,7 +2046,7 @@ impl fmt::Display for Plan {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         try!(write!(f, "Plan: "));
         for (idx, stage) in self.stages.iter().enumerate() {
-            let s = stage.nodes
+            let s = stage.sorted_nodes()
                          .iter()
                          .map(|node| format!("{}", node))
                          .collect::<Vec<String>>();
@@ -552,8 +2060,62 @@ impl fmt::Display for Plan {
     }
 }
 
-impl From<grammar::ParseError> for Error {
-    fn from(err: grammar::ParseError) -> Error {
-        Error::ParseError(err)
+impl Error {
+    fn from_parse_error(query: &str, err: query::ParseError) -> Error {
+        let snippet = query.lines().nth(err.line - 1).unwrap_or("");
+        Error::ParseError(err.line, err.column, snippet.to_owned())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::ParseError(line, column, ref snippet) => {
+                try!(write!(f, "parse error at line {}, column {}\n", line, column));
+                try!(write!(f, "  {}\n", snippet));
+                let padding = iter::repeat(" ").take(column - 1).collect::<String>();
+                write!(f, "  {}^", padding)
+            }
+            Error::NoStages => write!(f, "query has nothing to execute"),
+            Error::EmptyStages => write!(f, "plan has an empty stage"),
+            Error::InvalidStageOrder => write!(f, "plan stages are out of order"),
+            Error::EmptyNodeInStages => write!(f, "plan has an empty node"),
+            Error::InvalidGroup => {
+                write!(f, "group needs exactly one aggregate over a column of the same table")
+            }
+            Error::InvalidHaving => {
+                write!(f, "having compares the query's aggregate to ints")
+            }
+            Error::ZeroLimit => write!(f, "limit has to be above 0"),
+            Error::ConflictingLimits(first, second) => {
+                write!(f, "conflicting limits {} and {}", first, second)
+            }
+            Error::ConflictingMatching => write!(f, "conflicting match all and match any"),
+            Error::ConflictingAsOf(first, second) => {
+                write!(f, "conflicting as of times {} and {}", first, second)
+            }
+            Error::InvalidComparison(ref col_name) => {
+                write!(f, "{} can only be compared to columns of its own table", col_name)
+            }
+            Error::InvalidTimeValue(ref col_name, ref value) => {
+                write!(f, "{} can only be compared to ints or timestamps, not {}", col_name, value)
+            }
+            Error::CyclicPlan => write!(f, "joins form a cycle"),
+            Error::UnknownTable(ref table) => write!(f, "unknown table {}", table),
+            Error::UnknownColumn(ref col_name) => write!(f, "unknown column {}", col_name),
+            Error::DuplicateAlias(ref alias) => write!(f, "alias {} names two columns", alias),
+            Error::ConflictingAlias(ref col_name) => {
+                write!(f, "{} is selected under more than one name", col_name)
+            }
+            Error::UnexpandedWildcard(ref table) => {
+                write!(f, "{}.* can only be selected from a Db", table)
+            }
+            Error::InvalidJoin(ref left, ref right) => {
+                write!(f, "can't join {} on {}, their types differ", left, right)
+            }
+            Error::InvalidValue(ref col_name, ref value) => {
+                write!(f, "can't compare {} to {}, their types differ", col_name, value)
+            }
+        }
     }
 }
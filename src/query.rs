@@ -0,0 +1,49 @@
+use plan::QueryLine;
+
+peg_file! grammar("grammar.rustpeg");
+
+pub use self::grammar::ParseError;
+
+/// Parse a query into its lines without planning it, `Plan::from_lines`
+/// plans them once they've been checked or rewritten
+///
+/// ```
+/// extern crate twin_query;
+///
+/// use twin_query::ColumnName;
+/// use twin_query::plan::{Aggregate, Comparator, Direction, JoinKind, Matching, Predicate,
+///                        QueryLine, Selection};
+/// use twin_query::query;
+/// use twin_query::Value;
+///
+/// # fn main() {
+/// let col = |table: &str, column: &str| ColumnName::new(table.to_owned(), column.to_owned());
+/// let equal_one = Predicate::Constant(Comparator::Equal, Value::Int(1));
+///
+/// let cases = vec![("s distinct foo.a",
+///                   QueryLine::Select(vec![Selection::Column(col("foo", "a"))], true, false)),
+///                  ("j foo on bar.foo", QueryLine::Join(col("foo", "id"), col("bar", "foo"),
+///                                                       JoinKind::Inner)),
+///                  ("w foo.a = 1", QueryLine::Where(col("foo", "a"), equal_one.clone())),
+///                  ("l 5", QueryLine::Limit(5)),
+///                  ("offset 2", QueryLine::Offset(2)),
+///                  ("order foo.a desc", QueryLine::OrderBy(col("foo", "a"), Direction::Desc)),
+///                  ("group foo.b", QueryLine::GroupBy(col("foo", "b"))),
+///                  ("count foo.a", QueryLine::Aggregate(Aggregate::Count, col("foo", "a"))),
+///                  ("cohort foo.time by 2 active_in bar.time by 3",
+///                   QueryLine::Cohort(col("foo", "time"), 2, col("bar", "time"), 3)),
+///                  ("create table top as", QueryLine::CreateTable("top".to_owned())),
+///                  ("match any", QueryLine::Match(Matching::Any)),
+///                  ("having count = 1", QueryLine::Having(Aggregate::Count, equal_one)),
+///                  ("as of 4", QueryLine::AsOf(4))];
+///
+/// for (line, expected) in cases {
+///     assert_eq!(query::parse(line).unwrap(), vec![expected]);
+/// }
+/// assert_eq!(query::parse("s foo.a\nl 5").unwrap().len(), 2);
+/// assert_eq!(query::parse("s foo.a\nnope").unwrap_err().line, 2);
+/// # }
+/// ```
+pub fn parse(query: &str) -> Result<Vec<QueryLine>, ParseError> {
+    grammar::query(query)
+}
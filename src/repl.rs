@@ -1,115 +1,590 @@
+use csv;
+use libc::{self, c_char, c_int};
 use prettytable::format;
 use prettytable::Table;
 use prettytable::row::Row;
 use prettytable::cell::Cell;
-use rl_sys::readline;
-use rl_sys::history::{listmgmt, mgmt, histfile};
+use rl_sys::history::{listmgmt, mgmt};
+use rustc_serialize::json::Json;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
 use std::cmp;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::mem;
+use std::ops::Range;
 use std::path::Path;
 use std::process;
-use std::str::FromStr;
+use std::time::Duration;
 use time;
 
-use data::{ColumnName, Db, Data};
+use data::{ColumnName, Db, Data, Value};
 use exec;
-use plan::Plan;
+use insert;
+use plan::{self, Plan};
+use table;
 
-fn read_query_raw() -> String {
-    let mut query = "".to_owned();
+enum Input {
+    Pending,
+    Cleared,
+    ToggleCsv,
+    Schema(Option<String>),
+    Stats(Option<String>),
+    Limit(Option<String>),
+    Page(Option<String>),
+    Exit,
+    Query(String),
+}
+
+/// Buffers query lines until a blank line submits them, `:clear` throws away
+/// everything typed so far, `:csv` switches between table and CSV output and
+/// `:schema [table]` describes a table or lists them all, `:stats [table]`
+/// prints the size of every column, `:limit [rows]` shows or sets how many
+/// result rows are printed and `:page [rows|off]` pages through them
+#[derive(Debug, Default)]
+struct QueryBuffer {
+    query: String,
+}
+
+impl QueryBuffer {
+    fn push_line(&mut self, line: &str) -> Input {
+        match line {
+            "" => {
+                let mut query = mem::replace(&mut self.query, String::new());
+                let len = query.len();
+                if len > 0 {
+                    query.truncate(len - 1);
+                }
+                Input::Query(query)
+            }
+            "exit" => Input::Exit,
+            ":csv" => Input::ToggleCsv,
+            ":clear" => {
+                self.query.clear();
+                Input::Cleared
+            }
+            _ if line == ":schema" || line.starts_with(":schema ") => {
+                Input::Schema(line.split_whitespace().nth(1).map(|t| t.to_owned()))
+            }
+            _ if line == ":stats" || line.starts_with(":stats ") => {
+                Input::Stats(line.split_whitespace().nth(1).map(|t| t.to_owned()))
+            }
+            _ if line == ":limit" || line.starts_with(":limit ") => {
+                Input::Limit(line.split_whitespace().nth(1).map(|n| n.to_owned()))
+            }
+            _ if line == ":page" || line.starts_with(":page ") => {
+                Input::Page(line.split_whitespace().nth(1).map(|n| n.to_owned()))
+            }
+            _ => {
+                self.query.push_str(line);
+                self.query.push('\n');
+                Input::Pending
+            }
+        }
+    }
+}
+
+/// Query keywords worth completing, the one letter ones like `s` and `w` aren't
+const KEYWORDS: &'static [&'static str] = &["active_in", "and", "asc", "between", "by",
+                                             "cohort", "count", "create", "desc", "distinct",
+                                             "explain", "false", "group", "in", "is", "join",
+                                             "latest", "left", "like", "not", "null", "offset",
+                                             "on", "or", "order", "sum", "table", "time_count",
+                                             "true"];
+
+/// Tab completion of keywords and table names, and of a table's columns once
+/// the token has a `table.` prefix
+pub struct Completer {
+    tables: BTreeSet<String>,
+    columns: BTreeSet<String>,
+}
+
+impl Completer {
+    pub fn new(db: &Db) -> Completer {
+        Completer {
+            tables: db.cols.keys().map(|name| name.table.clone()).collect(),
+            columns: db.cols.keys().map(|name| format!("{}", name)).collect(),
+        }
+    }
+
+    /// Sorted candidates starting with `token`
+    pub fn complete(&self, token: &str) -> Vec<String> {
+        if token.contains('.') {
+            return self.columns.iter().filter(|c| c.starts_with(token)).cloned().collect();
+        }
+
+        let mut candidates = self.tables
+                                 .iter()
+                                 .map(|t| t.as_str())
+                                 .chain(KEYWORDS.iter().cloned())
+                                 .filter(|word| word.starts_with(token))
+                                 .map(|word| word.to_owned())
+                                 .collect::<Vec<String>>();
+        candidates.sort();
+        candidates.dedup();
+        candidates
+    }
+}
+
+extern "C" {
+    fn strdup(s: *const c_char) -> *mut c_char;
+    fn readline(prompt: *const c_char) -> *mut c_char;
+    fn add_history(line: *const c_char);
+    fn read_history(file: *const c_char) -> c_int;
+    fn write_history(file: *const c_char) -> c_int;
+    fn history_truncate_file(file: *const c_char, lines: c_int) -> c_int;
+    static mut rl_completion_entry_function: Option<extern "C" fn(*const c_char, c_int)
+                                                                  -> *mut c_char>;
+}
+
+thread_local! {
+    static COMPLETER: RefCell<Option<Completer>> = RefCell::new(None);
+    static MATCHES: RefCell<Vec<String>> = RefCell::new(vec![]);
+}
+
+/// Readline calls this with `state` 0 for a new token, then with the next
+/// state for every further match until it returns null
+extern "C" fn complete_entry(text: *const c_char, state: c_int) -> *mut c_char {
+    MATCHES.with(|matches| {
+        let mut matches = matches.borrow_mut();
+        if state == 0 {
+            let token = unsafe { CStr::from_ptr(text) }.to_string_lossy();
+            *matches = COMPLETER.with(|completer| {
+                completer.borrow().as_ref().map_or(vec![], |c| c.complete(&token))
+            });
+        }
+
+        match matches.get(state as usize) {
+            // Readline frees the matches it's given
+            Some(word) => unsafe { strdup(CString::new(word.as_str()).unwrap().as_ptr()) },
+            None => ::std::ptr::null_mut(),
+        }
+    })
+}
+
+/// `rl_sys` frees its copies of prompts, lines and paths before readline gets
+/// to read them, so prompts and history go through these instead
+fn read_line(prompt: &str) -> Option<String> {
+    let prompt = CString::new(prompt).expect("Prompt contains a nul byte");
+    unsafe {
+        let line = readline(prompt.as_ptr());
+        if line.is_null() {
+            return None;
+        }
+        let owned = CStr::from_ptr(line).to_string_lossy().into_owned();
+        libc::free(line as *mut libc::c_void);
+        Some(owned)
+    }
+}
+
+fn add_history_entry(line: &str) {
+    let line = CString::new(line).expect("Query contains a nul byte");
+    unsafe { add_history(line.as_ptr()) }
+}
+
+/// Calls a readline history file function with `path`
+fn with_history_file<F>(path: &Path, f: F) -> io::Result<()>
+    where F: FnOnce(*const c_char) -> c_int
+{
+    let path = CString::new(path.to_str().expect("History path isn't UTF-8")).unwrap();
+    match f(path.as_ptr()) {
+        0 => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(errno)),
+    }
+}
+
+/// Completes against `db`'s tables and columns, called again when they change
+fn set_completer(db: &Db) {
+    COMPLETER.with(|completer| *completer.borrow_mut() = Some(Completer::new(db)));
+    unsafe {
+        rl_completion_entry_function = Some(complete_entry);
+    }
+}
+
+/// Every table's name, or the columns of `table`
+fn print_schema(db: &Db, table: Option<&str>) {
+    match table {
+        Some(table) => {
+            if !print_columns(db, Some(table)) {
+                println!("unknown table {}", table);
+            }
+        }
+        None => {
+            let tables = db.cols.keys().map(|name| &name.table).collect::<BTreeSet<_>>();
+            for table in tables {
+                println!("{}", table);
+            }
+        }
+    }
+}
+
+/// Columns with their types and datum counts, of `table` when given, `false`
+/// when there's no such table
+pub fn print_columns(db: &Db, table: Option<&str>) -> bool {
+    let mut cols = db.cols
+                     .iter()
+                     .filter(|&(name, _)| table.map_or(true, |table| name.table == table))
+                     .collect::<Vec<_>>();
+    if table.is_some() && cols.is_empty() {
+        return false;
+    }
+    cols.sort_by(|a, b| format!("{}", a.0).cmp(&format!("{}", b.0)));
+
+    let mut output = Table::new();
+    output.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+    output.set_titles(Row::new(vec![Cell::new("column"), Cell::new("type"), Cell::new("datums")]));
+    for (name, column) in cols {
+        output.add_row(Row::new(vec![Cell::new(&format!("{}", name)),
+                                     Cell::new(&format!("{:?}", column.data.column_type())),
+                                     Cell::new(&column.data.len().to_string())]));
+    }
+    output.printstd();
+    true
+}
+
+/// Datums, time index and time range of every column, of `table` when given,
+/// followed by the size of the whole Db
+fn print_stats(db: &Db, table: Option<&str>) {
+    let mut cols = db.cols
+                     .iter()
+                     .filter(|&(name, _)| table.map_or(true, |table| name.table == table))
+                     .collect::<Vec<_>>();
+    if let (Some(table), true) = (table, cols.is_empty()) {
+        println!("unknown table {}", table);
+        return;
+    }
+    cols.sort_by(|a, b| format!("{}", a.0).cmp(&format!("{}", b.0)));
+
+    let mut output = Table::new();
+    output.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+    output.set_titles(Row::new(vec![Cell::new("column"),
+                                    Cell::new("datums"),
+                                    Cell::new("time index"),
+                                    Cell::new("min time"),
+                                    Cell::new("max time")]));
+    for (name, column) in cols {
+        let (min, max) = column.data
+                               .time_bounds()
+                               .map_or((String::new(), String::new()),
+                                       |(min, max)| (min.to_string(), max.to_string()));
+        output.add_row(Row::new(vec![Cell::new(&format!("{}", name)),
+                                     Cell::new(&column.data.len().to_string()),
+                                     Cell::new(if column.has_time_index() { "yes" } else { "no" }),
+                                     Cell::new(&min),
+                                     Cell::new(&max)]));
+    }
+    output.printstd();
+
+    let tables = db.cols.keys().map(|name| &name.table).collect::<BTreeSet<_>>();
+    let datums = db.cols.values().fold(0, |acc, column| acc + column.data.len());
+    println!("\n{} tables, {} datums, {} entities",
+             tables.len(),
+             datums,
+             db.entity_count());
+}
+
+fn read_query_raw(db: &Db,
+                  format: &mut Format,
+                  limit: &mut usize,
+                  page: &mut Option<usize>)
+                  -> String {
+    let mut buffer = QueryBuffer::default();
 
     loop {
-        match readline::readline("") {
-            Ok(Some(ref line)) => {
-                if line == "" {
-                    let len = query.len();
-                    if len > 0 {
-                        query.truncate(len - 1);
+        match read_line("") {
+            Some(ref line) => {
+                match buffer.push_line(line) {
+                    Input::Pending => continue,
+                    Input::Cleared => println!("cleared\n"),
+                    Input::ToggleCsv => {
+                        *format = if *format == Format::Csv { Format::Table } else { Format::Csv };
+                        println!("csv output {}\n",
+                                 if *format == Format::Csv { "on" } else { "off" });
+                    }
+                    Input::Schema(table) => {
+                        print_schema(db, table.as_ref().map(|t| t.as_str()));
+                        println!("");
+                    }
+                    Input::Stats(table) => {
+                        print_stats(db, table.as_ref().map(|t| t.as_str()));
+                        println!("");
+                    }
+                    Input::Limit(None) => println!("limit {}\n", limit),
+                    Input::Limit(Some(rows)) => {
+                        match rows.parse() {
+                            Ok(rows) if rows > 0 => {
+                                *limit = rows;
+                                println!("limit {}\n", limit);
+                            }
+                            _ => println!("limit has to be a positive integer\n"),
+                        }
+                    }
+                    Input::Page(None) => {
+                        match *page {
+                            Some(rows) => println!("paging every {} rows\n", rows),
+                            None => println!("paging off\n"),
+                        }
                     }
-                    return query;
-                } else if line == "exit" {
-                    return line.to_owned();
+                    Input::Page(Some(ref rows)) if rows == "off" => {
+                        *page = None;
+                        println!("paging off\n");
+                    }
+                    Input::Page(Some(rows)) => {
+                        match rows.parse() {
+                            Ok(rows) if rows > 0 => {
+                                *page = Some(rows);
+                                println!("paging every {} rows\n", rows);
+                            }
+                            _ => println!("page has to be a positive integer or off\n"),
+                        }
+                    }
+                    Input::Exit => return "exit".to_owned(),
+                    Input::Query(query) => return query,
                 }
-                query = query + &line + "\n";
             }
-            _ => panic!("Cannot read line from console"),
+            None => panic!("Cannot read line from console"),
         }
     }
 }
 
 pub fn print_table(cols: Vec<(&ColumnName, &Data)>, limit: usize) {
+    print!("{}", table::render(cols, limit));
+}
+
+/// Splits `rows` rows into consecutive pages of `page_size` rows, the last one
+/// can be shorter
+fn pages(rows: usize, page_size: usize) -> Vec<Range<usize>> {
+    (0..(rows + page_size - 1) / page_size)
+        .map(|page| page * page_size..cmp::min(rows, (page + 1) * page_size))
+        .collect()
+}
+
+/// `print_table` a page at a time, enter prints the next page and `q` stops
+fn print_paged(cols: Vec<(&ColumnName, &Data)>, limit: usize, page_size: usize) {
+    let mut cols = cols;
+    cols.sort_by(|a, b| format!("{}", a.0).cmp(&format!("{}", b.0)));
+
+    let max_col_len = cols.iter().fold(0, |acc, &(_, ref data)| cmp::max(acc, data.len()));
+    if max_col_len == 0 {
+        return print_table(cols, limit);
+    }
+    let pages = pages(cmp::min(limit, max_col_len), page_size);
+    let count = pages.len();
+
+    for (i, rows) in pages.into_iter().enumerate() {
+        print!("{}", table::render_rows(&cols, rows));
+        if i + 1 == count {
+            break;
+        }
+        let prompt = format!("-- page {}/{}, enter for more or q to stop --", i + 1, count);
+        match read_line(&prompt) {
+            Some(ref line) if line.trim() != "q" => (),
+            _ => break,
+        }
+    }
+}
+
+/// Same rows and column order as `print_table`, written as CSV with the raw
+/// values, quoted when they contain a comma, a quote or a line break
+pub fn print_csv(cols: Vec<(&ColumnName, &Data)>, limit: usize) {
     let mut cols = cols;
     cols.sort_by(|a, b| format!("{}", a.0).cmp(&format!("{}", b.0)));
 
-    let mut table = Table::new();
-    table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+    let mut wtr = csv::Writer::from_writer(io::stdout())
+                      .record_terminator(csv::RecordTerminator::CRLF);
 
     let col_names = cols.iter()
-                        .map(|&(ref name, _)| Cell::new(&format!("{}", name)))
-                        .collect::<Vec<Cell>>();
-    table.set_titles(Row::new(col_names));
+                        .map(|&(ref name, _)| format!("{}", name))
+                        .collect::<Vec<String>>();
+    wtr.write(col_names.iter()).expect("Failed to write CSV");
 
     let max_col_len = cols.iter().fold(0, |acc, &(_, ref data)| cmp::max(acc, data.len()));
 
     for i in 0..cmp::min(limit, max_col_len) {
-        let mut row = vec![];
-        for &(_, ref data) in &cols {
-            match data.get(i) {
-                Some(d) => row.push(Cell::new(&format!("{}", d))),
-                None => row.push(Cell::new(" ")),
-            }
-        }
-        table.add_row(Row::new(row));
+        let row = cols.iter()
+                      .map(|&(_, ref data)| {
+                          data.value(i).map_or_else(String::new, |value| value.to_raw_string())
+                      })
+                      .collect::<Vec<String>>();
+        wtr.write(row.iter()).expect("Failed to write CSV");
+    }
+
+    wtr.flush().expect("Failed to write CSV");
+}
+
+/// One object per row keyed by `table.column`, a column with fewer datums than
+/// the longest one is `null` in the rows past its end
+pub fn print_json(cols: Vec<(&ColumnName, &Data)>, limit: usize) {
+    println!("{}", Json::Array(json_rows(cols, limit)));
+}
+
+/// Same objects as `print_json` but one per line, so results printed in
+/// chunks by `--stream` still parse as JSON Lines
+pub fn print_json_lines(cols: Vec<(&ColumnName, &Data)>, limit: usize) {
+    for row in json_rows(cols, limit) {
+        println!("{}", row);
+    }
+}
+
+fn json_rows(cols: Vec<(&ColumnName, &Data)>, limit: usize) -> Vec<Json> {
+    let max_col_len = cols.iter().fold(0, |acc, &(_, ref data)| cmp::max(acc, data.len()));
+
+    (0..cmp::min(limit, max_col_len))
+        .map(|i| {
+            let row = cols.iter()
+                          .map(|&(ref name, ref data)| {
+                              (format!("{}", name), data.value(i).map_or(Json::Null, json_value))
+                          })
+                          .collect::<BTreeMap<String, Json>>();
+            Json::Object(row)
+        })
+        .collect()
+}
+
+fn json_value(value: Value) -> Json {
+    match value {
+        Value::Bool(v) => Json::Boolean(v),
+        Value::Int(v) => Json::I64(v),
+        Value::String(v) => Json::String(v),
+        Value::Float(v) => Json::F64(v),
+        value @ Value::Timestamp(_) |
+        value @ Value::Bytes(_) => Json::String(value.to_raw_string()),
     }
+}
+
+pub fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1_000_000_000.0
+}
+
+/// How results are written to stdout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Table,
+    Csv,
+    Json,
+}
 
-    table.printstd();
+pub fn print_results(cols: Vec<(&ColumnName, &Data)>, limit: usize, format: Format) {
+    match format {
+        Format::Table => print_table(cols, limit),
+        Format::Csv => print_csv(cols, limit),
+        Format::Json => print_json(cols, limit),
+    }
 }
 
-pub fn start_repl(path: &str) {
+/// Rows of a result printed when nothing else says how many, separate from
+/// any limit in the query itself
+pub const DISPLAY_LIMIT: usize = 2000;
+
+/// History entries kept when `repl` isn't given `--max-history`
+pub const DEFAULT_MAX_HISTORY: usize = 2000;
+
+/// `max_history` caps the entries kept in `./.history`, dropping the oldest
+pub fn start_repl(path: &str, max_history: usize, default_limit: usize) {
     let history_path = Path::new("./.history");
     let mut start = time::precise_time_s();
-    let db = Db::from_file(path).expect("Failed to load db from file");
+    let mut db = Db::from_file(path).expect("Failed to load db from file");
     println!("\nload time: {:.4}", time::precise_time_s() - start);
 
+    let mut format = Format::Table;
+    let mut limit = DISPLAY_LIMIT;
+    let mut page = None;
+
     mgmt::init();
+    set_completer(&db);
+    listmgmt::stifle(max_history as i32);
     if history_path.exists() {
-        histfile::read(Some(history_path)).expect("Failed to read history");
+        with_history_file(history_path, |file| unsafe { read_history(file) })
+            .expect("Failed to read history");
     }
 
     loop {
         println!("\n>>>>>>>>>>>>>>>>>>>>>>>>>>>>>\n");
 
-        let query_raw = read_query_raw();
+        let query_raw = read_query_raw(&db, &mut format, &mut limit, &mut page);
         if query_raw == "exit" {
             mgmt::cleanup();
             process::exit(0);
         };
 
-        listmgmt::add(&query_raw).expect("Failed to save history");
-        histfile::write(Some(history_path)).expect("Failed to write history");
+        add_history_entry(&query_raw);
+        with_history_file(history_path, |file| unsafe { write_history(file) })
+            .expect("Failed to write history");
+        // Queries spanning several lines take a line of the file each
+        with_history_file(history_path,
+                          |file| unsafe { history_truncate_file(file, max_history as c_int) })
+            .expect("Failed to truncate history");
+
+        // `explain` before a query prints its plan instead of running it
+        let explain = query_raw.starts_with("explain ") || query_raw.starts_with("explain\n");
+        let query = if explain { &query_raw[8..] } else { &query_raw[..] };
 
-        let plan = match Plan::from_str(&query_raw) {
+        let plan = match Plan::parse_against(query, &db, default_limit) {
             Ok(plan) => plan,
             Err(e) => {
-                println!("{:?}", e);
+                println!("{}", e);
                 continue;
             }
         };
 
+        if let Err(e) = plan.validate_against(&db) {
+            println!("{}", e);
+            if let plan::Error::UnknownColumn(ref col_name) = e {
+                println!("columns in {}: {}",
+                         col_name.table,
+                         db.table_columns(&col_name.table).join(", "));
+            }
+            continue;
+        }
+
+        if explain {
+            println!("{}", plan.explain());
+            continue;
+        }
+
         println!("{}", plan);
+        for summary in plan.filter_summaries() {
+            println!("filter: {}", summary);
+        }
 
         start = time::precise_time_s();
-        match exec::exec(&db, &plan) {
-            Ok(data) => {
+        let progress = |p: exec::Progress| {
+            println!("stage {}/{} done, {} rows, {} scanned, {} skipped",
+                     p.stage + 1,
+                     p.stages,
+                     p.rows,
+                     p.scanned,
+                     p.skipped)
+        };
+        let options = exec::ExecOptions::default();
+        let data = match exec::exec_timed(&db, &plan, options, progress) {
+            Ok((data, durations)) => {
                 println!("exec time: {:.4}\n", time::precise_time_s() - start);
-                print_table(data.iter()
-                                .map(|&(ref n, ref e)| (n, e))
-                                .collect(),
-                            2000)
+                let cols = data.iter().map(|&(ref n, ref e)| (n, e)).collect();
+                match page {
+                    Some(rows) if format == Format::Table => print_paged(cols, limit, rows),
+                    _ => print_results(cols, limit, format),
+                }
+                println!("");
+                for (i, duration) in durations.iter().enumerate() {
+                    println!("stage {}/{} time: {:.4}", i + 1, durations.len(), seconds(*duration));
+                }
+                data
             }
             Err(e) => {
                 println!("{:?}", e);
                 continue;
             }
         };
+
+        if let Some(ref table) = plan.create_table {
+            match insert::add_result_to_db(&mut db, path, table, data) {
+                Ok(()) => {
+                    set_completer(&db);
+                    println!("\ncreated table {}", table)
+                }
+                Err(e) => println!("{:?}", e),
+            }
+        }
     }
 }
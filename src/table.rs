@@ -0,0 +1,74 @@
+//! Renders query results as the text tables the REPL and `query` print.
+//!
+//! ```
+//! extern crate twin_query;
+//!
+//! use twin_query::{table, ColumnName, Data};
+//!
+//! # fn main() {
+//! assert_eq!(table::render(vec![], 20), "no columns selected\n");
+//!
+//! let name = ColumnName::new("foo", "a");
+//! let empty = Data::Int(vec![]);
+//! assert_eq!(table::render(vec![(&name, &empty)], 20), "(0 rows)\n");
+//! # }
+//! ```
+
+use prettytable::format;
+use prettytable::Table;
+use prettytable::row::Row;
+use prettytable::cell::Cell;
+use std::cmp;
+use std::ops::Range;
+
+use data::{ColumnName, Data};
+
+/// The first `limit` rows of `cols`, ordered by column name, or a one line
+/// message when there are no columns or no rows
+pub fn render(cols: Vec<(&ColumnName, &Data)>, limit: usize) -> String {
+    let mut cols = cols;
+    cols.sort_by(|a, b| format!("{}", a.0).cmp(&format!("{}", b.0)));
+
+    if cols.is_empty() {
+        return "no columns selected\n".to_owned();
+    }
+
+    // prettytable only sizes columns by their rows, so the titles alone print nothing
+    let max_col_len = cols.iter().fold(0, |acc, &(_, ref data)| cmp::max(acc, data.len()));
+    if max_col_len == 0 {
+        return "(0 rows)\n".to_owned();
+    }
+    render_rows(&cols, 0..cmp::min(limit, max_col_len))
+}
+
+/// `rows` of `cols` under a title row, in the order of `cols`
+pub fn render_rows(cols: &[(&ColumnName, &Data)], rows: Range<usize>) -> String {
+    let mut table = Table::new();
+    table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+
+    let col_names = cols.iter()
+                        .map(|&(ref name, _)| Cell::new(&format!("{}", name)))
+                        .collect::<Vec<Cell>>();
+    table.set_titles(Row::new(col_names));
+
+    for i in rows {
+        let mut row = vec![];
+        for &(_, ref data) in cols {
+            match data.get(i) {
+                Some(d) => row.push(Cell::new_align(&format!("{}", d), alignment(data))),
+                None => row.push(Cell::new(" ")),
+            }
+        }
+        table.add_row(Row::new(row));
+    }
+
+    table.to_string()
+}
+
+/// Numbers are right-aligned so their digits line up, everything else is left-aligned
+fn alignment(data: &Data) -> format::Alignment {
+    match *data {
+        Data::Int(_) | Data::Float(_) => format::Alignment::RIGHT,
+        _ => format::Alignment::LEFT,
+    }
+}
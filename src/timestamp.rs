@@ -0,0 +1,108 @@
+/// Seconds since the epoch for an ISO-8601 UTC date or date time, e.g.
+/// `2020-01-01`, `2020-01-01T12:30` or `2020-01-01T12:30:15Z`
+pub fn parse(s: &str) -> Option<i64> {
+    let s = if s.ends_with('Z') { &s[..s.len() - 1] } else { s };
+    let (date, time) = match s.find(|c| c == 'T' || c == ' ') {
+        Some(index) => (&s[..index], Some(&s[index + 1..])),
+        None => (s, None),
+    };
+
+    let date = date.split('-').collect::<Vec<&str>>();
+    if date.len() != 3 {
+        return None;
+    }
+    let (year, month, day) = match (digits(date[0], 4), digits(date[1], 2), digits(date[2], 2)) {
+        (Some(year), Some(month), Some(day)) => (year, month, day),
+        _ => return None,
+    };
+    if month < 1 || month > 12 || day < 1 || day > days_in_month(year, month) {
+        return None;
+    }
+
+    let seconds = match time {
+        Some(time) => {
+            let time = time.split(':').collect::<Vec<&str>>();
+            if time.len() < 2 || time.len() > 3 {
+                return None;
+            }
+            let second = if time.len() == 3 { digits(time[2], 2) } else { Some(0) };
+            match (digits(time[0], 2), digits(time[1], 2), second) {
+                (Some(hour), Some(minute), Some(second)) if hour < 24 && minute < 60 &&
+                                                            second < 60 => {
+                    hour * 3600 + minute * 60 + second
+                }
+                _ => return None,
+            }
+        }
+        None => 0,
+    };
+
+    Some(days_from_civil(year, month, day) * 86400 + seconds)
+}
+
+/// Render epoch seconds as `YYYY-MM-DDTHH:MM:SSZ`
+pub fn format(timestamp: i64) -> String {
+    let days = floor_div(timestamp, 86400);
+    let seconds = timestamp - days * 86400;
+    let (year, month, day) = civil_from_days(days);
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            year,
+            month,
+            day,
+            seconds / 3600,
+            seconds % 3600 / 60,
+            seconds % 60)
+}
+
+fn digits(s: &str, len: usize) -> Option<i64> {
+    if s.len() != len || !s.chars().all(|c| c.is_digit(10)) {
+        return None;
+    }
+    s.parse::<i64>().ok()
+}
+
+fn floor_div(a: i64, b: i64) -> i64 {
+    if a >= 0 { a / b } else { (a - b + 1) / b }
+}
+
+fn is_leap_year(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 in the proleptic Gregorian calendar, counted in 400
+/// year eras that start on March 1st so leap days fall at the end of a year
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = floor_div(year, 400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+/// Inverse of `days_from_civil`
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = floor_div(days, 146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 -
+                       day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400;
+
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
@@ -0,0 +1,138 @@
+use std::fs::File;
+use std::io::Read;
+
+use data::{ColumnName, Data, Db};
+use insert;
+use plan::{self, Aggregate, Plan, PlanNode};
+
+#[derive(Debug)]
+pub enum Error {
+    Plan(plan::Error),
+    MissingTable(String),
+    MissingColumn(ColumnName),
+    InvalidJoin(ColumnName),
+    InvalidLike(ColumnName),
+    InvalidModulo(ColumnName),
+    InvalidComparison(ColumnName),
+    InvalidOrderBy(ColumnName),
+    InvalidGroup(ColumnName),
+}
+
+impl From<plan::Error> for Error {
+    fn from(err: plan::Error) -> Error {
+        Error::Plan(err)
+    }
+}
+
+fn validate_node(db: &Db, node: &PlanNode) -> Result<(), Error> {
+    match *node {
+        PlanNode::Select(ref name, _, _, _) |
+        PlanNode::Latest(ref name, _, _, _, _) |
+        PlanNode::TimeCount(ref name, _) |
+        PlanNode::WhereId(ref name, _) => {
+            try!(db.cols.get(name).ok_or(Error::MissingColumn(name.to_owned())));
+        }
+        PlanNode::Group(ref key, aggregate, ref name, _, _) => {
+            try!(db.cols.get(key).ok_or(Error::MissingColumn(key.to_owned())));
+            let column = try!(db.cols.get(name).ok_or(Error::MissingColumn(name.to_owned())));
+
+            match (aggregate, &column.data) {
+                (Aggregate::Sum, &Data::Int(_)) |
+                (Aggregate::Count, _) => (),
+                _ => return Err(Error::InvalidGroup(name.to_owned())),
+            }
+        }
+        PlanNode::Cohort(ref signup, _, ref activity, _, _) => {
+            try!(db.cols.get(signup).ok_or(Error::MissingColumn(signup.to_owned())));
+            try!(db.cols.get(activity).ok_or(Error::MissingColumn(activity.to_owned())));
+        }
+        PlanNode::Join(ref left, ref right, _) => {
+            if !db.ids.contains_key(&left.table) {
+                return Err(Error::MissingTable(left.table.to_owned()));
+            }
+
+            let left_column = try!(db.cols
+                                     .get(left)
+                                     .ok_or(Error::MissingColumn(left.to_owned())));
+            let column = try!(db.cols.get(right).ok_or(Error::MissingColumn(right.to_owned())));
+            if !left_column.data.is_same_type(&column.data) {
+                return Err(Error::InvalidJoin(right.to_owned()));
+            }
+        }
+        PlanNode::Where(ref name, ref predicate, _) => {
+            let column = try!(db.cols.get(name).ok_or(Error::MissingColumn(name.to_owned())));
+
+            match column.data {
+                Data::String(_) => (),
+                _ if predicate.has_like() => return Err(Error::InvalidLike(name.to_owned())),
+                _ => (),
+            }
+            match column.data {
+                Data::Int(_) => (),
+                _ if predicate.has_mod() => return Err(Error::InvalidModulo(name.to_owned())),
+                _ => (),
+            }
+
+            for other in predicate.compared_columns() {
+                let other_column = try!(db.cols
+                                          .get(other)
+                                          .ok_or(Error::MissingColumn(other.to_owned())));
+                if !other_column.data.is_same_type(&column.data) {
+                    return Err(Error::InvalidComparison(other.to_owned()));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that every column a plan touches exists in `db` with a compatible
+/// type, without executing it. Works against a schema-only `Db`.
+pub fn validate_plan(db: &Db, plan: &Plan) -> Result<(), Error> {
+    try!(plan.validate_against(db));
+
+    for stage in &plan.stages {
+        for node in &stage.nodes {
+            try!(validate_node(db, node));
+        }
+    }
+
+    if let Some((ref name, _)) = plan.order {
+        let selected = plan.stages.iter().flat_map(|stage| stage.nodes.iter()).any(|node| {
+            match *node {
+                PlanNode::Select(ref n, _, _, _) |
+                PlanNode::Latest(ref n, _, _, _, _) => n == name,
+                _ => false,
+            }
+        });
+
+        if !selected {
+            return Err(Error::InvalidOrderBy(name.to_owned()));
+        }
+    }
+
+    Ok(())
+}
+
+pub fn validate_query(db: &Db, query: &str) -> Result<(), Error> {
+    let plan = try!(Plan::parse_against(query, db, plan::DEFAULT_LIMIT));
+    validate_plan(db, &plan)
+}
+
+/// Validate each blank line separated query in `queries_path` against the
+/// columns declared in `schema_path`, each query is returned with its result
+pub fn validate_queries(schema_path: &str,
+                        queries_path: &str)
+                        -> Result<Vec<(String, Result<(), Error>)>, insert::Error> {
+    let db = try!(insert::schema_db(schema_path));
+
+    let mut contents = String::new();
+    try!(File::open(queries_path).and_then(|mut f| f.read_to_string(&mut contents)));
+
+    Ok(contents.split("\n\n")
+               .map(|q| q.trim())
+               .filter(|q| !q.is_empty())
+               .map(|query| (query.to_owned(), validate_query(&db, query)))
+               .collect())
+}